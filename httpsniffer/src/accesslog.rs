@@ -0,0 +1,481 @@
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use sniffglue::structs::http::Request;
+
+use crate::dimension::header;
+
+/// Buffered lines are written at least this often while requests arrive
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// How requests are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// nginx `combined`, followed by the host and the selected headers
+    Combined,
+    /// One json object per request
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "combined" => Ok(Format::Combined),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("invalid access log format: {:?}", s)),
+        }
+    }
+}
+
+/// When the log file is moved aside and a new one is started
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotate {
+    /// After the file grew to this many bytes
+    Size(u64),
+    /// Whenever a request falls into the next interval of this many seconds
+    Interval(u64),
+}
+
+impl FromStr for Rotate {
+    type Err = String;
+
+    /// `size:<n>[K|M|G]` or `time:<n>[s|m|h|d]`
+    fn from_str(s: &str) -> Result<Rotate, String> {
+        let invalid = || format!("invalid rotation: {:?}", s);
+        let mut parts = s.splitn(2, ':');
+        let (kind, value) = match (parts.next(), parts.next()) {
+            (Some(kind), Some(value)) if !value.is_empty() => (kind, value),
+            _ => return Err(invalid()),
+        };
+        let last = value.chars().last().map_or(0, char::len_utf8);
+        let (number, unit) = value.split_at(value.len() - last);
+        let (number, multiplier) = match (kind, unit) {
+            ("size", "K") => (number, 1 << 10),
+            ("size", "M") => (number, 1 << 20),
+            ("size", "G") => (number, 1 << 30),
+            ("size", _) => (value, 1),
+            ("time", "s") => (number, 1),
+            ("time", "m") => (number, 60),
+            ("time", "h") => (number, 60 * 60),
+            ("time", "d") => (number, 24 * 60 * 60),
+            _ => return Err(invalid()),
+        };
+        let n = match number.parse::<u64>() {
+            Ok(n) if n > 0 => n.checked_mul(multiplier).ok_or_else(invalid)?,
+            _ => return Err(invalid()),
+        };
+        match kind {
+            "size" => Ok(Rotate::Size(n)),
+            _ => Ok(Rotate::Interval(n)),
+        }
+    }
+}
+
+/// A file that is renamed to `<path>.<time>` when it's rotated
+struct RotatingFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    written: u64,
+    rotate: Option<Rotate>,
+    /// Interval of the last request, for time based rotation
+    period: Option<u64>,
+    /// Time of the first request in the file, unknown for appended files
+    started: Option<u64>,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, rotate: Option<Rotate>) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file: BufWriter::new(file),
+            written,
+            rotate,
+            period: None,
+            started: None,
+        })
+    }
+
+    fn write_line(&mut self, ts: Duration, line: &str) -> io::Result<()> {
+        // rotated files are named after their first request, or the start of
+        // their interval
+        let secs = ts.as_secs();
+        let rotate = match self.rotate {
+            Some(Rotate::Size(max)) if self.written + line.len() as u64 > max => {
+                Some(self.started.unwrap_or(secs))
+            }
+            Some(Rotate::Interval(interval)) => {
+                let period = secs / interval;
+                match self.period.replace(period) {
+                    Some(previous) if previous != period => Some(previous * interval),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        if self.written == 0 {
+            self.started = Some(secs);
+        } else if let Some(started) = rotate {
+            self.rotate(started)?;
+            self.started = Some(secs);
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self, started: u64) -> io::Result<()> {
+        self.file.flush()?;
+        let (year, month, day, hour, minute, second) = civil(started);
+        let stamp = format!(
+            "{:04}{:02}{:02}-{:02}{:02}{:02}",
+            year, month, day, hour, minute, second
+        );
+        let mut rotated = PathBuf::from(format!("{}.{}", self.path.display(), stamp));
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = PathBuf::from(format!("{}.{}.{}", self.path.display(), stamp, n));
+            n += 1;
+        }
+        fs::rename(&self.path, rotated)?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+enum Output {
+    Writer(Box<dyn Write + Send>),
+    File(RotatingFile),
+}
+
+/// Writes every parsed request as one line, for debugging and for tools that
+/// read web server logs
+pub struct AccessLog {
+    format: Format,
+    /// Extra headers written after the fixed fields
+    headers: Vec<String>,
+    out: Output,
+    flushed: Instant,
+}
+
+impl AccessLog {
+    /// `-` writes to stdout, everything else is a file that is appended to
+    /// and rotated
+    pub fn open(
+        path: &str,
+        format: Format,
+        headers: Vec<String>,
+        rotate: Option<Rotate>,
+    ) -> io::Result<AccessLog> {
+        if path == "-" {
+            if rotate.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "stdout can't be rotated",
+                ));
+            }
+            return Ok(AccessLog::new(Box::new(io::stdout()), format, headers));
+        }
+        let file = RotatingFile::open(PathBuf::from(path), rotate)?;
+        Ok(AccessLog::with_output(Output::File(file), format, headers))
+    }
+
+    pub fn new(out: Box<dyn Write + Send>, format: Format, headers: Vec<String>) -> AccessLog {
+        AccessLog::with_output(Output::Writer(out), format, headers)
+    }
+
+    fn with_output(out: Output, format: Format, headers: Vec<String>) -> AccessLog {
+        AccessLog {
+            format,
+            headers: headers.iter().map(|name| name.to_lowercase()).collect(),
+            out,
+            flushed: Instant::now(),
+        }
+    }
+
+    /// A request from `client` that was captured at `ts`
+    pub fn log(&mut self, ts: Duration, client: IpAddr, request: &Request) {
+        let line = self.line(ts, client, request);
+        let result = match self.out {
+            Output::Writer(ref mut out) => writeln!(out, "{}", line),
+            Output::File(ref mut file) => file.write_line(ts, &line),
+        };
+        if let Err(err) = result {
+            eprintln!("access log error: {:?}", err);
+        }
+        if self.flushed.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.flushed = Instant::now();
+        let result = match self.out {
+            Output::Writer(ref mut out) => out.flush(),
+            Output::File(ref mut file) => file.file.flush(),
+        };
+        if let Err(err) = result {
+            eprintln!("access log error: {:?}", err);
+        }
+    }
+
+    fn line(&self, ts: Duration, client: IpAddr, request: &Request) -> String {
+        match self.format {
+            Format::Combined => {
+                let quoted = |value: Option<&str>| match value {
+                    Some(value) => format!("\"{}\"", escape(value)),
+                    None => "\"-\"".to_string(),
+                };
+                let (year, month, day, hour, minute, second) = civil(ts.as_secs());
+                let mut line = format!(
+                    "{} - - [{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] \"{} {} HTTP/{}\" - - {} {} {}",
+                    client,
+                    day,
+                    MONTHS[month as usize - 1],
+                    year,
+                    hour,
+                    minute,
+                    second,
+                    escape(&request.method),
+                    escape(&request.uri),
+                    escape(&request.version),
+                    quoted(request.referer.as_deref()),
+                    quoted(request.agent.as_deref()),
+                    quoted(request.host.as_deref()),
+                );
+                for name in &self.headers {
+                    let _ = write!(line, " {}", quoted(header(request, name)));
+                }
+                line
+            }
+            Format::Json => {
+                let headers: serde_json::Map<_, _> = self
+                    .headers
+                    .iter()
+                    .map(|name| (name.clone(), json!(header(request, name))))
+                    .collect();
+                json!({
+                    "time": rfc3339(ts),
+                    "client": client.to_string(),
+                    "host": request.host,
+                    "method": request.method,
+                    "uri": request.uri,
+                    "version": request.version,
+                    "user_agent": request.agent,
+                    "referer": request.referer,
+                    "headers": headers,
+                })
+                .to_string()
+            }
+        }
+    }
+}
+
+/// Quotes, backslashes and control characters are written as `\xHH`, like
+/// nginx does, so every field stays parseable
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | '\x00'..='\x1f' | '\x7f' => {
+                let _ = write!(escaped, "\\x{:02X}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `2019-04-23T06:13:20.250Z`
+pub fn rfc3339(ts: Duration) -> String {
+    let (year, month, day, hour, minute, second) = civil(ts.as_secs());
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        ts.subsec_millis()
+    )
+}
+
+/// UTC date and time of a unix timestamp
+fn civil(secs: u64) -> (u64, u64, u64, u64, u64, u64) {
+    let days = secs / 86_400;
+    let time = secs % 86_400;
+
+    // days since 0000-03-01, so leap days are at the end of a year
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sniffglue::centrifuge::http;
+
+    use crate::testutil::{Buffer, TS};
+
+    fn lines(buf: &Buffer) -> Vec<String> {
+        buf.contents().lines().map(String::from).collect()
+    }
+
+    fn request(raw: &str) -> Request {
+        http::extract(raw.as_bytes()).unwrap()
+    }
+
+    fn log(format: Format, headers: &[&str]) -> (AccessLog, Buffer) {
+        let buf = Buffer::default();
+        let headers = headers.iter().map(|name| name.to_string()).collect();
+        (AccessLog::new(Box::new(buf.clone()), format, headers), buf)
+    }
+
+    #[test]
+    fn combined() {
+        let (mut log, buf) = log(Format::Combined, &["X-Request-Id", "x-missing"]);
+        let req = request(
+            "GET /recipe?id=1 HTTP/1.1\r\nHost: example.com\r\nUser-Agent: curl/7.64 \"x\"\r\nX-Request-Id: abc\r\n\r\n",
+        );
+        log.log(TS, "1.2.3.4".parse().unwrap(), &req);
+
+        assert_eq!(
+            lines(&buf)[0],
+            r#"1.2.3.4 - - [23/Apr/2019:06:13:20 +0000] "GET /recipe?id=1 HTTP/1.1" - - "-" "curl/7.64 \x22x\x22" "example.com" "abc" "-""#
+        );
+    }
+
+    #[test]
+    fn json() {
+        let (mut log, buf) = log(Format::Json, &["x-request-id"]);
+        let req = request(
+            "POST /login HTTP/1.1\r\nHost: example.com\r\nReferer: https://example.com/\r\n\r\n",
+        );
+        log.log(TS, "2001:db8::1".parse().unwrap(), &req);
+
+        let line: serde_json::Value = serde_json::from_str(&lines(&buf)[0]).unwrap();
+        assert_eq!(line["time"], "2019-04-23T06:13:20.250Z");
+        assert_eq!(line["client"], "2001:db8::1");
+        assert_eq!(line["host"], "example.com");
+        assert_eq!(line["method"], "POST");
+        assert_eq!(line["uri"], "/login");
+        assert_eq!(line["referer"], "https://example.com/");
+        assert_eq!(line["user_agent"], serde_json::Value::Null);
+        assert_eq!(line["headers"]["x-request-id"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn parse_rotate() {
+        assert_eq!("size:100M".parse(), Ok(Rotate::Size(100 << 20)));
+        assert_eq!("size:4096".parse(), Ok(Rotate::Size(4096)));
+        assert_eq!("time:1h".parse(), Ok(Rotate::Interval(3600)));
+        assert_eq!("time:1d".parse(), Ok(Rotate::Interval(86_400)));
+        assert!("time:1".parse::<Rotate>().is_err());
+        assert!("size:0".parse::<Rotate>().is_err());
+        assert!("size:".parse::<Rotate>().is_err());
+        assert!("100M".parse::<Rotate>().is_err());
+        assert!("time:1é".parse::<Rotate>().is_err());
+        assert!("size:99999999999G".parse::<Rotate>().is_err());
+        assert!("time:999999999999999999d".parse::<Rotate>().is_err());
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(civil(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(civil(951_782_400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(civil(1_556_000_000), (2019, 4, 23, 6, 13, 20));
+        assert_eq!(civil(4_107_542_399), (2100, 2, 28, 23, 59, 59));
+    }
+
+    fn rotated(dir: &PathBuf) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rotate_files() {
+        let dir = std::env::temp_dir().join(format!("httpsniffer-access-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let req = request("GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        let client = "1.2.3.4".parse().unwrap();
+
+        let path = dir.join("size.log");
+        let rotate = Some(Rotate::Size(150));
+        let mut log =
+            AccessLog::open(path.to_str().unwrap(), Format::Combined, Vec::new(), rotate).unwrap();
+        for _ in 0..3 {
+            log.log(TS, client, &req);
+        }
+        log.flush();
+        assert_eq!(
+            rotated(&dir),
+            vec![
+                "size.log",
+                "size.log.20190423-061320",
+                "size.log.20190423-061320.1"
+            ]
+        );
+
+        let path = dir.join("time.log");
+        let rotate = Some(Rotate::Interval(3600));
+        let mut log =
+            AccessLog::open(path.to_str().unwrap(), Format::Json, Vec::new(), rotate).unwrap();
+        log.log(TS, client, &req);
+        log.log(TS + Duration::from_secs(60), client, &req);
+        log.log(TS + Duration::from_secs(3600), client, &req);
+        log.flush();
+
+        let names = rotated(&dir);
+        assert!(
+            names.contains(&"time.log.20190423-060000".to_string()),
+            "{:?}",
+            names
+        );
+        let old = fs::read_to_string(dir.join("time.log.20190423-060000")).unwrap();
+        assert_eq!(old.lines().count(), 2);
+        let current = fs::read_to_string(dir.join("time.log")).unwrap();
+        assert_eq!(current.lines().count(), 1);
+
+        assert!(AccessLog::open("-", Format::Json, Vec::new(), rotate).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use sniffglue::link::DataLink;
+
+/// Packets that are decoded together by one pool job
+pub const BATCH_SIZE: usize = 64;
+
+/// Bytes a pooled batch keeps allocated, a full batch of ethernet frames.
+/// Batches that grew beyond this after a burst of large frames are shrunk.
+const KEPT_CAPACITY: usize = BATCH_SIZE * 1514;
+
+/// Flows kept by `sample` without a rate
+const DEFAULT_SAMPLE_RATE: u32 = 10;
+
+/// Captured packets, copied into one buffer that is reused for later batches
+#[derive(Debug, Default)]
+pub struct Batch {
+    data: Vec<u8>,
+    packets: Vec<(Duration, Range<usize>)>,
+}
+
+impl Batch {
+    pub fn push(&mut self, ts: Duration, packet: &[u8]) {
+        let start = self.data.len();
+        self.data.extend_from_slice(packet);
+        self.packets.push((ts, start..self.data.len()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Duration, &[u8])> {
+        self.packets
+            .iter()
+            .map(move |(ts, range)| (*ts, &self.data[range.clone()]))
+    }
+
+    /// Forget the packets but keep the allocations
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.packets.clear();
+    }
+}
+
+/// Free list of batches, so capturing doesn't allocate for every packet
+#[derive(Clone)]
+pub struct Buffers {
+    free: Arc<Mutex<Vec<Batch>>>,
+    max: usize,
+}
+
+impl Buffers {
+    /// Keeps at most `max` unused batches around
+    pub fn new(max: usize) -> Buffers {
+        Buffers {
+            free: Arc::new(Mutex::new(Vec::new())),
+            max,
+        }
+    }
+
+    pub fn take(&self) -> Batch {
+        self.free
+            .lock()
+            .expect("lock buffers")
+            .pop()
+            .unwrap_or_default()
+    }
+
+    pub fn give(&self, mut batch: Batch) {
+        batch.clear();
+        if batch.data.capacity() > KEPT_CAPACITY {
+            batch.data = Vec::with_capacity(KEPT_CAPACITY);
+        }
+        let mut free = self.free.lock().expect("lock buffers");
+        if free.len() < self.max {
+            free.push(batch);
+        }
+    }
+}
+
+/// What happens to captured packets while the decoding queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overload {
+    /// Drop every packet until the queue drained
+    Drop,
+    /// Keep every n-th connection, so sampled connections stay complete
+    Sample(u32),
+}
+
+impl Overload {
+    /// Whether a packet is queued anyway while overloaded
+    pub fn keep(self, datalink: &DataLink, packet: &[u8]) -> bool {
+        match self {
+            Overload::Drop => false,
+            Overload::Sample(rate) => match flow_hash(datalink, packet) {
+                Some(hash) => hash % u64::from(rate) == 0,
+                None => false,
+            },
+        }
+    }
+}
+
+impl FromStr for Overload {
+    type Err = String;
+
+    /// `drop`, `sample` or `sample:<n>`
+    fn from_str(s: &str) -> Result<Overload, String> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("drop"), None) => Ok(Overload::Drop),
+            (Some("sample"), None) => Ok(Overload::Sample(DEFAULT_SAMPLE_RATE)),
+            (Some("sample"), Some(rate)) => match rate.parse() {
+                Ok(rate) if rate > 0 => Ok(Overload::Sample(rate)),
+                _ => Err(format!("invalid sample rate: {:?}", rate)),
+            },
+            _ => Err(format!("invalid overload policy: {:?}", s)),
+        }
+    }
+}
+
+/// Hash of the addresses and ports of a tcp packet that is the same for both
+/// directions. Only the fixed headers are looked at, this runs on the capture
+/// thread.
+fn flow_hash(datalink: &DataLink, packet: &[u8]) -> Option<u64> {
+    let ip = match datalink {
+        DataLink::Ethernet => {
+            let ethertype = |offset: usize| {
+                let bytes = packet.get(offset..offset + 2)?;
+                Some(u16::from(bytes[0]) << 8 | u16::from(bytes[1]))
+            };
+            let mut offset = 12;
+            // skip vlan tags
+            while let 0x8100 | 0x88a8 = ethertype(offset)? {
+                offset += 4;
+            }
+            match ethertype(offset)? {
+                0x0800 | 0x86dd => packet.get(offset + 2..)?,
+                _ => return None,
+            }
+        }
+        DataLink::Tun => packet,
+        DataLink::RadioTap => return None,
+    };
+
+    let (source, dest, tcp) = match ip.first()? >> 4 {
+        4 => {
+            let ihl = usize::from(ip[0] & 0x0f) * 4;
+            (ip.get(12..16)?, ip.get(16..20)?, ip.get(ihl..)?)
+        }
+        6 => (ip.get(8..24)?, ip.get(24..40)?, ip.get(40..)?),
+        _ => return None,
+    };
+    let ports = tcp.get(..4)?;
+
+    let a = (source, &ports[..2]);
+    let b = (dest, &ports[2..]);
+    let mut hasher = DefaultHasher::new();
+    if a <= b { (a, b) } else { (b, a) }.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(source: [u8; 4], sport: u16, dest: [u8; 4], dport: u16) -> Vec<u8> {
+        let mut packet = vec![0; 14];
+        packet[12..14].copy_from_slice(&[0x08, 0x00]);
+        let mut ip = vec![0x45, 0, 0, 40, 0, 0, 0, 0, 64, 6, 0, 0];
+        ip.extend_from_slice(&source);
+        ip.extend_from_slice(&dest);
+        packet.extend(ip);
+        packet.extend_from_slice(&sport.to_be_bytes());
+        packet.extend_from_slice(&dport.to_be_bytes());
+        packet.extend_from_slice(&[0; 16]);
+        packet
+    }
+
+    #[test]
+    fn parse_overload() {
+        assert_eq!("drop".parse(), Ok(Overload::Drop));
+        assert_eq!("sample".parse(), Ok(Overload::Sample(10)));
+        assert_eq!("sample:100".parse(), Ok(Overload::Sample(100)));
+        assert!("sample:0".parse::<Overload>().is_err());
+        assert!("drop:1".parse::<Overload>().is_err());
+        assert!("block".parse::<Overload>().is_err());
+    }
+
+    #[test]
+    fn reuse_batches() {
+        let buffers = Buffers::new(1);
+        let mut batch = buffers.take();
+        batch.push(Duration::from_secs(1), b"abc");
+        batch.push(Duration::from_secs(2), b"de");
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            batch.iter().collect::<Vec<_>>(),
+            vec![
+                (Duration::from_secs(1), &b"abc"[..]),
+                (Duration::from_secs(2), &b"de"[..])
+            ]
+        );
+
+        let capacity = batch.data.capacity();
+        buffers.give(batch);
+        buffers.give(Batch::default());
+        let batch = buffers.take();
+        assert!(batch.is_empty());
+        assert_eq!(batch.data.capacity(), capacity);
+        assert!(buffers.take().data.capacity() == 0);
+
+        // a burst of jumbo frames doesn't stay allocated
+        let mut batch = buffers.take();
+        for _ in 0..BATCH_SIZE {
+            batch.push(Duration::from_secs(3), &[0; 9000]);
+        }
+        buffers.give(batch);
+        assert_eq!(buffers.take().data.capacity(), KEPT_CAPACITY);
+    }
+
+    #[test]
+    fn flow_hash_is_symmetric() {
+        let request = packet([10, 0, 0, 1], 51234, [10, 0, 0, 2], 80);
+        let response = packet([10, 0, 0, 2], 80, [10, 0, 0, 1], 51234);
+        let other = packet([10, 0, 0, 1], 51235, [10, 0, 0, 2], 80);
+
+        let hash = flow_hash(&DataLink::Ethernet, &request);
+        assert!(hash.is_some());
+        assert_eq!(hash, flow_hash(&DataLink::Ethernet, &response));
+        assert_ne!(hash, flow_hash(&DataLink::Ethernet, &other));
+        assert_eq!(hash, flow_hash(&DataLink::Tun, &request[14..]));
+        assert_eq!(flow_hash(&DataLink::Ethernet, &request[..20]), None);
+
+        let mut vlan = request[..12].to_vec();
+        vlan.extend_from_slice(&[0x81, 0x00, 0x00, 0x64]);
+        vlan.extend_from_slice(&request[12..]);
+        assert_eq!(hash, flow_hash(&DataLink::Ethernet, &vlan));
+    }
+
+    #[test]
+    fn sample_whole_flows() {
+        let mut kept = 0;
+        for port in 1024..2024 {
+            let request = packet([10, 0, 0, 1], port, [10, 0, 0, 2], 80);
+            let response = packet([10, 0, 0, 2], 80, [10, 0, 0, 1], port);
+            let keep = Overload::Sample(10).keep(&DataLink::Ethernet, &request);
+            assert_eq!(
+                keep,
+                Overload::Sample(10).keep(&DataLink::Ethernet, &response)
+            );
+            if keep {
+                kept += 1;
+            }
+            assert!(!Overload::Drop.keep(&DataLink::Ethernet, &request));
+        }
+        assert!(kept > 50 && kept < 150, "{}", kept);
+    }
+}
@@ -0,0 +1,297 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use failure::{bail, format_err, Error};
+
+use sniffglue::structs::http::Request;
+
+use crate::config::ClientIpConfig;
+use crate::dimension::header;
+
+/// An address range like `10.0.0.0/8`, a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(cidr: &str) -> Result<Cidr, Error> {
+        let mut parts = cidr.trim().splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or("")
+            .parse()
+            .map_err(|_| format_err!("invalid address in {:?}", cidr))?;
+        let addr = canonical(addr);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max => prefix,
+                _ => bail!("invalid prefix length in {:?}", cidr),
+            },
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Finds the address of the client behind the proxies we trust
+///
+/// Forwarding headers are only believed if the connection comes from a
+/// trusted proxy. Their addresses are walked from the right, the one that was
+/// added by the closest proxy, and the first untrusted address is the client.
+#[derive(Debug)]
+pub struct ClientIp {
+    trusted: Vec<Cidr>,
+    headers: Vec<Header>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Header {
+    Forwarded,
+    XForwardedFor,
+    XRealIp,
+}
+
+impl ClientIp {
+    pub fn new(config: &ClientIpConfig) -> Result<ClientIp, Error> {
+        let trusted = config
+            .trusted
+            .iter()
+            .map(|cidr| Cidr::parse(cidr))
+            .collect::<Result<_, _>>()?;
+        let headers = config
+            .headers
+            .iter()
+            .map(|name| match name.to_lowercase().as_str() {
+                "forwarded" => Ok(Header::Forwarded),
+                "x-forwarded-for" => Ok(Header::XForwardedFor),
+                "x-real-ip" => Ok(Header::XRealIp),
+                _ => Err(format_err!("unknown client ip header {:?}", name)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(ClientIp { trusted, headers })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The client of a `request` that arrived from `peer`
+    pub fn resolve(&self, request: &Request, peer: IpAddr) -> IpAddr {
+        let peer = canonical(peer);
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+
+        // the first configured header that is present wins
+        let chain = self.headers.iter().find_map(|h| match h {
+            Header::Forwarded => header(request, "forwarded").map(forwarded),
+            Header::XForwardedFor => header(request, "x-forwarded-for").map(forwarded_for),
+            Header::XRealIp => header(request, "x-real-ip").map(|value| vec![parse_ip(value)]),
+        });
+
+        let mut client = peer;
+        for hop in chain.unwrap_or_default().into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = canonical(ip);
+                    if !self.is_trusted(client) {
+                        break;
+                    }
+                }
+                // nothing left of a hop we can't read can be believed
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// `X-Forwarded-For: client, proxy1, proxy2`
+fn forwarded_for(value: &str) -> Vec<Option<IpAddr>> {
+    value.split(',').map(parse_ip).collect()
+}
+
+/// The `for` parameters of RFC 7239 `Forwarded: for=client;proto=https, for=proxy1`
+fn forwarded(value: &str) -> Vec<Option<IpAddr>> {
+    value
+        .split(',')
+        .map(|element| {
+            let node = element.split(';').find_map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                if kv.next()?.trim().eq_ignore_ascii_case("for") {
+                    kv.next()
+                } else {
+                    None
+                }
+            })?;
+            // `unknown` and obfuscated identifiers don't have an address
+            parse_ip(node.trim().trim_matches('"'))
+        })
+        .collect()
+}
+
+/// An address, optionally with a port and ipv6 in brackets
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| value.trim_matches(&['[', ']'][..]).parse::<IpAddr>())
+        .ok()
+}
+
+/// IPv4 addresses that were mapped into IPv6 are treated as IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(hi) << 16 | u32::from(lo)))
+            }
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sniffglue::centrifuge::http;
+
+    use crate::config::ClientIpConfig;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        let mut raw = String::from("GET / HTTP/1.1\r\nHost: example.com\r\n");
+        for (name, value) in headers {
+            raw += &format!("{}: {}\r\n", name, value);
+        }
+        raw += "\r\n";
+        http::extract(raw.as_bytes()).unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn client_ip() -> ClientIp {
+        ClientIp::new(&ClientIpConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn cidr() {
+        let net = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(!net.contains(ip("::1")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("1.2.3.4")));
+        assert!(Cidr::parse("fc00::/7").unwrap().contains(ip("fd12::1")));
+        assert!(Cidr::parse("192.168.1.1")
+            .unwrap()
+            .contains(ip("192.168.1.1")));
+        assert!(!Cidr::parse("192.168.1.1")
+            .unwrap()
+            .contains(ip("192.168.1.2")));
+
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+        assert!(Cidr::parse("fc00::/129").is_err());
+    }
+
+    #[test]
+    fn untrusted_peer() {
+        let req = request(&[("X-Forwarded-For", "1.2.3.4")]);
+        assert_eq!(client_ip().resolve(&req, ip("5.6.7.8")), ip("5.6.7.8"));
+    }
+
+    #[test]
+    fn forwarded_for_chain() {
+        let client_ip = client_ip();
+        let peer = ip("10.0.0.1");
+
+        let req = request(&[("X-Forwarded-For", "1.2.3.4, 10.0.0.5")]);
+        assert_eq!(client_ip.resolve(&req, peer), ip("1.2.3.4"));
+
+        // addresses left of the first untrusted one are made up by the client
+        let req = request(&[("X-Forwarded-For", "9.9.9.9, 1.2.3.4, 10.0.0.5")]);
+        assert_eq!(client_ip.resolve(&req, peer), ip("1.2.3.4"));
+
+        let req = request(&[("X-Forwarded-For", "garbage, 10.0.0.5")]);
+        assert_eq!(client_ip.resolve(&req, peer), ip("10.0.0.5"));
+
+        let req = request(&[("X-Forwarded-For", "[2001:db8::1]:443")]);
+        assert_eq!(client_ip.resolve(&req, peer), ip("2001:db8::1"));
+
+        let req = request(&[]);
+        assert_eq!(client_ip.resolve(&req, peer), peer);
+    }
+
+    #[test]
+    fn forwarded_header() {
+        let client_ip = client_ip();
+        let peer = ip("10.0.0.1");
+
+        let req = request(&[(
+            "Forwarded",
+            r#"for=192.0.2.60;proto=http;by=203.0.113.43, For="[2001:db8:cafe::17]:4711""#,
+        )]);
+        assert_eq!(client_ip.resolve(&req, peer), ip("2001:db8:cafe::17"));
+
+        let req = request(&[("Forwarded", "for=192.0.2.60, for=unknown")]);
+        assert_eq!(client_ip.resolve(&req, peer), peer);
+
+        // forwarded is preferred over x-forwarded-for
+        let req = request(&[
+            ("Forwarded", "for=192.0.2.60"),
+            ("X-Forwarded-For", "1.2.3.4"),
+        ]);
+        assert_eq!(client_ip.resolve(&req, peer), ip("192.0.2.60"));
+    }
+
+    #[test]
+    fn real_ip() {
+        let req = request(&[("X-Real-IP", "1.2.3.4")]);
+        assert_eq!(client_ip().resolve(&req, ip("127.0.0.1")), ip("1.2.3.4"));
+
+        let config = ClientIpConfig {
+            trusted: vec!["192.168.0.0/16".to_string()],
+            headers: vec!["x-real-ip".to_string()],
+        };
+        let client_ip = ClientIp::new(&config).unwrap();
+        let req = request(&[("X-Forwarded-For", "1.2.3.4"), ("X-Real-IP", "5.6.7.8")]);
+        assert_eq!(client_ip.resolve(&req, ip("192.168.1.1")), ip("5.6.7.8"));
+        assert_eq!(client_ip.resolve(&req, ip("10.0.0.1")), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn invalid_config() {
+        let mut config = ClientIpConfig::default();
+        config.headers.push("x-client".to_string());
+        assert!(ClientIp::new(&config).is_err());
+
+        let mut config = ClientIpConfig::default();
+        config.trusted.push("10.0.0.0/40".to_string());
+        assert!(ClientIp::new(&config).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use failure::Error;
+use serde_derive::Deserialize;
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_cardinality")]
+    pub cardinality: Vec<CardinalityConfig>,
+    #[serde(default)]
+    pub route: Vec<RouteConfig>,
+    /// Routes beyond this number are reported as `other`
+    #[serde(default = "default_max_routes")]
+    pub max_routes_per_host: usize,
+    #[serde(default)]
+    pub hosts: HostsConfig,
+    #[serde(default)]
+    pub client_ip: ClientIpConfig,
+    /// Break metrics down by the location of the client
+    pub geoip: Option<GeoIpConfig>,
+}
+
+/// One unique value metric, reported as `{name}_per_{duration}s`
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct CardinalityConfig {
+    pub name: String,
+    /// `header:<name>`, `cookie:<name>`, `query:<name>`, `uri` or `client_ip`
+    pub source: String,
+    /// `uuid`, `ip` or `regex`, values that don't validate are dropped
+    pub validator: Option<String>,
+    /// Required by the `regex` validator. If the pattern has a capture group
+    /// the first group is counted instead of the whole value.
+    pub pattern: Option<String>,
+    /// `exact` (default) or `hyperloglog`
+    pub backend: Option<String>,
+    /// Standard error of the `hyperloglog` backend, defaults to 1%
+    pub error: Option<f64>,
+    /// Also report the most frequent values, as `{name}_top_per_{duration}s`
+    pub top: Option<usize>,
+    /// Longer periods like `1h` or `1d` that values are counted over as well,
+    /// reported as `{name}_per_{resolution}`
+    #[serde(default)]
+    pub resolutions: Vec<String>,
+    /// Static tags, the `host` tag is always added
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+/// Maps matching uris to a route name. Either `pattern`, a path template like
+/// `/recipe/{id}/comments`, or `regex` together with `name` has to be set.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct RouteConfig {
+    pub pattern: Option<String>,
+    pub regex: Option<String>,
+    /// Defaults to the pattern
+    pub name: Option<String>,
+}
+
+/// Which hosts get their own metrics, everything else is reported as `other`.
+/// Patterns are either a `*` glob or `.example.com`, which matches the domain
+/// and all of its subdomains.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct HostsConfig {
+    /// If not empty, only matching hosts are reported
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Checked before the allow list
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Report requests to bare ip addresses under their address
+    #[serde(default = "default_allow_ips")]
+    pub allow_ips: bool,
+}
+
+/// How the client address is found behind load balancers and proxies
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ClientIpConfig {
+    /// Proxies whose forwarding headers are believed, as addresses or cidrs.
+    /// Defaults to loopback and private networks.
+    #[serde(default = "default_trusted")]
+    pub trusted: Vec<String>,
+    /// Forwarding headers that are looked at, the first one present is used
+    #[serde(default = "default_client_ip_headers")]
+    pub headers: Vec<String>,
+}
+
+/// Country and asn tags from local MaxMind databases
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct GeoIpConfig {
+    /// GeoIP2 or GeoLite2 country or city database
+    pub country: Option<String>,
+    /// GeoLite2 ASN database
+    pub asn: Option<String>,
+    /// Client addresses whose location is remembered
+    #[serde(default = "default_geoip_cache")]
+    pub cache_size: usize,
+    /// Metrics that get the tags, names of cardinality metrics or `reqs`
+    #[serde(default = "default_geoip_metrics")]
+    pub metrics: Vec<String>,
+}
+
+impl Default for ClientIpConfig {
+    fn default() -> ClientIpConfig {
+        ClientIpConfig {
+            trusted: default_trusted(),
+            headers: default_client_ip_headers(),
+        }
+    }
+}
+
+impl Default for HostsConfig {
+    fn default() -> HostsConfig {
+        HostsConfig {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            allow_ips: default_allow_ips(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            cardinality: default_cardinality(),
+            route: Vec::new(),
+            max_routes_per_host: default_max_routes(),
+            hosts: HostsConfig::default(),
+            client_ip: ClientIpConfig::default(),
+            geoip: None,
+        }
+    }
+}
+
+fn default_max_routes() -> usize {
+    100
+}
+
+fn default_allow_ips() -> bool {
+    true
+}
+
+fn default_trusted() -> Vec<String> {
+    [
+        "127.0.0.0/8",
+        "10.0.0.0/8",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "::1",
+        "fc00::/7",
+    ]
+    .iter()
+    .map(|cidr| cidr.to_string())
+    .collect()
+}
+
+fn default_client_ip_headers() -> Vec<String> {
+    ["forwarded", "x-forwarded-for", "x-real-ip"]
+        .iter()
+        .map(|header| header.to_string())
+        .collect()
+}
+
+fn default_geoip_cache() -> usize {
+    10_000
+}
+
+/// `reqs` is already tagged by route and method, enriching it is opt-in
+fn default_geoip_metrics() -> Vec<String> {
+    vec!["ips".to_string()]
+}
+
+fn default_cardinality() -> Vec<CardinalityConfig> {
+    vec![
+        CardinalityConfig {
+            name: "ips".to_string(),
+            source: "client_ip".to_string(),
+            validator: None,
+            pattern: None,
+            backend: None,
+            error: None,
+            top: None,
+            resolutions: Vec::new(),
+            tags: HashMap::new(),
+        },
+        CardinalityConfig {
+            name: "pdids".to_string(),
+            source: "header:x-xcf-pdid".to_string(),
+            validator: Some("uuid".to_string()),
+            pattern: None,
+            backend: None,
+            error: None,
+            top: None,
+            resolutions: Vec::new(),
+            tags: HashMap::new(),
+        },
+    ]
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+    let buf = fs::read_to_string(path)?;
+    let config = toml::from_str(&buf)?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn cardinality_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [[cardinality]]
+            name = "sessions"
+            source = "cookie:sid"
+            validator = "regex"
+            pattern = "^([0-9a-f]{32})$"
+            backend = "hyperloglog"
+            error = 0.02
+            top = 20
+            resolutions = ["1h", "1d"]
+            tags = { team = "growth" }
+
+            [[cardinality]]
+            name = "users"
+            source = "query:uid"
+
+            [[route]]
+            pattern = "/recipe/{id}/comments"
+
+            [[route]]
+            regex = "^/api/v[0-9]+/users"
+            name = "/api/users"
+
+            [hosts]
+            allow = [".example.com"]
+            deny = ["*.internal.example.com"]
+
+            [client_ip]
+            trusted = ["10.0.0.0/8"]
+
+            [geoip]
+            country = "/usr/share/GeoIP/GeoLite2-Country.mmdb"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.cardinality.len(), 2);
+        assert_eq!(config.cardinality[0].source, "cookie:sid");
+        assert_eq!(config.cardinality[0].validator, Some("regex".to_string()));
+        assert_eq!(
+            config.cardinality[0].backend,
+            Some("hyperloglog".to_string())
+        );
+        assert_eq!(config.cardinality[0].error, Some(0.02));
+        assert_eq!(config.cardinality[0].top, Some(20));
+        assert_eq!(config.cardinality[0].resolutions, vec!["1h", "1d"]);
+        assert_eq!(config.cardinality[0].tags["team"], "growth");
+        assert_eq!(config.cardinality[1].validator, None);
+        assert_eq!(config.cardinality[1].backend, None);
+        assert_eq!(config.cardinality[1].top, None);
+        assert!(config.cardinality[1].resolutions.is_empty());
+        assert!(config.cardinality[1].tags.is_empty());
+
+        assert_eq!(config.route.len(), 2);
+        assert_eq!(
+            config.route[0].pattern,
+            Some("/recipe/{id}/comments".to_string())
+        );
+        assert_eq!(config.route[1].name, Some("/api/users".to_string()));
+        assert_eq!(config.max_routes_per_host, 100);
+
+        assert_eq!(config.hosts.allow, vec![".example.com".to_string()]);
+        assert_eq!(
+            config.hosts.deny,
+            vec!["*.internal.example.com".to_string()]
+        );
+        assert!(config.hosts.allow_ips);
+
+        assert_eq!(config.client_ip.trusted, vec!["10.0.0.0/8".to_string()]);
+        assert_eq!(config.client_ip.headers.len(), 3);
+
+        let geoip = config.geoip.unwrap();
+        assert_eq!(
+            geoip.country,
+            Some("/usr/share/GeoIP/GeoLite2-Country.mmdb".to_string())
+        );
+        assert_eq!(geoip.asn, None);
+        assert_eq!(geoip.cache_size, 10_000);
+        assert_eq!(geoip.metrics, vec!["ips".to_string()]);
+    }
+}
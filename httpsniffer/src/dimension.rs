@@ -0,0 +1,382 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+
+use failure::{bail, format_err, Error};
+use regex::Regex;
+use uuid::Uuid;
+
+use sniffglue::structs::http::Request;
+
+use crate::config::CardinalityConfig;
+use crate::metrics::Backend;
+use crate::rollup::Resolution;
+
+const DEFAULT_ERROR: f64 = 0.01;
+
+/// Where the value of a dimension is read from
+#[derive(Debug)]
+pub enum Source {
+    Header(String),
+    Cookie(String),
+    Query(String),
+    /// The request target as sent by the client
+    Uri,
+    /// The client address behind trusted proxies
+    ClientIp,
+}
+
+/// Checks a value and brings it into a canonical form
+#[derive(Debug)]
+pub enum Validator {
+    Uuid,
+    Ip,
+    Regex(Regex),
+}
+
+/// A cardinality metric compiled from its config entry
+#[derive(Debug)]
+pub struct Dimension {
+    pub name: String,
+    pub tags: HashMap<String, String>,
+    pub backend: Backend,
+    /// Number of most frequent values reported per window
+    pub top: Option<usize>,
+    /// Longer periods the values are counted over as well
+    pub resolutions: Vec<Resolution>,
+    /// Standard error of the sketches of the resolutions
+    pub error: f64,
+    source: Source,
+    validator: Option<Validator>,
+}
+
+impl Source {
+    fn parse(source: &str) -> Result<Source, Error> {
+        match source {
+            "uri" => return Ok(Source::Uri),
+            "client_ip" => return Ok(Source::ClientIp),
+            _ => {}
+        }
+
+        let mut parts = source.splitn(2, ':');
+        let kind = parts.next().unwrap_or("");
+        let name = match parts.next() {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => bail!("invalid source {:?}, expected <kind>:<name>", source),
+        };
+
+        match kind {
+            // header names are stored lowercase by the parser
+            "header" => Ok(Source::Header(name.to_lowercase())),
+            "cookie" => Ok(Source::Cookie(name)),
+            "query" => Ok(Source::Query(name)),
+            _ => bail!("unknown source kind {:?}", kind),
+        }
+    }
+
+    fn get<'a>(&self, request: &'a Request, client: IpAddr) -> Option<Cow<'a, str>> {
+        match self {
+            Source::Header(name) => header(request, name).map(Cow::Borrowed),
+            Source::Cookie(name) => cookie(request, name).map(Cow::Borrowed),
+            Source::Query(name) => query(request, name).map(Cow::Borrowed),
+            Source::Uri => Some(Cow::Borrowed(&request.uri)),
+            Source::ClientIp => Some(Cow::Owned(client.to_string())),
+        }
+    }
+}
+
+impl Validator {
+    fn parse(validator: &str, pattern: Option<&str>) -> Result<Validator, Error> {
+        match validator {
+            "uuid" => Ok(Validator::Uuid),
+            "ip" => Ok(Validator::Ip),
+            "regex" => {
+                let pattern =
+                    pattern.ok_or_else(|| format_err!("regex validator requires a pattern"))?;
+                Ok(Validator::Regex(Regex::new(pattern)?))
+            }
+            _ => bail!("unknown validator {:?}", validator),
+        }
+    }
+
+    fn normalize(&self, value: &str) -> Option<String> {
+        match self {
+            Validator::Uuid => Uuid::parse_str(&value.replace("-", ""))
+                .ok()
+                .map(|uuid| uuid.to_string()),
+            Validator::Ip => {
+                // proxies may append the port, ipv6 addresses are in brackets then
+                let ip = match value.parse::<IpAddr>() {
+                    Ok(ip) => ip,
+                    Err(_) => value.parse::<SocketAddr>().ok()?.ip(),
+                };
+                Some(ip.to_string())
+            }
+            Validator::Regex(regex) => {
+                let captures = regex.captures(value)?;
+                let value = captures.get(1).or_else(|| captures.get(0))?;
+                Some(value.as_str().to_string())
+            }
+        }
+    }
+}
+
+impl Dimension {
+    pub fn from_config(config: &CardinalityConfig) -> Result<Dimension, Error> {
+        let source = Source::parse(&config.source)?;
+        let validator = match config.validator {
+            Some(ref validator) => Some(Validator::parse(validator, config.pattern.as_deref())?),
+            None => None,
+        };
+
+        let resolutions = config
+            .resolutions
+            .iter()
+            .map(|resolution| {
+                resolution
+                    .parse()
+                    .map_err(|err: String| format_err!("{}", err))
+            })
+            .collect::<Result<Vec<Resolution>, _>>()?;
+
+        // resolutions are always counted with sketches
+        let hyperloglog = config.backend.as_deref() == Some("hyperloglog");
+        let error = config.error.unwrap_or(DEFAULT_ERROR);
+        if (hyperloglog || !resolutions.is_empty()) && !(error > 0.0 && error < 1.0) {
+            bail!("error must be between 0 and 1, got {}", error);
+        }
+        let backend = match config.backend.as_deref() {
+            None | Some("exact") => Backend::Exact,
+            Some("hyperloglog") => Backend::HyperLogLog(error),
+            Some(backend) => bail!("unknown backend {:?}", backend),
+        };
+
+        if config.top == Some(0) {
+            bail!("top must be at least 1");
+        }
+
+        Ok(Dimension {
+            name: config.name.clone(),
+            tags: config.tags.clone(),
+            backend,
+            top: config.top,
+            resolutions,
+            error,
+            source,
+            validator,
+        })
+    }
+
+    /// The normalized value of this dimension, if the request from `client`
+    /// has a valid one
+    pub fn extract(&self, request: &Request, client: IpAddr) -> Option<String> {
+        let value = self.source.get(request, client)?;
+        let value = value.trim();
+        if value.is_empty() {
+            return None;
+        }
+
+        match self.validator {
+            Some(ref validator) => validator.normalize(value),
+            None => Some(value.to_string()),
+        }
+    }
+}
+
+pub fn compile(configs: &[CardinalityConfig]) -> Result<Vec<Dimension>, Error> {
+    configs
+        .iter()
+        .map(|config| {
+            Dimension::from_config(config)
+                .map_err(|err| format_err!("cardinality {:?}: {}", config.name, err))
+        })
+        .collect()
+}
+
+pub fn header<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    // some headers are moved out of extra_headers by the parser
+    let value = match name {
+        "host" => &request.host,
+        "user-agent" => &request.agent,
+        "referer" => &request.referer,
+        "authorization" => &request.auth,
+        "cookie" => &request.cookies,
+        name => request.extra_headers.get(name)?,
+    };
+    value.as_ref().map(String::as_str)
+}
+
+fn cookie<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    let cookies = request.cookies.as_ref()?;
+    cookies.split(';').find_map(|pair| {
+        let mut kv = pair.splitn(2, '=');
+        if kv.next()?.trim() == name {
+            kv.next()
+        } else {
+            None
+        }
+    })
+}
+
+fn query<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    let start = request.uri.find('?')? + 1;
+    let query = request.uri[start..].split('#').next()?;
+    query.split('&').find_map(|pair| {
+        let mut kv = pair.splitn(2, '=');
+        if kv.next()? == name {
+            kv.next()
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sniffglue::centrifuge::http;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request {
+        let mut raw = format!("GET {} HTTP/1.1\r\n", uri);
+        for (name, value) in headers {
+            raw += &format!("{}: {}\r\n", name, value);
+        }
+        raw += "\r\n";
+        http::extract(raw.as_bytes()).unwrap()
+    }
+
+    fn client() -> IpAddr {
+        "192.0.2.1".parse().unwrap()
+    }
+
+    fn dimension(source: &str, validator: Option<&str>, pattern: Option<&str>) -> Dimension {
+        Dimension::from_config(&CardinalityConfig {
+            name: "test".to_string(),
+            source: source.to_string(),
+            validator: validator.map(String::from),
+            pattern: pattern.map(String::from),
+            backend: None,
+            error: None,
+            top: None,
+            resolutions: Vec::new(),
+            tags: HashMap::new(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn sources() {
+        let req = request(
+            "/recipe?id=42&from=home#top",
+            &[("x-pdid", " abc "), ("cookie", "a=1; sid=xyz; b=2")],
+        );
+        assert_eq!(
+            dimension("header:X-Pdid", None, None).extract(&req, client()),
+            Some("abc".to_string())
+        );
+        assert_eq!(
+            dimension("cookie:sid", None, None).extract(&req, client()),
+            Some("xyz".to_string())
+        );
+        assert_eq!(
+            dimension("query:from", None, None).extract(&req, client()),
+            Some("home".to_string())
+        );
+        assert_eq!(
+            dimension("uri", None, None).extract(&req, client()),
+            Some("/recipe?id=42&from=home#top".to_string())
+        );
+        assert_eq!(
+            dimension("client_ip", None, None).extract(&req, client()),
+            Some("192.0.2.1".to_string())
+        );
+        assert_eq!(
+            dimension("query:missing", None, None).extract(&req, client()),
+            None
+        );
+        assert_eq!(
+            dimension("header:x-other", None, None).extract(&req, client()),
+            None
+        );
+    }
+
+    #[test]
+    fn validators() {
+        let req = request(
+            "/",
+            &[
+                ("x-pdid", "0E0E4C0D6B1B4C1B9B1D6B1B4C1B9B1D"),
+                ("x-ip", "10.0.0.1"),
+                ("x-bad", "not valid"),
+                ("x-token", "v1-deadbeef"),
+            ],
+        );
+
+        let uuid = dimension("header:x-pdid", Some("uuid"), None);
+        assert_eq!(
+            uuid.extract(&req, client()),
+            Some("0e0e4c0d-6b1b-4c1b-9b1d-6b1b4c1b9b1d".to_string())
+        );
+        let ip = dimension("header:x-ip", Some("ip"), None);
+        assert_eq!(ip.extract(&req, client()), Some("10.0.0.1".to_string()));
+        for value in &["2001:DB8:0::1", "[2001:db8::1]:443"] {
+            let req = request("/", &[("x-ip", value)]);
+            assert_eq!(ip.extract(&req, client()), Some("2001:db8::1".to_string()));
+        }
+        let regex = dimension("header:x-token", Some("regex"), Some("^v1-([0-9a-f]+)$"));
+        assert_eq!(regex.extract(&req, client()), Some("deadbeef".to_string()));
+
+        for validator in &["uuid", "ip"] {
+            let bad = dimension("header:x-bad", Some(validator), None);
+            assert_eq!(bad.extract(&req, client()), None);
+        }
+        let bad = dimension("header:x-bad", Some("regex"), Some("^v1-"));
+        assert_eq!(bad.extract(&req, client()), None);
+    }
+
+    #[test]
+    fn invalid_config() {
+        let config = |source: &str, validator: Option<&str>| CardinalityConfig {
+            name: "test".to_string(),
+            source: source.to_string(),
+            validator: validator.map(String::from),
+            pattern: None,
+            backend: None,
+            error: None,
+            top: None,
+            resolutions: Vec::new(),
+            tags: HashMap::new(),
+        };
+        assert!(Dimension::from_config(&config("header", None)).is_err());
+        assert!(Dimension::from_config(&config("body:x", None)).is_err());
+        assert!(Dimension::from_config(&config("header:x", Some("email"))).is_err());
+        assert!(Dimension::from_config(&config("header:x", Some("regex"))).is_err());
+
+        let mut hll = config("header:x", None);
+        hll.backend = Some("hyperloglog".to_string());
+        assert_eq!(
+            Dimension::from_config(&hll).unwrap().backend,
+            Backend::HyperLogLog(DEFAULT_ERROR)
+        );
+        hll.error = Some(1.5);
+        assert!(Dimension::from_config(&hll).is_err());
+        hll.backend = Some("bloom".to_string());
+        assert!(Dimension::from_config(&hll).is_err());
+
+        let mut top = config("uri", None);
+        top.top = Some(10);
+        assert_eq!(Dimension::from_config(&top).unwrap().top, Some(10));
+        top.top = Some(0);
+        assert!(Dimension::from_config(&top).is_err());
+
+        let mut daily = config("uri", None);
+        daily.resolutions = vec!["1h".to_string(), "1d".to_string()];
+        let dimension = Dimension::from_config(&daily).unwrap();
+        assert_eq!(dimension.resolutions[1].seconds(), 86_400);
+        assert_eq!(dimension.error, DEFAULT_ERROR);
+        daily.resolutions.push("1w".to_string());
+        assert!(Dimension::from_config(&daily).is_err());
+    }
+}
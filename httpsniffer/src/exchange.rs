@@ -0,0 +1,231 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::mem;
+use std::time::Duration;
+
+use sniffglue::reassembly::{FlowKey, MAX_STREAMS};
+use sniffglue::structs::http::Request;
+
+/// Requests that are waiting for a response on the same connection
+const MAX_PENDING: usize = 64;
+
+/// Which end of the connection sent a segment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// What is kept of a request until its response arrives. The method decides
+/// whether the response has a body and the host is what the response is
+/// recorded under, the whole request is only kept for the HAR file.
+#[derive(Debug)]
+pub struct Pending {
+    pub method: String,
+    pub host: Option<String>,
+    pub request: Option<Request>,
+}
+
+impl Pending {
+    pub fn new(request: Request, keep: bool) -> Pending {
+        Pending {
+            method: request.method.clone(),
+            host: request.host.clone(),
+            request: if keep { Some(request) } else { None },
+        }
+    }
+}
+
+/// Matches responses to the requests that were sent on the same connection.
+/// HTTP/1.x answers requests in order, so a queue per connection is enough.
+pub struct Exchanges<T = Pending> {
+    pending: HashMap<FlowKey, VecDeque<(Duration, T)>>,
+    timeout: Duration,
+    last_sweep: Duration,
+    max_connections: usize,
+    evicted: usize,
+}
+
+impl<T> Exchanges<T> {
+    pub fn new(timeout: Duration) -> Self {
+        Exchanges {
+            pending: HashMap::new(),
+            timeout,
+            last_sweep: Duration::from_secs(0),
+            max_connections: MAX_STREAMS,
+            evicted: 0,
+        }
+    }
+
+    /// Once this many connections have requests waiting, the ones with the
+    /// oldest last request are given up on to make room for new ones
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = cmp::max(max_connections, 1);
+    }
+
+    /// `key` is the client to server direction of the connection. Returns the
+    /// requests that were given up on, because they waited too long or too
+    /// many were waiting on the same connection, with their connection and
+    /// the time they were seen.
+    pub fn request(
+        &mut self,
+        key: FlowKey,
+        request: T,
+        ts: Duration,
+    ) -> Vec<(FlowKey, Duration, T)> {
+        let mut expired = self.sweep(ts);
+        if self.pending.len() >= self.max_connections && !self.pending.contains_key(&key) {
+            expired.extend(self.evict());
+        }
+
+        let queue = self.pending.entry(key).or_default();
+        if queue.len() >= MAX_PENDING {
+            expired.extend(
+                queue
+                    .pop_front()
+                    .map(|(started, request)| (key, started, request)),
+            );
+        }
+        queue.push_back((ts, request));
+        expired
+    }
+
+    /// `key` is the server to client direction of the connection. Returns the
+    /// oldest unanswered request and the time it was seen.
+    pub fn response(&mut self, key: &FlowKey) -> Option<(Duration, T)> {
+        let key = key.reverse();
+        let queue = self.pending.get_mut(&key)?;
+        let exchange = queue.pop_front();
+        if queue.is_empty() {
+            self.pending.remove(&key);
+        }
+        exchange
+    }
+
+    /// Every request that is still waiting for a response
+    pub fn drain(&mut self) -> Vec<(FlowKey, Duration, T)> {
+        self.pending
+            .drain()
+            .flat_map(|(key, queue)| {
+                queue
+                    .into_iter()
+                    .map(move |(started, request)| (key, started, request))
+            })
+            .collect()
+    }
+
+    /// Give up on the eighth of the connections whose last request is the
+    /// oldest, like `Reassembler` does with its streams
+    fn evict(&mut self) -> Vec<(FlowKey, Duration, T)> {
+        let mut last_seen: Vec<(Duration, FlowKey)> = self
+            .pending
+            .iter()
+            .filter_map(|(key, queue)| queue.back().map(|(started, _)| (*started, *key)))
+            .collect();
+        last_seen.sort_unstable_by_key(|&(last_seen, _)| last_seen);
+        last_seen.truncate(cmp::max(last_seen.len() / 8, 1));
+
+        self.evicted += last_seen.len();
+        let mut evicted = Vec::new();
+        for (_, key) in last_seen {
+            if let Some(queue) = self.pending.remove(&key) {
+                evicted.extend(
+                    queue
+                        .into_iter()
+                        .map(|(started, request)| (key, started, request)),
+                );
+            }
+        }
+        evicted
+    }
+
+    /// Number of connections that were given up on to stay below the limit
+    /// since the last call
+    pub fn take_evicted(&mut self) -> usize {
+        mem::replace(&mut self.evicted, 0)
+    }
+
+    fn sweep(&mut self, ts: Duration) -> Vec<(FlowKey, Duration, T)> {
+        if ts < self.last_sweep + self.timeout {
+            return Vec::new();
+        }
+        self.last_sweep = ts;
+
+        let timeout = self.timeout;
+        let idle: Vec<FlowKey> = self
+            .pending
+            .iter()
+            .filter(|(_, queue)| match queue.back() {
+                Some((started, _)) => ts >= *started + timeout,
+                None => true,
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        let mut expired = Vec::new();
+        for key in idle {
+            if let Some(queue) = self.pending.remove(&key) {
+                expired.extend(
+                    queue
+                        .into_iter()
+                        .map(|(started, request)| (key, started, request)),
+                );
+            }
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(port: u16) -> FlowKey {
+        FlowKey {
+            source_addr: "10.0.0.1".parse().unwrap(),
+            source_port: port,
+            dest_addr: "10.0.0.2".parse().unwrap(),
+            dest_port: 80,
+        }
+    }
+
+    fn ts(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn pending_without_har() {
+        let request = sniffglue::centrifuge::http::extract(
+            b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 2\r\n\r\nok",
+        )
+        .unwrap();
+        let pending = Pending::new(request, false);
+        assert_eq!(pending.method, "POST");
+        assert_eq!(pending.host, Some("example.com".to_string()));
+        assert!(pending.request.is_none());
+    }
+
+    #[test]
+    fn max_connections() {
+        let mut exchanges = Exchanges::new(ts(60));
+        exchanges.set_max_connections(16);
+        for port in 0..16 {
+            assert!(exchanges
+                .request(key(port), port, ts(1 + u64::from(port)))
+                .is_empty());
+        }
+        assert_eq!(exchanges.take_evicted(), 0);
+
+        // the two connections that were seen first make room
+        let expired = exchanges.request(key(16), 16, ts(20));
+        let mut ports: Vec<_> = expired.iter().map(|(key, _, _)| key.source_port).collect();
+        ports.sort();
+        assert_eq!(ports, vec![0, 1]);
+        assert_eq!(exchanges.take_evicted(), 2);
+        assert_eq!(exchanges.take_evicted(), 0);
+
+        // more requests on a known connection don't evict anything
+        assert!(exchanges.request(key(16), 17, ts(21)).is_empty());
+        assert_eq!(exchanges.response(&key(16).reverse()), Some((ts(20), 16)));
+    }
+}
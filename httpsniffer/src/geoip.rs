@@ -0,0 +1,356 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use failure::{bail, format_err, Error};
+use lru::LruCache;
+use maxminddb::geoip2;
+use maxminddb::Reader;
+
+use crate::config::GeoIpConfig;
+
+/// Tag value of addresses that aren't in a database
+pub const UNKNOWN: &str = "unknown";
+
+/// Result of looking up an address in one database
+#[derive(Debug, Clone, PartialEq)]
+enum Lookup {
+    /// The database isn't configured
+    Disabled,
+    Miss,
+    Found(String),
+}
+
+/// Country and autonomous system of a client address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    country: Lookup,
+    asn: Lookup,
+}
+
+impl Location {
+    fn fields(&self) -> impl Iterator<Item = (&'static str, &Lookup)> {
+        vec![("country", &self.country), ("asn", &self.asn)].into_iter()
+    }
+
+    /// `country` and `asn` tags of the configured databases
+    pub fn tags(&self) -> Vec<(&'static str, &str)> {
+        self.fields()
+            .filter_map(|(name, lookup)| match lookup {
+                Lookup::Disabled => None,
+                Lookup::Miss => Some((name, UNKNOWN)),
+                Lookup::Found(value) => Some((name, value.as_str())),
+            })
+            .collect()
+    }
+
+    /// Databases that didn't know the address
+    pub fn misses(&self) -> Vec<&'static str> {
+        self.fields()
+            .filter(|(_, lookup)| **lookup == Lookup::Miss)
+            .map(|(name, _)| name)
+            .collect()
+    }
+}
+
+/// Looks up client addresses in local MaxMind databases, which are read once
+/// at startup. Recent results are cached, so busy clients cost one lookup.
+pub struct GeoIp {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+    cache: LruCache<IpAddr, Location>,
+    metrics: HashSet<String>,
+}
+
+impl GeoIp {
+    pub fn open(config: &GeoIpConfig) -> Result<GeoIp, Error> {
+        let open = |path: &Option<String>| -> Result<_, Error> {
+            match path {
+                Some(path) => Reader::open_readfile(path)
+                    .map(Some)
+                    .map_err(|err| format_err!("{:?}: {}", path, err)),
+                None => Ok(None),
+            }
+        };
+        let country = open(&config.country)?;
+        let asn = open(&config.asn)?;
+        if country.is_none() && asn.is_none() {
+            bail!("geoip requires a country or asn database");
+        }
+        if config.cache_size == 0 {
+            bail!("geoip cache_size must be at least 1");
+        }
+
+        Ok(GeoIp {
+            country,
+            asn,
+            cache: LruCache::new(config.cache_size),
+            metrics: config.metrics.iter().cloned().collect(),
+        })
+    }
+
+    /// Metrics that get the location tags
+    pub fn metrics(&self) -> impl Iterator<Item = &str> {
+        self.metrics.iter().map(String::as_str)
+    }
+
+    /// Whether `metric` is broken down by location
+    pub fn enriches(&self, metric: &str) -> bool {
+        self.metrics.contains(metric)
+    }
+
+    pub fn lookup(&mut self, ip: IpAddr) -> Location {
+        if let Some(location) = self.cache.get(&ip) {
+            return location.clone();
+        }
+
+        let location = Location {
+            country: lookup(&self.country, ip, |country: geoip2::Country| {
+                country
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .map(String::from)
+            }),
+            asn: lookup(&self.asn, ip, |asn: geoip2::Asn| {
+                asn.autonomous_system_number.map(|asn| asn.to_string())
+            }),
+        };
+        self.cache.put(ip, location.clone());
+        location
+    }
+}
+
+fn lookup<'a, T, F>(reader: &'a Option<Reader<Vec<u8>>>, ip: IpAddr, f: F) -> Lookup
+where
+    T: serde::Deserialize<'a>,
+    F: FnOnce(T) -> Option<String>,
+{
+    let reader = match reader {
+        Some(reader) => reader,
+        None => return Lookup::Disabled,
+    };
+    // an ipv4 only database can't answer for ipv6 clients
+    if ip.is_ipv6() && reader.metadata.ip_version == 4 {
+        return Lookup::Miss;
+    }
+    match reader.lookup(ip).ok().and_then(f) {
+        Some(value) => Lookup::Found(value),
+        None => Lookup::Miss,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::net::Ipv4Addr;
+    use std::path::PathBuf;
+
+    /// Encodes values of the MaxMind DB data section
+    enum Value {
+        Str(&'static str),
+        U16(u16),
+        U32(u32),
+        U64(u64),
+        Map(Vec<(&'static str, Value)>),
+        Array(Vec<Value>),
+    }
+
+    impl Value {
+        fn encode(&self, out: &mut Vec<u8>) {
+            let uint = |out: &mut Vec<u8>, kind: u8, value: u64| {
+                let bytes: Vec<u8> = value
+                    .to_be_bytes()
+                    .iter()
+                    .cloned()
+                    .skip_while(|b| *b == 0)
+                    .collect();
+                if kind < 8 {
+                    out.push(kind << 5 | bytes.len() as u8);
+                } else {
+                    out.push(bytes.len() as u8);
+                    out.push(kind - 7);
+                }
+                out.extend(bytes);
+            };
+            match self {
+                Value::Str(s) => {
+                    out.push(2 << 5 | s.len() as u8);
+                    out.extend_from_slice(s.as_bytes());
+                }
+                Value::U16(n) => uint(out, 5, u64::from(*n)),
+                Value::U32(n) => uint(out, 6, u64::from(*n)),
+                Value::U64(n) => uint(out, 9, *n),
+                Value::Map(entries) => {
+                    out.push(7 << 5 | entries.len() as u8);
+                    for (key, value) in entries {
+                        Value::Str(key).encode(out);
+                        value.encode(out);
+                    }
+                }
+                Value::Array(values) => {
+                    out.extend_from_slice(&[values.len() as u8, 11 - 7]);
+                    for value in values {
+                        value.encode(out);
+                    }
+                }
+            }
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    /// Writes an ipv4 database with 24 bit records that maps `networks` to
+    /// their values
+    fn write_db(name: &str, kind: &'static str, networks: Vec<(Ipv4Addr, u32, Value)>) -> PathBuf {
+        let mut nodes = vec![[Record::Empty; 2]];
+        let mut data = Vec::new();
+        for (addr, prefix, value) in networks {
+            let offset = data.len();
+            value.encode(&mut data);
+
+            let bits = u32::from(addr);
+            let mut node = 0;
+            for i in 0..prefix {
+                let bit = (bits >> (31 - i) & 1) as usize;
+                if i == prefix - 1 {
+                    nodes[node][bit] = Record::Data(offset);
+                } else {
+                    node = match nodes[node][bit] {
+                        Record::Node(next) => next,
+                        _ => {
+                            nodes.push([Record::Empty; 2]);
+                            nodes[node][bit] = Record::Node(nodes.len() - 1);
+                            nodes.len() - 1
+                        }
+                    };
+                }
+            }
+        }
+
+        let count = nodes.len();
+        let mut db = Vec::new();
+        for node in &nodes {
+            for record in node {
+                let value = match *record {
+                    Record::Empty => count,
+                    Record::Node(next) => next,
+                    Record::Data(offset) => count + 16 + offset,
+                };
+                db.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+            }
+        }
+        db.extend_from_slice(&[0; 16]);
+        db.extend(data);
+        db.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        Value::Map(vec![
+            ("binary_format_major_version", Value::U16(2)),
+            ("binary_format_minor_version", Value::U16(0)),
+            ("build_epoch", Value::U64(1_556_000_000)),
+            ("database_type", Value::Str(kind)),
+            ("description", Value::Map(Vec::new())),
+            ("ip_version", Value::U16(4)),
+            ("languages", Value::Array(Vec::new())),
+            ("node_count", Value::U32(count as u32)),
+            ("record_size", Value::U16(24)),
+        ])
+        .encode(&mut db);
+
+        let path =
+            std::env::temp_dir().join(format!("httpsniffer-{}-{}.mmdb", name, std::process::id()));
+        fs::write(&path, db).unwrap();
+        path
+    }
+
+    fn country(iso_code: &'static str) -> Value {
+        Value::Map(vec![(
+            "country",
+            Value::Map(vec![("iso_code", Value::Str(iso_code))]),
+        )])
+    }
+
+    fn config(country: Option<&PathBuf>, asn: Option<&PathBuf>) -> GeoIpConfig {
+        GeoIpConfig {
+            country: country.map(|path| path.display().to_string()),
+            asn: asn.map(|path| path.display().to_string()),
+            cache_size: 2,
+            metrics: vec!["ips".to_string()],
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn lookup_country_and_asn() {
+        let countries = write_db(
+            "country",
+            "GeoLite2-Country",
+            vec![
+                (Ipv4Addr::new(1, 0, 0, 0), 8, country("AU")),
+                (Ipv4Addr::new(81, 2, 0, 0), 16, country("DE")),
+            ],
+        );
+        let asns = write_db(
+            "asn",
+            "GeoLite2-ASN",
+            vec![(
+                Ipv4Addr::new(1, 1, 1, 0),
+                24,
+                Value::Map(vec![("autonomous_system_number", Value::U32(13335))]),
+            )],
+        );
+        let mut geoip = GeoIp::open(&config(Some(&countries), Some(&asns))).unwrap();
+        fs::remove_file(countries).unwrap();
+        fs::remove_file(asns).unwrap();
+
+        let location = geoip.lookup(ip("1.1.1.1"));
+        assert_eq!(location.tags(), vec![("country", "AU"), ("asn", "13335")]);
+        assert!(location.misses().is_empty());
+
+        let location = geoip.lookup(ip("81.2.69.142"));
+        assert_eq!(location.tags(), vec![("country", "DE"), ("asn", UNKNOWN)]);
+        assert_eq!(location.misses(), vec!["asn"]);
+
+        for addr in &["10.0.0.1", "2001:db8::1"] {
+            let location = geoip.lookup(ip(addr));
+            assert_eq!(
+                location.tags(),
+                vec![("country", UNKNOWN), ("asn", UNKNOWN)]
+            );
+            assert_eq!(location.misses(), vec!["country", "asn"]);
+        }
+
+        // results are cached
+        assert_eq!(geoip.cache.len(), 2);
+        assert!(geoip.cache.contains(&ip("2001:db8::1")));
+        assert!(geoip.enriches("ips"));
+        assert!(!geoip.enriches("reqs"));
+    }
+
+    #[test]
+    fn only_configured_databases() {
+        let countries = write_db(
+            "country-only",
+            "GeoLite2-Country",
+            vec![(Ipv4Addr::new(1, 0, 0, 0), 8, country("AU"))],
+        );
+        let mut geoip = GeoIp::open(&config(Some(&countries), None)).unwrap();
+        fs::remove_file(countries).unwrap();
+
+        assert_eq!(geoip.lookup(ip("1.2.3.4")).tags(), vec![("country", "AU")]);
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!(GeoIp::open(&config(None, None)).is_err());
+        let missing = PathBuf::from("/nonexistent/GeoLite2-Country.mmdb");
+        assert!(GeoIp::open(&config(Some(&missing), None)).is_err());
+    }
+}
@@ -0,0 +1,476 @@
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use sniffglue::reassembly::FlowKey;
+use sniffglue::structs::http::{Request, Response};
+
+use crate::accesslog::rfc3339;
+use crate::dimension::header;
+
+/// Buffered entries are written at least this often while requests arrive
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Headers that the parser keeps in their own fields
+const REQUEST_HEADERS: [&str; 5] = ["host", "user-agent", "referer", "authorization", "cookie"];
+
+/// Headers whose values are replaced unless credentials are kept
+const CREDENTIAL_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+/// Written instead of credentials and cookie values
+const REDACTED: &str = "redacted";
+
+/// Writes captured exchanges as a HAR 1.2 document, for browser devtools and
+/// other HAR tools
+///
+/// Requests are paired with their responses by the caller. Requests that
+/// were never answered are written with an empty response of status 0. The
+/// document is closed when the writer is dropped.
+pub struct Har {
+    out: Box<dyn Write + Send>,
+    /// Keep authorization headers and cookie values instead of redacting them
+    credentials: bool,
+    entries: usize,
+    flushed: Instant,
+}
+
+impl Har {
+    /// `-` writes to stdout, everything else is a file that is overwritten
+    pub fn open(path: &str, credentials: bool) -> io::Result<Har> {
+        let out: Box<dyn Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        Ok(Har::new(out, credentials))
+    }
+
+    pub fn new(out: Box<dyn Write + Send>, credentials: bool) -> Har {
+        Har {
+            out,
+            credentials,
+            entries: 0,
+            flushed: Instant::now(),
+        }
+    }
+
+    /// A request sent at `started` that was answered at `ts`. `key` is the
+    /// client to server direction of the connection.
+    pub fn exchange(
+        &mut self,
+        key: &FlowKey,
+        request: &Request,
+        started: Duration,
+        response: &Response,
+        ts: Duration,
+    ) {
+        let mut entry = entry(key, request, started, self.credentials);
+        // bodies aren't reassembled, so all of the time is spent waiting
+        let wait = ts.checked_sub(started).unwrap_or_default().as_secs_f64() * 1000.0;
+        entry["time"] = json!(wait);
+        entry["response"] = response_json(response, &request.method, self.credentials);
+        entry["timings"] = json!({"send": 0, "wait": wait, "receive": 0});
+        self.write(&entry);
+    }
+
+    /// A request sent at `started` that no response was captured for
+    pub fn unanswered(&mut self, key: &FlowKey, request: &Request, started: Duration) {
+        let entry = entry(key, request, started, self.credentials);
+        self.write(&entry);
+    }
+
+    pub fn flush(&mut self) {
+        self.flushed = Instant::now();
+        if let Err(err) = self.out.flush() {
+            eprintln!("har error: {:?}", err);
+        }
+    }
+
+    fn write(&mut self, entry: &Value) {
+        let result = if self.entries == 0 {
+            write!(self.out, "{}\n{}", preamble(), entry)
+        } else {
+            write!(self.out, ",\n{}", entry)
+        };
+        if let Err(err) = result {
+            eprintln!("har error: {:?}", err);
+        }
+        self.entries += 1;
+        if self.flushed.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Ends the document
+    fn finish(&mut self) {
+        let result = if self.entries == 0 {
+            writeln!(self.out, "{}]}}}}", preamble())
+        } else {
+            writeln!(self.out, "\n]}}}}")
+        };
+        if let Err(err) = result {
+            eprintln!("har error: {:?}", err);
+        }
+        self.flush();
+    }
+}
+
+impl Drop for Har {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Everything in front of the first entry
+fn preamble() -> String {
+    format!(
+        r#"{{"log":{{"version":"1.2","creator":{{"name":"httpsniffer","version":"{}"}},"entries":["#,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// An entry of a request that hasn't been answered (yet)
+fn entry(key: &FlowKey, request: &Request, ts: Duration, credentials: bool) -> Value {
+    json!({
+        "startedDateTime": rfc3339(ts),
+        "time": 0,
+        "request": request_json(key, request, credentials),
+        "response": {
+            "status": 0,
+            "statusText": "",
+            "httpVersion": "",
+            "cookies": [],
+            "headers": [],
+            "content": {"size": 0, "mimeType": ""},
+            "redirectURL": "",
+            "headersSize": -1,
+            "bodySize": -1,
+            "comment": "no response was captured",
+        },
+        "cache": {},
+        "timings": {"send": 0, "wait": 0, "receive": 0},
+        "serverIPAddress": key.dest_addr.to_string(),
+        "connection": key.source_port.to_string(),
+    })
+}
+
+fn request_json(key: &FlowKey, request: &Request, credentials: bool) -> Value {
+    let url = if request.uri.starts_with("http://") || request.uri.starts_with("https://") {
+        request.uri.clone()
+    } else {
+        match request.host {
+            Some(ref host) => format!("http://{}{}", host, request.uri),
+            None => format!(
+                "http://{}{}",
+                SocketAddr::new(key.dest_addr, key.dest_port),
+                request.uri
+            ),
+        }
+    };
+
+    let mut extra: Vec<_> = request
+        .extra_headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.as_ref()?.as_str())))
+        .collect();
+    extra.sort();
+    let headers = REQUEST_HEADERS
+        .iter()
+        .filter_map(|name| Some((*name, header(request, name)?)))
+        .chain(extra)
+        .map(|(name, value)| (name, redact(name, value, credentials)));
+
+    let cookies = request
+        .cookies
+        .iter()
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| cookie(pair, credentials));
+
+    // the body may have been cut off, the header knows its real size
+    let body_size = header(request, "content-length")
+        .and_then(|len| len.trim().parse().ok())
+        .unwrap_or(request.data.len());
+
+    let mut json = json!({
+        "method": request.method,
+        "url": url,
+        "httpVersion": format!("HTTP/{}", request.version),
+        "cookies": cookies.collect::<Vec<_>>(),
+        "headers": pairs(headers),
+        "queryString": query_string(&request.uri),
+        "headersSize": -1,
+        "bodySize": body_size,
+    });
+    if !request.data.is_empty() {
+        json["postData"] = json!({
+            "mimeType": header(request, "content-type").unwrap_or(""),
+            "text": request.data,
+        });
+    }
+    json
+}
+
+/// `method` is the method of the request, a response to HEAD has no body
+fn response_json(response: &Response, method: &str, credentials: bool) -> Value {
+    let get = |name: &str| response.headers.get(name).and_then(Option::as_deref);
+
+    let mut headers: Vec<_> = response
+        .headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let value = redact(name, value.as_ref()?, credentials);
+            Some((name.as_str(), value))
+        })
+        .collect();
+    headers.sort();
+
+    // the parser keeps one value of repeated headers, so at most one cookie
+    let cookies: Vec<_> = get("set-cookie")
+        .and_then(|value| value.split(';').next())
+        .and_then(|pair| cookie(pair, credentials))
+        .into_iter()
+        .collect();
+
+    let body_size = if !response.has_body_for(method) {
+        0
+    } else {
+        response.content_length.map_or(-1, |len| len as i64)
+    };
+
+    json!({
+        "status": response.status,
+        "statusText": response.reason,
+        "httpVersion": format!("HTTP/{}", response.version),
+        "cookies": cookies,
+        "headers": pairs(headers.into_iter()),
+        "content": {
+            "size": body_size.max(0),
+            "mimeType": get("content-type").unwrap_or(""),
+        },
+        "redirectURL": get("location").unwrap_or(""),
+        "headersSize": -1,
+        "bodySize": body_size,
+    })
+}
+
+fn pairs<'a>(pairs: impl Iterator<Item = (&'a str, &'a str)>) -> Vec<Value> {
+    pairs
+        .map(|(name, value)| json!({"name": name, "value": value}))
+        .collect()
+}
+
+/// The value of a header, or a placeholder if it carries credentials that
+/// aren't kept
+fn redact<'a>(name: &str, value: &'a str, credentials: bool) -> &'a str {
+    let secret = CREDENTIAL_HEADERS
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name));
+    if secret && !credentials {
+        REDACTED
+    } else {
+        value
+    }
+}
+
+/// `name=value`, surrounding whitespace is dropped. The value is only kept
+/// with `credentials`.
+fn cookie(pair: &str, credentials: bool) -> Option<Value> {
+    let mut parts = pair.trim().splitn(2, '=');
+    let name = parts.next().filter(|name| !name.is_empty())?;
+    let value = if credentials {
+        parts.next().unwrap_or("")
+    } else {
+        REDACTED
+    };
+    Some(json!({"name": name, "value": value}))
+}
+
+/// The parameters of the query, as they were sent
+fn query_string(uri: &str) -> Vec<Value> {
+    let query = match uri.find('?') {
+        Some(start) => &uri[start + 1..],
+        None => return Vec::new(),
+    };
+    query
+        .split('#')
+        .next()
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            json!({
+                "name": parts.next().unwrap_or(""),
+                "value": parts.next().unwrap_or(""),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sniffglue::centrifuge::http;
+
+    use crate::testutil::{Buffer, TS};
+
+    fn har(credentials: bool) -> (Har, Buffer) {
+        let buf = Buffer::default();
+        (Har::new(Box::new(buf.clone()), credentials), buf)
+    }
+
+    fn document(buf: &Buffer) -> Value {
+        serde_json::from_str(&buf.contents()).unwrap()
+    }
+
+    fn key(port: u16) -> FlowKey {
+        FlowKey {
+            source_addr: "10.0.0.1".parse().unwrap(),
+            source_port: port,
+            dest_addr: "10.0.0.2".parse().unwrap(),
+            dest_port: 80,
+        }
+    }
+
+    const LOGIN: &[u8] = b"POST /login?next=%2F&debug HTTP/1.1\r\nHost: example.com\r\nAuthorization: Basic bWU6c2VjcmV0\r\nCookie: a=1; b=2\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 7\r\n\r\nuser=me";
+    const REDIRECT: &[u8] = b"HTTP/1.1 302 Found\r\nLocation: /\r\nSet-Cookie: session=abc; HttpOnly\r\nContent-Length: 0\r\n\r\n";
+
+    /// The only entry of a document with one exchange of `request` and `response`
+    fn exchange(credentials: bool, request: &[u8], response: &[u8]) -> Value {
+        let (mut har, buf) = har(credentials);
+        let req = http::extract(request).unwrap();
+        let res = http::extract_response(response).unwrap();
+        har.exchange(&key(5000), &req, TS, &res, TS + Duration::from_millis(120));
+        drop(har);
+
+        let doc = document(&buf);
+        assert_eq!(doc["log"]["version"], "1.2");
+        assert_eq!(doc["log"]["creator"]["name"], "httpsniffer");
+        let entries = doc["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        entries[0].clone()
+    }
+
+    fn header<'a>(headers: &'a Value, name: &str) -> &'a Value {
+        &headers
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|header| header["name"] == name)
+            .unwrap()["value"]
+    }
+
+    #[test]
+    fn entry() {
+        let entry = exchange(true, LOGIN, REDIRECT);
+        assert_eq!(entry["startedDateTime"], "2019-04-23T06:13:20.250Z");
+        assert_eq!(entry["time"], 120.0);
+        assert_eq!(entry["timings"]["wait"], 120.0);
+        assert_eq!(entry["serverIPAddress"], "10.0.0.2");
+        assert_eq!(entry["connection"], "5000");
+
+        let request = &entry["request"];
+        assert_eq!(request["method"], "POST");
+        assert_eq!(request["url"], "http://example.com/login?next=%2F&debug");
+        assert_eq!(request["httpVersion"], "HTTP/1.1");
+        assert_eq!(
+            request["queryString"],
+            json!([{"name": "next", "value": "%2F"}, {"name": "debug", "value": ""}])
+        );
+        assert_eq!(
+            request["cookies"],
+            json!([{"name": "a", "value": "1"}, {"name": "b", "value": "2"}])
+        );
+        assert_eq!(
+            request["headers"][0],
+            json!({"name": "host", "value": "example.com"})
+        );
+        assert_eq!(
+            header(&request["headers"], "authorization"),
+            "Basic bWU6c2VjcmV0"
+        );
+        assert_eq!(request["bodySize"], 7);
+        assert_eq!(
+            request["postData"],
+            json!({"mimeType": "application/x-www-form-urlencoded", "text": "user=me"})
+        );
+
+        let response = &entry["response"];
+        assert_eq!(response["status"], 302);
+        assert_eq!(response["statusText"], "Found");
+        assert_eq!(response["redirectURL"], "/");
+        assert_eq!(
+            response["cookies"],
+            json!([{"name": "session", "value": "abc"}])
+        );
+        assert_eq!(response["bodySize"], 0);
+    }
+
+    #[test]
+    fn redact_credentials() {
+        let entry = exchange(false, LOGIN, REDIRECT);
+        let request = &entry["request"];
+        assert_eq!(header(&request["headers"], "authorization"), REDACTED);
+        assert_eq!(header(&request["headers"], "cookie"), REDACTED);
+        assert_eq!(
+            request["cookies"],
+            json!([{"name": "a", "value": REDACTED}, {"name": "b", "value": REDACTED}])
+        );
+        assert_eq!(header(&request["headers"], "host"), "example.com");
+
+        let response = &entry["response"];
+        assert_eq!(header(&response["headers"], "set-cookie"), REDACTED);
+        assert_eq!(
+            response["cookies"],
+            json!([{"name": "session", "value": REDACTED}])
+        );
+    }
+
+    #[test]
+    fn head() {
+        let entry = exchange(
+            false,
+            b"HEAD /a HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1024\r\n\r\n",
+        );
+        assert_eq!(entry["response"]["bodySize"], 0);
+    }
+
+    #[test]
+    fn unanswered() {
+        let (mut har, buf) = har(false);
+        let req = http::extract(b"GET /a HTTP/1.1\r\nAccept: */*\r\n\r\n").unwrap();
+        har.unanswered(&key(5000), &req, TS);
+        har.unanswered(&key(5001), &req, TS + Duration::from_secs(1));
+        assert_eq!(har.entries, 2);
+        drop(har);
+
+        let doc = document(&buf);
+        let entries = doc["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        for entry in entries {
+            assert_eq!(entry["response"]["status"], 0);
+            // without a host the server address is used
+            assert_eq!(entry["request"]["url"], "http://10.0.0.2:80/a");
+        }
+        assert_eq!(entries[1]["connection"], "5001");
+    }
+
+    #[test]
+    fn empty() {
+        let (har, buf) = har(false);
+        drop(har);
+        assert_eq!(document(&buf)["log"]["entries"], json!([]));
+    }
+}
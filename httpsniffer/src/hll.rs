@@ -0,0 +1,220 @@
+use std::hash::Hasher;
+
+use siphasher::sip::SipHasher13;
+
+const MIN_PRECISION: u32 = 4;
+const MAX_PRECISION: u32 = 18;
+
+/// Keys of the SipHash-1-3 that items are hashed with. Registers are only
+/// comparable between sketches that hash the same way, so saved sketches
+/// depend on these.
+const HASH_KEYS: (u64, u64) = (0x6874_7470_736e_6966, 0x6665_722d_686c_6c31);
+
+/// Names the hash function and its keys, changes whenever either does
+pub const HASH_ID: &str = "siphash13-68747470736e6966-6665722d686c6c31";
+
+/// HyperLogLog cardinality estimator
+///
+/// Uses `2^precision` one byte registers regardless of how many items are
+/// added. The standard error of the estimate is about `1.04 / sqrt(2^precision)`.
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    precision: u32,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// The smallest sketch whose standard error is at most `error`
+    pub fn new(error: f64) -> HyperLogLog {
+        HyperLogLog::with_precision(HyperLogLog::precision_for(error))
+    }
+
+    /// Precision of the smallest sketch whose standard error is at most `error`
+    pub fn precision_for(error: f64) -> u32 {
+        let registers = (1.04 / error).powi(2);
+        (registers.log2().ceil() as u32).clamp(MIN_PRECISION, MAX_PRECISION)
+    }
+
+    pub fn with_precision(precision: u32) -> HyperLogLog {
+        let precision = precision.clamp(MIN_PRECISION, MAX_PRECISION);
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    /// Returns true if the sketch changed, which means the item is new. The
+    /// opposite isn't true, a new item doesn't necessarily change the sketch.
+    pub fn insert(&mut self, item: &str) -> bool {
+        let hash = hash(item);
+
+        let index = (hash >> (64 - self.precision)) as usize;
+        // the guard bit limits the rank to the bits that are left
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+
+        let mut sum = 0.0;
+        let mut zeros = 0;
+        for &register in &self.registers {
+            sum += 1.0 / f64::from(1u32 << register.min(31));
+            if register == 0 {
+                zeros += 1;
+            }
+        }
+
+        let estimate = alpha * m * m / sum;
+        // linear counting is more accurate for small cardinalities
+        let estimate = if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / f64::from(zeros)).ln()
+        } else {
+            estimate
+        };
+        estimate.round() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registers.iter().all(|&register| register == 0)
+    }
+
+    pub fn precision(&self) -> u32 {
+        self.precision
+    }
+
+    /// The raw registers, for saving the sketch
+    pub fn registers(&self) -> &[u8] {
+        &self.registers
+    }
+
+    /// A sketch saved with `registers`, `None` if the number of registers
+    /// isn't a valid precision
+    pub fn from_registers(registers: Vec<u8>) -> Option<HyperLogLog> {
+        let precision = registers.len().trailing_zeros();
+        if registers.len() != 1 << precision
+            || !(MIN_PRECISION..=MAX_PRECISION).contains(&precision)
+        {
+            return None;
+        }
+        Some(HyperLogLog {
+            precision,
+            registers,
+        })
+    }
+
+    /// Add everything `other` has seen, both need the same precision
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        debug_assert_eq!(self.precision, other.precision);
+        for (register, &theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(theirs);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for register in self.registers.iter_mut() {
+            *register = 0;
+        }
+    }
+}
+
+/// SipHash-1-3 of the UTF-8 bytes of `item`. Unlike the std hashers this is
+/// the same on every Rust release and platform.
+fn hash(item: &str) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(HASH_KEYS.0, HASH_KEYS.1);
+    hasher.write(item.as_bytes());
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_hash() {
+        // saved sketches rely on these, see HASH_ID
+        assert_eq!(hash(""), 0x0a96_4507_7e9c_d213);
+        assert_eq!(hash("10.0.0.1"), 0x2529_b200_6fee_3c10);
+        assert_eq!(
+            hash("a long value that spans more than one block"),
+            0x5f46_24ea_715d_8a67
+        );
+    }
+
+    #[test]
+    fn precision_from_error() {
+        assert_eq!(HyperLogLog::new(0.01).registers.len(), 1 << 14);
+        assert_eq!(HyperLogLog::new(0.05).registers.len(), 1 << 9);
+        assert_eq!(HyperLogLog::new(0.5).registers.len(), 1 << MIN_PRECISION);
+        assert_eq!(HyperLogLog::new(0.0001).registers.len(), 1 << MAX_PRECISION);
+    }
+
+    #[test]
+    fn estimate_within_error() {
+        for &n in &[0usize, 10, 1000, 100_000] {
+            let mut hll = HyperLogLog::new(0.01);
+            for i in 0..n {
+                hll.insert(&format!("item-{}", i));
+                // duplicates don't count
+                hll.insert(&format!("item-{}", i));
+            }
+            let estimate = hll.len() as f64;
+            let error = (estimate - n as f64).abs() / (n as f64).max(1.0);
+            assert!(error < 0.03, "n={} estimate={}", n, estimate);
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let mut a = HyperLogLog::new(0.01);
+        let mut b = HyperLogLog::new(0.01);
+        for i in 0..1000 {
+            a.insert(&i.to_string());
+            b.insert(&(i + 500).to_string());
+        }
+        a.merge(&b);
+        let error = (a.len() as f64 - 1500.0).abs() / 1500.0;
+        assert!(error < 0.03, "estimate={}", a.len());
+    }
+
+    #[test]
+    fn clear() {
+        let mut hll = HyperLogLog::new(0.02);
+        assert!(hll.is_empty());
+        assert!(hll.insert("a"));
+        assert!(!hll.insert("a"));
+        assert_eq!(hll.len(), 1);
+        hll.clear();
+        assert!(hll.is_empty());
+        assert_eq!(hll.len(), 0);
+    }
+
+    #[test]
+    fn registers() {
+        let mut hll = HyperLogLog::new(0.02);
+        for i in 0..100 {
+            hll.insert(&i.to_string());
+        }
+        assert_eq!(hll.precision(), HyperLogLog::precision_for(0.02));
+        let copy = HyperLogLog::from_registers(hll.registers().to_vec()).unwrap();
+        assert_eq!(copy.precision(), hll.precision());
+        assert_eq!(copy.len(), hll.len());
+
+        assert!(HyperLogLog::from_registers(vec![0; 1000]).is_none());
+        assert!(HyperLogLog::from_registers(vec![0; 8]).is_none());
+    }
+}
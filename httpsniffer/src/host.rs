@@ -0,0 +1,232 @@
+use std::net::IpAddr;
+
+use failure::{format_err, Error};
+use regex::Regex;
+
+use crate::config::HostsConfig;
+
+/// Host reported for requests that are missing from the allow list, denied
+/// or don't have a valid host at all
+pub const OTHER_HOST: &str = "other";
+
+const DEFAULT_PORTS: &[u16] = &[80, 443];
+
+/// A `*` glob, or a `.example.com` suffix that matches the domain and all of
+/// its subdomains
+#[derive(Debug)]
+struct Pattern(Regex);
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Pattern, Error> {
+        let (suffix, pattern) = match pattern.strip_prefix('.') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        // patterns are normalized like hosts, except for the wildcards
+        let mut parts = Vec::new();
+        for part in pattern.split('*') {
+            let part = normalize_label(part)
+                .ok_or_else(|| format_err!("invalid host pattern {:?}", pattern))?;
+            parts.push(regex::escape(&part));
+        }
+
+        let mut regex = String::from("^");
+        if suffix {
+            regex.push_str(r"(?:.+\.)?");
+        }
+        regex.push_str(&parts.join(".*"));
+        regex.push('$');
+        Ok(Pattern(Regex::new(&regex)?))
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        self.0.is_match(host)
+    }
+}
+
+/// Normalizes host headers and maps everything that isn't wanted to `other`
+#[derive(Debug)]
+pub struct Hosts {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+    allow_ips: bool,
+}
+
+impl Hosts {
+    pub fn new(config: &HostsConfig) -> Result<Hosts, Error> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| Pattern::parse(pattern))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Hosts {
+            allow: compile(&config.allow)?,
+            deny: compile(&config.deny)?,
+            allow_ips: config.allow_ips,
+        })
+    }
+
+    /// The host a request to server `port` is reported under
+    pub fn host(&self, host: &str, port: u16) -> String {
+        let host = match normalize(host, port) {
+            Some(host) => host,
+            None => return OTHER_HOST.to_string(),
+        };
+
+        let is_ip = host.trim_matches(&['[', ']'][..]).parse::<IpAddr>().is_ok();
+        if is_ip && !self.allow_ips {
+            return OTHER_HOST.to_string();
+        }
+        if self.deny.iter().any(|pattern| pattern.matches(&host)) {
+            return OTHER_HOST.to_string();
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|pattern| pattern.matches(&host)) {
+            return OTHER_HOST.to_string();
+        }
+        host
+    }
+}
+
+/// Lowercase, punycode, and without the port if it's a default one or the
+/// port the server listens on. `None` if this isn't a valid host.
+pub fn normalize(host: &str, port: u16) -> Option<String> {
+    let host = host.trim();
+
+    let (name, host_port) = if host.starts_with('[') {
+        // ipv6 literal, optionally followed by a port
+        let end = host.find(']')?;
+        let port = match &host[end + 1..] {
+            "" => None,
+            rest if rest.starts_with(':') => Some(&rest[1..]),
+            _ => return None,
+        };
+        (&host[..=end], port)
+    } else {
+        match host.rfind(':') {
+            Some(pos) => (&host[..pos], Some(&host[pos + 1..])),
+            None => (host, None),
+        }
+    };
+
+    let host_port = match host_port {
+        Some(host_port) => Some(host_port.parse::<u16>().ok()?),
+        None => None,
+    };
+
+    let name = if name.starts_with('[') {
+        let ip: IpAddr = name[1..name.len() - 1].parse().ok()?;
+        format!("[{}]", ip)
+    } else {
+        normalize_label(name.trim_end_matches('.'))?
+    };
+    if name.is_empty() {
+        return None;
+    }
+
+    match host_port {
+        Some(host_port) if host_port != port && !DEFAULT_PORTS.contains(&host_port) => {
+            Some(format!("{}:{}", name, host_port))
+        }
+        _ => Some(name),
+    }
+}
+
+/// Lowercase and punycode a part of a domain, which may start or end with a dot
+fn normalize_label(name: &str) -> Option<String> {
+    if name.is_ascii() {
+        let valid = name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_');
+        if valid {
+            Some(name.to_ascii_lowercase())
+        } else {
+            None
+        }
+    } else {
+        idna::domain_to_ascii(name).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(allow: &[&str], deny: &[&str], allow_ips: bool) -> Hosts {
+        Hosts::new(&HostsConfig {
+            allow: allow.iter().map(|x| x.to_string()).collect(),
+            deny: deny.iter().map(|x| x.to_string()).collect(),
+            allow_ips,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn normalize_hosts() {
+        assert_eq!(
+            normalize("Api.Example.com:80", 80),
+            Some("api.example.com".to_string())
+        );
+        assert_eq!(
+            normalize("api.example.com.", 8080),
+            Some("api.example.com".to_string())
+        );
+        assert_eq!(
+            normalize("api.example.com:8080", 8080),
+            Some("api.example.com".to_string())
+        );
+        assert_eq!(
+            normalize("api.example.com:8443", 80),
+            Some("api.example.com:8443".to_string())
+        );
+        assert_eq!(
+            normalize("Bücher.example", 80),
+            Some("xn--bcher-kva.example".to_string())
+        );
+        assert_eq!(
+            normalize("[2001:DB8::1]:443", 80),
+            Some("[2001:db8::1]".to_string())
+        );
+        assert_eq!(normalize("10.0.0.1", 80), Some("10.0.0.1".to_string()));
+
+        assert_eq!(normalize("", 80), None);
+        assert_eq!(normalize("example.com:http", 80), None);
+        assert_eq!(normalize("[::1", 80), None);
+        assert_eq!(normalize("exa mple.com", 80), None);
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let hosts = hosts(
+            &[".example.com", "static-*.cdn.net"],
+            &["*.internal.example.com"],
+            false,
+        );
+        assert_eq!(hosts.host("example.com", 80), "example.com");
+        assert_eq!(hosts.host("API.example.com", 80), "api.example.com");
+        assert_eq!(hosts.host("static-01.cdn.net", 80), "static-01.cdn.net");
+        assert_eq!(hosts.host("static.cdn.net", 80), OTHER_HOST);
+        assert_eq!(hosts.host("notexample.com", 80), OTHER_HOST);
+        assert_eq!(hosts.host("db.internal.example.com", 80), OTHER_HOST);
+        assert_eq!(hosts.host("10.0.0.1", 80), OTHER_HOST);
+        assert_eq!(hosts.host("\u{0}", 80), OTHER_HOST);
+    }
+
+    #[test]
+    fn everything_allowed() {
+        let hosts = Hosts::new(&HostsConfig::default()).unwrap();
+        assert_eq!(hosts.host("anything.test:80", 80), "anything.test");
+        assert_eq!(hosts.host("10.0.0.1:8080", 80), "10.0.0.1:8080");
+        assert_eq!(hosts.host("", 80), OTHER_HOST);
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let config = HostsConfig {
+            allow: vec!["exa mple.com".to_string()],
+            deny: Vec::new(),
+            allow_ips: true,
+        };
+        assert!(Hosts::new(&config).is_err());
+    }
+}
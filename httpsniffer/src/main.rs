@@ -1,41 +1,139 @@
+use std::cmp;
 use std::collections::HashMap;
+use std::mem;
 use std::net::IpAddr;
-use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use get_if_addrs::Interface;
-use num_cpus;
+use pcap::Active;
 use pcap::Capture;
 use pcap::Device;
-use pcap::Direction;
+use pcap::Offline;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use threadpool::ThreadPool;
-use uuid::Uuid;
 
-use sniffglue::centrifuge;
+use sniffglue::centrifuge::http;
 use sniffglue::link::DataLink;
-use sniffglue::structs::ether::Ether;
+use sniffglue::reassembly::{self, Reassembler, Segment};
 use sniffglue::structs::http::Request;
-use sniffglue::structs::ipv4::IPv4;
-use sniffglue::structs::raw::Raw;
-use sniffglue::structs::tcp::TCP;
+use sniffglue::structs::http::Response;
 
+use crate::accesslog::{AccessLog, Rotate};
+use crate::batch::{Batch, Buffers, Overload, BATCH_SIZE};
+use crate::client::ClientIp;
+use crate::config::Config;
+use crate::dimension::Dimension;
+use crate::exchange::Exchanges;
+use crate::exchange::Pending;
+use crate::exchange::Side;
+use crate::geoip::GeoIp;
+use crate::har::Har;
+use crate::host::Hosts;
+use crate::metrics::Limits;
+use crate::ports::{PortRange, Ports};
+use crate::prometheus::Prometheus;
+use crate::rollup::Rollups;
+use crate::route::Router;
+use crate::sink::SinkSpec;
+use crate::telemetry::Telemetry;
+use crate::toplog::TopLog;
+
+mod accesslog;
+mod batch;
+mod client;
+mod config;
+mod dimension;
+mod exchange;
+mod geoip;
+mod har;
+mod hll;
+mod host;
 mod metrics;
+mod ports;
+mod prometheus;
+mod quantile;
+mod rollup;
+mod route;
+mod sink;
+mod telemetry;
+#[cfg(test)]
+mod testutil;
+mod topk;
+mod toplog;
+
+/// Streams that didn't see a segment for this long are dropped
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often capture threads wake up to check for a shutdown
+const CAPTURE_TIMEOUT_MS: i32 = 500;
+/// A batch that isn't full is handed to the pool after this long
+const BATCH_DELAY: Duration = Duration::from_millis(100);
+
+/// Stopped by a signal, or a pcap file was fully replayed
+const EXIT_OK: i32 = 0;
+/// Invalid arguments or config, or a device couldn't be opened
+const EXIT_SETUP: i32 = 1;
+/// Capturing failed while running, metrics up to the failure were flushed
+const EXIT_CAPTURE: i32 = 2;
+/// A second signal arrived before the shutdown finished
+const EXIT_INTERRUPTED: i32 = 130;
 
-type Message = (Ipv4Addr, Request);
-type Sender = mpsc::Sender<Message>;
+/// Set by SIGINT or SIGTERM, or when a capture fails
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+type Message = (Arc<str>, Side, Segment, Duration);
+type Sender = mpsc::SyncSender<Message>;
 type Receiver = mpsc::Receiver<Message>;
 
 #[derive(Debug, StructOpt)]
 #[structopt(raw(global_settings = "&[AppSettings::ColoredHelp]"))]
 pub struct Args {
+    /// Send dogstatsd metrics to this address, same as --sink dogstatsd://<addr>
     #[structopt(long = "statsd_host", help = "192.168.1.1:2221")]
     pub statsd_host: Option<String>,
+    /// Prefix of the metric names in every sink
     #[structopt(long = "statsd_prefix")]
     pub statsd_prefix: Option<String>,
+    /// Send metrics to dogstatsd://, statsd://, influx://, influx+tcp:// or
+    /// json://<path>, can be repeated
+    #[structopt(long = "sink", raw(number_of_values = "1"))]
+    pub sink: Vec<SinkSpec>,
+    /// Serve metrics for prometheus on this address, e.g. 127.0.0.1:9091
+    #[structopt(long = "prometheus")]
+    pub prometheus: Option<String>,
+    /// Append the top values of every window as json lines to this file, - for stdout
+    #[structopt(long = "top_output")]
+    pub top_output: Option<String>,
+    /// Write every request to this file, - for stdout
+    #[structopt(long = "access_log")]
+    pub access_log: Option<String>,
+    /// Access log lines as combined or json
+    #[structopt(long = "access_log_format", default_value = "combined")]
+    pub access_log_format: accesslog::Format,
+    /// Request header added to every access log line, can be repeated
+    #[structopt(long = "access_log_header", raw(number_of_values = "1"))]
+    pub access_log_header: Vec<String>,
+    /// Rotate the access log file at size:<n>[K|M|G] or every time:<n>[s|m|h|d]
+    #[structopt(long = "access_log_rotate")]
+    pub access_log_rotate: Option<Rotate>,
+    /// Write captured requests and their responses as a HAR 1.2 file, - for stdout
+    #[structopt(long = "har")]
+    pub har: Option<String>,
+    /// Keep authorization headers and cookie values in the HAR file instead of redacting them
+    #[structopt(long = "har_credentials")]
+    pub har_credentials: bool,
+    /// Drop metrics that reported nothing for this many windows, 0 keeps them forever
+    #[structopt(long = "evict_after", default_value = "6")]
+    pub evict_after: usize,
+    /// Maximum number of metrics, the rest are counted with an overflow tag
+    #[structopt(long = "max_metrics", default_value = "10000")]
+    pub max_metrics: usize,
     #[structopt(
         short = "d",
         long = "duration",
@@ -43,196 +141,1456 @@ pub struct Args {
         help = "duration seconds"
     )]
     pub duration: u64,
+    /// Also report requests, responses and unique counts of the last n seconds
+    /// every window, n has to be a multiple of the duration
+    #[structopt(long = "sliding")]
+    pub sliding: Option<u64>,
     /// Set device to promisc
     #[structopt(short = "p", long = "promisc")]
     pub promisc: bool,
-    #[structopt(long = "port")]
-    pub port: Option<u16>,
+    /// Server port or range like 8000-8100, can be repeated
+    #[structopt(long = "port", raw(number_of_values = "1"))]
+    pub port: Vec<PortRange>,
+    /// Additional bpf expression for the capture filter
+    #[structopt(long = "filter")]
+    pub filter: Option<String>,
     /// Show more packets (maximum: 4)
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     pub verbose: u64,
+    /// Open device as pcap file
+    #[structopt(short = "r", long = "read")]
+    pub read: bool,
     /// Number of cores
     #[structopt(short = "n", long = "cpus")]
     pub cpus: Option<usize>,
-    /// Device for sniffing
-    pub device: Option<String>,
+    /// Segments waiting to be reassembled, and packets waiting to be decoded.
+    /// Up to twice as many packets wait with --overload sample
+    #[structopt(long = "max_queue", default_value = "65536")]
+    pub max_queue: usize,
+    /// TCP streams tracked per interface, the least recently seen ones are
+    /// dropped to make room for new ones
+    #[structopt(long = "max_streams", default_value = "100000")]
+    pub max_streams: usize,
+    /// What to do with packets while the queue is full: drop, or sample:<n> to
+    /// keep every n-th connection
+    #[structopt(long = "overload", default_value = "drop")]
+    pub overload: Overload,
+    /// Load cardinality dimensions from a toml file
+    #[structopt(short = "c", long = "config")]
+    pub config: Option<String>,
+    /// Save the unique counts of the cardinality resolutions to this file, so
+    /// they survive a restart
+    #[structopt(long = "state_file")]
+    pub state_file: Option<String>,
+    /// Devices for sniffing, or pcap files with --read
+    pub devices: Vec<String>,
 }
 
-fn get_if_addr(name: &str) -> Option<Ipv4Addr> {
+fn get_if_addrs(name: &str) -> Vec<IpAddr> {
     let addrs: Vec<Interface> = get_if_addrs::get_if_addrs().expect("get if addrs");
     addrs
         .iter()
-        .find(|iface| iface.name == name)
-        .and_then(|iface| match iface.ip() {
-            IpAddr::V4(addr) => Some(addr),
-            IpAddr::V6(_) => None,
-        })
+        .filter(|iface| iface.name == name)
+        .map(|iface| iface.ip())
+        .collect()
+}
+
+fn get_datalink(linktype: pcap::Linktype) -> Option<DataLink> {
+    match DataLink::from_linktype(linktype) {
+        Ok(link) => Some(link),
+        Err(x) => {
+            eprintln!(
+                "Unknown link type: {:?}, {:?}, {}",
+                x.get_name().unwrap_or_else(|_| "???".into()),
+                x.get_description().unwrap_or_else(|_| "???".into()),
+                x.0
+            );
+            None
+        }
+    }
+}
+
+/// Decode a packet and figure out which end of the connection sent it. If the
+/// local addresses aren't known, the side that uses one of `ports` (or the
+/// lower port) is considered to be the server.
+fn parse_segment(
+    datalink: &DataLink,
+    data: &[u8],
+    ports: &Ports,
+    addrs: &[IpAddr],
+    telemetry: &Telemetry,
+) -> Option<(Side, Segment)> {
+    let segment = reassembly::segment(datalink, data);
+    telemetry.segment(&segment);
+    let segment = segment.ok()?;
+    let key = &segment.key;
+    let serves = |port| !ports.is_empty() && ports.contains(port);
+
+    let side = if addrs.contains(&key.dest_addr) {
+        Side::Client
+    } else if addrs.contains(&key.source_addr) {
+        Side::Server
+    } else if !addrs.is_empty() {
+        return None;
+    } else {
+        match (serves(key.source_port), serves(key.dest_port)) {
+            (true, false) => Side::Server,
+            (false, true) => Side::Client,
+            _ if key.source_port < key.dest_port => Side::Server,
+            _ => Side::Client,
+        }
+    };
+
+    let server_port = match side {
+        Side::Client => key.dest_port,
+        Side::Server => key.source_port,
+    };
+    if !ports.contains(server_port) {
+        return None;
+    }
+
+    Some((side, segment))
+}
+
+/// Time since the epoch, like the timestamps of captured packets
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// The first multiple of `duration` seconds since the epoch after `ts`
+fn window_end(ts: Duration, duration: u64) -> Duration {
+    Duration::from_secs((ts.as_secs() / duration + 1) * duration)
+}
+
+fn timestamp(header: &pcap::PacketHeader) -> Duration {
+    Duration::new(header.ts.tv_sec as u64, header.ts.tv_usec as u32 * 1000)
+}
+
+/// Name of the `iface` tag for a replayed pcap file
+fn file_iface(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string())
+}
+
+fn tags(iface: &str, host: &str, port: u16) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    map.insert("iface".to_string(), iface.to_string());
+    map.insert("host".to_string(), host.to_string());
+    map.insert("port".to_string(), port.to_string());
+    map
+}
+
+/// Size of the request body, the captured body may be cut short
+fn body_size(request: &Request) -> u64 {
+    request
+        .extra_headers
+        .get("content-length")
+        .and_then(|value| value.as_ref()?.trim().parse().ok())
+        .unwrap_or(request.data.len() as u64)
+}
+
+/// Number of request headers, including those the parser moves into fields
+fn header_count(request: &Request) -> u64 {
+    let fields = [
+        &request.host,
+        &request.agent,
+        &request.referer,
+        &request.auth,
+        &request.cookies,
+    ];
+    (request.extra_headers.len() + fields.iter().filter(|field| field.is_some()).count()) as u64
+}
+
+/// Everything a pool thread needs to decode the packets of one device
+#[derive(Clone)]
+struct Decoder {
+    datalink: DataLink,
+    device_addrs: Arc<Vec<IpAddr>>,
+    ports: Arc<Ports>,
+    telemetry: Arc<Telemetry>,
+}
+
+impl Decoder {
+    fn decode(&self, data: &[u8]) -> Option<(Side, Segment)> {
+        parse_segment(
+            &self.datalink,
+            data,
+            &self.ports,
+            &self.device_addrs,
+            &self.telemetry,
+        )
+    }
+}
+
+/// Hands batches of packets to the pool, or sheds them while the pool is behind
+#[derive(Clone)]
+struct Dispatcher {
+    pool: ThreadPool,
+    tx: Sender,
+    buffers: Buffers,
+    overload: Overload,
+    max_batches: usize,
 }
 
-fn parse_http_request(packet: Raw, port: u16, addr: Ipv4Addr) -> Option<Message> {
-    match packet {
-        Raw::Ether(_, ether) => match ether {
-            Ether::IPv4(ipv4_header, ipv4) => {
-                if ipv4_header.dest_addr != addr {
-                    return None;
+impl Dispatcher {
+    /// Whether a packet is queued. Once `max_batches` are waiting only the
+    /// overload policy decides, and at twice that everything is dropped.
+    fn admit(&self, datalink: &DataLink, packet: &[u8]) -> bool {
+        let queued = self.pool.queued_count();
+        queued < self.max_batches
+            || (queued < 2 * self.max_batches && self.overload.keep(datalink, packet))
+    }
+
+    fn dispatch(&self, iface: &Arc<str>, decoder: &Decoder, batch: Batch) {
+        let iface = iface.clone();
+        let decoder = decoder.clone();
+        let tx = self.tx.clone();
+        let buffers = self.buffers.clone();
+        self.pool.execute(move || {
+            for (ts, packet) in batch.iter() {
+                if let Some((side, segment)) = decoder.decode(packet) {
+                    decoder.telemetry.enqueue();
+                    // blocks while the pipeline is behind, so the pool queue fills up
+                    tx.send((iface.clone(), side, segment, ts)).expect("send");
+                }
+            }
+            buffers.give(batch);
+        });
+    }
+}
+
+/// Connection state of a single interface
+struct Flows {
+    reassembler: Reassembler,
+    exchanges: Exchanges,
+}
+
+impl Flows {
+    fn new(max_streams: usize) -> Flows {
+        let mut reassembler = Reassembler::new(FLOW_TIMEOUT);
+        reassembler.set_max_streams(max_streams);
+        let mut exchanges = Exchanges::new(FLOW_TIMEOUT);
+        exchanges.set_max_connections(max_streams);
+        Flows {
+            reassembler,
+            exchanges,
+        }
+    }
+}
+
+/// Everything that happens to a segment after it has been decoded. This has to
+/// run on a single thread since segments are put back in order here. Streams
+/// are tracked per interface, so the same flow seen on a bond and on one of its
+/// vlans doesn't get mixed up.
+struct Pipeline {
+    registry: metrics::Registry,
+    dimensions: Vec<Dimension>,
+    router: Router,
+    hosts: Hosts,
+    client_ip: ClientIp,
+    geoip: Option<GeoIp>,
+    access_log: Option<AccessLog>,
+    har: Option<Har>,
+    rollups: Rollups,
+    /// Window of the last segment, unset right after a flush
+    window: Option<u64>,
+    last_ts: Duration,
+    duration: u64,
+    /// Seconds of the sliding window counters, if any
+    sliding: Option<u64>,
+    /// Streams tracked per interface
+    max_streams: usize,
+    verbose: u64,
+    flows: HashMap<Arc<str>, Flows>,
+    telemetry: Arc<Telemetry>,
+}
+
+impl Pipeline {
+    fn new(
+        registry: metrics::Registry,
+        config: &Config,
+        duration: u64,
+        verbose: u64,
+    ) -> Result<Self, failure::Error> {
+        let dimensions = dimension::compile(&config.cardinality)?;
+        let geoip = match config.geoip {
+            Some(ref geoip) => Some(GeoIp::open(geoip)?),
+            None => None,
+        };
+        for dimension in &dimensions {
+            for resolution in &dimension.resolutions {
+                if resolution.seconds() < duration {
+                    return Err(failure::format_err!(
+                        "cardinality {:?}: resolution {} is shorter than the {}s window",
+                        dimension.name,
+                        resolution.name(),
+                        duration
+                    ));
                 }
+            }
+        }
+        if let Some(ref geoip) = geoip {
+            for metric in geoip.metrics() {
+                if metric != "reqs" && !dimensions.iter().any(|d| d.name == metric) {
+                    return Err(failure::format_err!("geoip: unknown metric {:?}", metric));
+                }
+            }
+        }
+
+        let mut rollups = Rollups::default();
+        rollups.set_max_rollups(registry.limits().max_metrics);
+
+        Ok(Pipeline {
+            registry,
+            dimensions,
+            router: Router::new(&config.route, config.max_routes_per_host)?,
+            hosts: Hosts::new(&config.hosts)?,
+            client_ip: ClientIp::new(&config.client_ip)?,
+            geoip,
+            access_log: None,
+            har: None,
+            rollups,
+            window: None,
+            last_ts: Duration::default(),
+            duration,
+            sliding: None,
+            max_streams: reassembly::MAX_STREAMS,
+            verbose,
+            flows: HashMap::new(),
+            telemetry: Arc::new(Telemetry::new()),
+        })
+    }
+
+    /// The normalized host, usable as part of a statsd name
+    fn host(&self, host: &str, port: u16) -> String {
+        self.hosts
+            .host(host, port)
+            .replace(&['[', ']'][..], "")
+            .replace(&['.', ':'][..], "_")
+    }
+
+    /// `peer` is the address the connection came from, `port` the server port
+    fn record(&mut self, iface: &str, request: &Request, peer: IpAddr, port: u16, ts: Duration) {
+        let duration = self.duration;
+        let client = self.client_ip.resolve(request, peer);
+        if let Some(ref mut log) = self.access_log {
+            log.log(ts, client, request);
+        }
+
+        let host = if let Some(host) = request.host.as_ref() {
+            self.host(host, port)
+        } else {
+            return;
+        };
+
+        let location = self.geoip.as_mut().map(|geoip| geoip.lookup(client));
+        if let Some(ref location) = location {
+            for database in location.misses() {
+                self.telemetry.geoip_miss(database);
+            }
+        }
+        // location tags of a metric, and the same as part of its name
+        let geoip = &self.geoip;
+        let geo = |metric: &str| match (geoip, &location) {
+            (Some(geoip), Some(location)) if geoip.enriches(metric) => {
+                let tags = location.tags();
+                let name = tags.iter().map(|(_, v)| format!(".{}", v)).collect();
+                (tags, name)
+            }
+            _ => (Vec::new(), String::new()),
+        };
 
-                match ipv4 {
-                    IPv4::TCP(tcp_header, tcp) => {
-                        if port != 0 && tcp_header.dest_port != port {
-                            return None;
+        for dimension in &self.dimensions {
+            let value = match dimension.extract(request, client) {
+                Some(value) => value,
+                None => continue,
+            };
+            let (geo_tags, geo_name) = geo(&dimension.name);
+            let dimension_tags = || {
+                let mut map = dimension.tags.clone();
+                map.extend(tags(iface, &host, port));
+                for (k, v) in &geo_tags {
+                    map.insert(k.to_string(), v.to_string());
+                }
+                Some(map)
+            };
+            if let Some(size) = dimension.top {
+                let top = self.registry.get_topk(
+                    format!(
+                        "{}.{}.{}{}.{}_top_per_{}s",
+                        iface, &host, port, geo_name, &dimension.name, duration
+                    ),
+                    format!("{}_top_per_{}s", &dimension.name, duration),
+                    dimension_tags(),
+                    size,
+                );
+                top.add(&value);
+            }
+            for resolution in &dimension.resolutions {
+                self.rollups.add(
+                    format!(
+                        "{}.{}.{}{}.{}_per_{}",
+                        iface,
+                        &host,
+                        port,
+                        geo_name,
+                        &dimension.name,
+                        resolution.name()
+                    ),
+                    || {
+                        let key = format!("{}_per_{}", &dimension.name, resolution.name());
+                        (key, dimension_tags().unwrap_or_default())
+                    },
+                    resolution,
+                    dimension.error,
+                    ts,
+                    &value,
+                );
+            }
+            if let Some(span) = self.sliding {
+                let unique = self.registry.get_sliding_cardinality(
+                    format!(
+                        "{}.{}.{}{}.{}_per_{}s",
+                        iface, &host, port, geo_name, &dimension.name, span
+                    ),
+                    format!("{}_per_{}s", &dimension.name, span),
+                    dimension_tags(),
+                    dimension.backend,
+                    Duration::from_secs(span),
+                );
+                unique.add(value.clone());
+            }
+            let unique = self.registry.get_cardinality(
+                format!(
+                    "{}.{}.{}{}.{}_per_{}s",
+                    iface, &host, port, geo_name, &dimension.name, duration
+                ),
+                format!("{}_per_{}s", &dimension.name, duration),
+                dimension_tags(),
+                dimension.backend,
+            );
+            unique.add(value);
+        }
+
+        let route = self.router.route(&host, &request.uri);
+        let method = route::method(&request.method);
+        let (geo_tags, geo_name) = geo("reqs");
+        let reqs_tags = || {
+            let mut map = tags(iface, &host, port);
+            map.insert("route".to_string(), route.clone());
+            map.insert("method".to_string(), method.to_string());
+            for (k, v) in &geo_tags {
+                map.insert(k.to_string(), v.to_string());
+            }
+            Some(map)
+        };
+        let reqs = self.registry.get_counter(
+            format!(
+                "{}.{}.{}.{}.{}{}.reqs_per_{}s",
+                iface, &host, port, method, &route, geo_name, duration
+            ),
+            format!("reqs_per_{}s", duration),
+            reqs_tags(),
+        );
+        reqs.add(1);
+        if let Some(span) = self.sliding {
+            let reqs = self.registry.get_sliding_counter(
+                format!(
+                    "{}.{}.{}.{}.{}{}.reqs_per_{}s",
+                    iface, &host, port, method, &route, geo_name, span
+                ),
+                format!("reqs_per_{}s", span),
+                reqs_tags(),
+                Duration::from_secs(span),
+            );
+            reqs.add(1);
+        }
+
+        let body = self.registry.get_histogram(
+            format!("{}.{}.{}.request_body_bytes", iface, &host, port),
+            "request_body_bytes",
+            Some(tags(iface, &host, port)),
+        );
+        body.add(body_size(request));
+        let headers = self.registry.get_histogram(
+            format!("{}.{}.{}.request_headers", iface, &host, port),
+            "request_headers",
+            Some(tags(iface, &host, port)),
+        );
+        headers.add(header_count(request));
+
+        if self.verbose > 0 {
+            println!("{:?}", request);
+        }
+    }
+
+    fn record_response(
+        &self,
+        iface: &str,
+        request: &Pending,
+        response: &Response,
+        latency: Duration,
+        port: u16,
+    ) {
+        let duration = self.duration;
+        let host = if let Some(host) = request.host.as_ref() {
+            self.host(host, port)
+        } else {
+            return;
+        };
+        let class = format!("{}xx", response.status / 100);
+
+        let responses_tags = || {
+            let mut map = tags(iface, &host, port);
+            map.insert("status".to_string(), class.clone());
+            Some(map)
+        };
+        let responses = self.registry.get_counter(
+            format!(
+                "{}.{}.{}.{}.responses_per_{}s",
+                iface, &host, port, &class, duration
+            ),
+            format!("responses_per_{}s", duration),
+            responses_tags(),
+        );
+        responses.add(1);
+        if let Some(span) = self.sliding {
+            let responses = self.registry.get_sliding_counter(
+                format!(
+                    "{}.{}.{}.{}.responses_per_{}s",
+                    iface, &host, port, &class, span
+                ),
+                format!("responses_per_{}s", span),
+                responses_tags(),
+                Duration::from_secs(span),
+            );
+            responses.add(1);
+        }
+
+        let latency_ms = latency.as_secs() * 1000 + u64::from(latency.subsec_millis());
+        let histogram = self.registry.get_histogram(
+            format!("{}.{}.{}.latency", iface, &host, port),
+            "latency",
+            Some(tags(iface, &host, port)),
+        );
+        histogram.add(latency_ms);
+    }
+
+    fn push(&mut self, iface: &Arc<str>, side: Side, segment: &Segment, ts: Duration) {
+        // live captures are flushed by a timer, so the unique counts of the
+        // resolutions and routes are brought up to date whenever a window starts
+        let window = ts.as_secs() / self.duration;
+        if matches!(self.window, Some(last) if last != window) {
+            self.end_window();
+        }
+        self.window = Some(window);
+        self.last_ts = ts;
+
+        // taken out of the map while recording, which needs the rest of self
+        let max_streams = self.max_streams;
+        let mut flows = self
+            .flows
+            .remove(iface)
+            .unwrap_or_else(|| Flows::new(max_streams));
+
+        if let Some(stream) = flows.reassembler.push(segment, ts) {
+            match side {
+                Side::Client => {
+                    for request in http::extract_stream(stream) {
+                        let key = &segment.key;
+                        self.record(iface, &request, key.source_addr, key.dest_port, ts);
+                        let pending = Pending::new(request, self.har.is_some());
+                        let expired = flows.exchanges.request(segment.key, pending, ts);
+                        if let Some(ref mut har) = self.har {
+                            for (key, started, pending) in expired {
+                                if let Some(ref request) = pending.request {
+                                    har.unanswered(&key, request, started);
+                                }
+                            }
                         }
-                        match tcp {
-                            TCP::HTTP(request) => Some((ipv4_header.source_addr, request)),
-                            _ => None,
+                    }
+                }
+                Side::Server => {
+                    // requests are paired while parsing, their method decides
+                    // whether a body follows
+                    let exchanges = &mut flows.exchanges;
+                    let mut requests = Vec::new();
+                    let responses = http::extract_response_stream(stream, || {
+                        let exchange = exchanges.response(&segment.key);
+                        let method = exchange.as_ref().map(|(_, pending)| pending.method.clone());
+                        requests.push(exchange);
+                        method
+                    });
+                    for (response, exchange) in responses.into_iter().zip(requests) {
+                        if let Some((started, pending)) = exchange {
+                            let latency = ts.checked_sub(started).unwrap_or_default();
+                            let port = segment.key.source_port;
+                            self.record_response(iface, &pending, &response, latency, port);
+                            if let (Some(har), Some(request)) = (&mut self.har, &pending.request) {
+                                let key = segment.key.reverse();
+                                har.exchange(&key, request, started, &response, ts);
+                            }
                         }
                     }
-                    _ => None,
                 }
             }
-            _ => None,
-        },
-        _ => None,
+            self.telemetry.http_errors(stream.take_errors());
+        }
+        self.telemetry
+            .streams_evicted(flows.reassembler.take_evicted());
+        self.telemetry
+            .exchanges_evicted(flows.exchanges.take_evicted());
+
+        let streams = self
+            .registry
+            .get_gauge(format!("{}.streams", iface), "streams", {
+                let mut map = HashMap::new();
+                map.insert("iface".to_string(), iface.to_string());
+                Some(map)
+            });
+        streams.set(flows.reassembler.len() as u64);
+
+        self.flows.insert(iface.clone(), flows);
+    }
+
+    /// Bring the resolutions up to date and age out the routes of the window
+    /// that just ended
+    fn end_window(&mut self) {
+        self.rollups.report(&self.registry, self.last_ts);
+        self.rollups.checkpoint();
+        self.router.end_window(self.registry.limits().idle_windows);
+    }
+
+    /// Send the current window, including the telemetry of a replay. The
+    /// window ends at the next multiple of the duration after the last segment,
+    /// and after `sent`, the end of the last window that was sent already.
+    fn flush(&mut self, sent: Duration) {
+        self.end_window();
+        self.window = None;
+        self.telemetry.report(&self.registry, None);
+        let last = if self.last_ts == Duration::default() {
+            now()
+        } else {
+            self.last_ts
+        };
+        let end = cmp::max(last, sent);
+        self.registry.send_at(window_end(end, self.duration));
+        if let Some(ref mut log) = self.access_log {
+            log.flush();
+        }
+        if let Some(ref mut har) = self.har {
+            har.flush();
+        }
     }
 }
 
+impl Drop for Pipeline {
+    /// Requests that are still waiting for a response end up in the HAR file,
+    /// before it's closed
+    fn drop(&mut self) {
+        let har = match self.har {
+            Some(ref mut har) => har,
+            None => return,
+        };
+        let mut unanswered: Vec<_> = self
+            .flows
+            .values_mut()
+            .flat_map(|flows| flows.exchanges.drain())
+            .collect();
+        unanswered.sort_by_key(|(_, started, _)| *started);
+        for (key, started, pending) in unanswered {
+            if let Some(ref request) = pending.request {
+                har.unanswered(&key, request, started);
+            }
+        }
+    }
+}
+
+/// Feed a saved capture through the same pipeline as a live device. Windows
+/// are cut by the pcap timestamp of each packet instead of the wall clock,
+/// and the last window is flushed once the file is exhausted or a shutdown
+/// was requested.
+fn replay(
+    mut cap: Capture<Offline>,
+    iface: &str,
+    pipeline: &mut Pipeline,
+    ports: &Ports,
+) -> Result<(), pcap::Error> {
+    let datalink = match get_datalink(cap.get_datalink()) {
+        Some(link) => link,
+        None => return Ok(()),
+    };
+    let iface: Arc<str> = Arc::from(iface);
+
+    let mut window = None;
+    let mut result = Ok(());
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        match cap.next() {
+            Ok(packet) => {
+                let ts = timestamp(packet.header);
+                let current = ts.as_secs() / pipeline.duration;
+                if window.is_some() && window != Some(current) {
+                    pipeline.flush(Duration::default());
+                }
+                window = Some(current);
+
+                pipeline.telemetry.packet();
+                let telemetry = &pipeline.telemetry;
+                if let Some((side, segment)) =
+                    parse_segment(&datalink, packet.data, ports, &[], telemetry)
+                {
+                    pipeline.push(&iface, side, &segment, ts);
+                }
+            }
+            Err(pcap::Error::NoMorePackets) => break,
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                result = Err(e);
+                break;
+            }
+        }
+    }
+
+    pipeline.flush(Duration::default());
+    result
+}
+
+/// Read packets until a shutdown is requested or capturing fails. The pcap
+/// counters are reported every `interval`.
+fn capture(
+    mut cap: Capture<Active>,
+    iface: Arc<str>,
+    decoder: Decoder,
+    dispatcher: Dispatcher,
+    registry: metrics::Registry,
+    interval: Duration,
+) -> Result<(), pcap::Error> {
+    let mut result = Ok(());
+    let mut reported = Instant::now();
+    let mut batch = dispatcher.buffers.take();
+    let mut started = Instant::now();
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        match cap.next() {
+            Ok(packet) => {
+                decoder.telemetry.packet();
+                if !dispatcher.admit(&decoder.datalink, packet.data) {
+                    decoder.telemetry.shed();
+                } else {
+                    if batch.is_empty() {
+                        started = Instant::now();
+                    }
+                    batch.push(timestamp(packet.header), packet.data);
+                }
+            }
+            Err(pcap::Error::TimeoutExpired) => {}
+            Err(e) => {
+                eprintln!("Error on {:?}: {:?}", iface, e);
+                result = Err(e);
+                // stop the other devices too, a half working process is easy to miss
+                SHUTDOWN.store(true, Ordering::SeqCst);
+                break;
+            }
+        }
+
+        if batch.len() >= BATCH_SIZE || (!batch.is_empty() && started.elapsed() >= BATCH_DELAY) {
+            let full = mem::replace(&mut batch, dispatcher.buffers.take());
+            dispatcher.dispatch(&iface, &decoder, full);
+        }
+
+        if reported.elapsed() >= interval {
+            if let Ok(stats) = cap.stats() {
+                telemetry::pcap_stats(&registry, &iface, &stats);
+            }
+            reported = Instant::now();
+        }
+    }
+    if batch.is_empty() {
+        dispatcher.buffers.give(batch);
+    } else {
+        dispatcher.dispatch(&iface, &decoder, batch);
+    }
+
+    match cap.stats() {
+        Ok(stats) => {
+            telemetry::pcap_stats(&registry, &iface, &stats);
+            eprintln!(
+                "{}: {} packets received, {} dropped, {} dropped by interface",
+                iface, stats.received, stats.dropped, stats.if_dropped
+            )
+        }
+        Err(e) => eprintln!("Failed to get stats for {:?}: {}", iface, e),
+    }
+    result
+}
+
 fn main() {
     env_logger::init();
 
     let args = dbg!(Args::from_args());
 
-    let device = match args.device {
-        Some(device) => device,
-        None => Device::lookup().expect("lookup device").name,
+    if let Err(e) = ctrlc::set_handler(|| {
+        if SHUTDOWN.swap(true, Ordering::SeqCst) {
+            eprintln!("Exiting without flushing metrics");
+            process::exit(EXIT_INTERRUPTED);
+        }
+        eprintln!("Shutting down, press ctrl-c again to exit immediately");
+    }) {
+        eprintln!("Failed to set signal handler: {}", e);
+        process::exit(EXIT_SETUP);
+    }
+
+    process::exit(run(args));
+}
+
+/// Returns the exit code of the process
+fn run(args: Args) -> i32 {
+    // windows are cut by dividing timestamps by the duration
+    if args.duration == 0 {
+        eprintln!("Duration has to be at least 1 second");
+        return EXIT_SETUP;
+    }
+
+    let devices = if args.devices.is_empty() {
+        vec![Device::lookup().expect("lookup device").name]
+    } else {
+        args.devices
     };
 
-    let device_addr = get_if_addr(&device).expect("get device addr");
-    let port = args.port.unwrap_or(0);
+    let ports = Ports::new(args.port);
+    let filter = ports.filter(args.filter.as_deref());
     let cpus = args.cpus.unwrap_or_else(num_cpus::get);
     let duration = args.duration;
-    let statsd_prefix = args.statsd_prefix.unwrap_or_else(|| "".to_string());
+    let verbose = args.verbose;
+    let statsd_prefix = args.statsd_prefix.unwrap_or_default();
+
+    let config = match args.config {
+        Some(ref path) => match config::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Failed to load config {:?}: {}", path, e);
+                return EXIT_SETUP;
+            }
+        },
+        None => Config::default(),
+    };
+
+    let mut registry = metrics::Registry::new();
+    registry.set_limits(Limits {
+        idle_windows: args.evict_after,
+        max_metrics: args.max_metrics,
+    });
+
+    let sinks = args
+        .statsd_host
+        .iter()
+        .map(SinkSpec::dogstatsd)
+        .chain(args.sink);
+    for sink in sinks {
+        match sink.open(&statsd_prefix) {
+            Ok(output) => registry.add_exporter(Arc::new(output)),
+            Err(e) => {
+                eprintln!("Failed to open sink {:?}: {}", sink, e);
+                return EXIT_SETUP;
+            }
+        }
+    }
+
+    if let Some(ref addr) = args.prometheus {
+        let prometheus = Arc::new(Prometheus::new(&statsd_prefix));
+        match prometheus.clone().serve(addr.as_str()) {
+            Ok(addr) => eprintln!("Serving prometheus metrics on http://{}/metrics", addr),
+            Err(e) => {
+                eprintln!("Failed to listen on {:?}: {}", addr, e);
+                return EXIT_SETUP;
+            }
+        }
+        registry.add_exporter(prometheus);
+    }
 
-    let mut cap = match Capture::from_device(device.as_str())
-        .expect("from device")
-        .promisc(args.promisc)
-        .open()
-    {
-        Ok(cap) => {
-            eprintln!("Listening on device: {:?}", device);
-            cap
+    if let Some(ref path) = args.top_output {
+        match TopLog::open(path) {
+            Ok(log) => registry.add_exporter(Arc::new(log)),
+            Err(e) => {
+                eprintln!("Failed to open {:?}: {}", path, e);
+                return EXIT_SETUP;
+            }
         }
+    }
+
+    let mut pipeline = match Pipeline::new(registry.clone(), &config, duration, verbose) {
+        Ok(pipeline) => pipeline,
         Err(e) => {
-            eprintln!("Failed to open interface {:?}: {}", device, e);
-            return;
+            eprintln!("Invalid config: {}", e);
+            return EXIT_SETUP;
         }
     };
-    cap.direction(Direction::In).expect("set capture direction");
-    cap.filter(&format!("tcp dst port {}", port))
-        .expect("set capture filter");
-
-    let (tx, rx): (Sender, Receiver) = mpsc::channel();
-
-    let join = thread::spawn(move || {
-        let pool = ThreadPool::new(cpus);
-
-        let datalink = match DataLink::from_linktype(cap.get_datalink()) {
-            Ok(link) => link,
-            Err(x) => {
-                // TODO: properly exit the program
-                eprintln!(
-                    "Unknown link type: {:?}, {:?}, {}",
-                    x.get_name().unwrap_or_else(|_| "???".into()),
-                    x.get_description().unwrap_or_else(|_| "???".into()),
-                    x.0
-                );
-                return;
+
+    pipeline.max_streams = args.max_streams;
+
+    if let Some(span) = args.sliding {
+        if span <= duration || span % duration != 0 {
+            eprintln!(
+                "Sliding window of {}s has to be a longer multiple of the {}s duration",
+                span, duration
+            );
+            return EXIT_SETUP;
+        }
+        pipeline.sliding = Some(span);
+    }
+
+    if let Some(ref path) = args.state_file {
+        match Rollups::open(path) {
+            Ok(mut rollups) => {
+                rollups.set_max_rollups(registry.limits().max_metrics);
+                pipeline.rollups = rollups;
             }
-        };
+            Err(e) => {
+                eprintln!("Failed to load state {:?}: {}", path, e);
+                return EXIT_SETUP;
+            }
+        }
+    }
 
-        loop {
-            match cap.next() {
-                Ok(packet) => {
-                    let tx = tx.clone();
-                    let packet = packet.data.to_vec();
-
-                    let datalink = datalink.clone();
-                    pool.execute(move || {
-                        let packet = centrifuge::parse(&datalink, &packet);
-                        if let Some(message) = parse_http_request(packet, port, device_addr) {
-                            tx.send(message).expect("send");
-                        }
-                    });
+    if let Some(ref path) = args.access_log {
+        let format = args.access_log_format;
+        let headers = args.access_log_header.clone();
+        match AccessLog::open(path, format, headers, args.access_log_rotate) {
+            Ok(log) => pipeline.access_log = Some(log),
+            Err(e) => {
+                eprintln!("Failed to open {:?}: {}", path, e);
+                return EXIT_SETUP;
+            }
+        }
+    }
+
+    if let Some(ref path) = args.har {
+        match Har::open(path, args.har_credentials) {
+            Ok(har) => pipeline.har = Some(har),
+            Err(e) => {
+                eprintln!("Failed to open {:?}: {}", path, e);
+                return EXIT_SETUP;
+            }
+        }
+    }
+
+    if args.read {
+        // files are replayed one after another, each tagged with its name
+        for path in &devices {
+            match Capture::from_file(path.as_str()) {
+                Ok(mut cap) => {
+                    eprintln!("Reading from file: {:?}", path);
+                    if let Err(e) = cap.filter(&filter) {
+                        eprintln!("Invalid filter {:?}: {}", filter, e);
+                        return EXIT_SETUP;
+                    }
+                    if replay(cap, &file_iface(path), &mut pipeline, &ports).is_err() {
+                        return EXIT_CAPTURE;
+                    }
                 }
-                Err(pcap::Error::TimeoutExpired) => {}
                 Err(e) => {
-                    eprintln!("Error: {:?}", e);
-                    return;
+                    eprintln!("Failed to open pcap file {:?}: {}", path, e);
+                    return EXIT_SETUP;
                 }
             }
         }
-    });
+        return EXIT_OK;
+    }
+
+    // open every device before starting, so a typo doesn't leave us half running
+    let mut captures = Vec::new();
+    for device in &devices {
+        let device_addrs = get_if_addrs(device);
+        if device_addrs.is_empty() {
+            eprintln!("No address found for interface {:?}", device);
+            return EXIT_SETUP;
+        }
+
+        let mut cap = match Capture::from_device(device.as_str())
+            .expect("from device")
+            .promisc(args.promisc)
+            .timeout(CAPTURE_TIMEOUT_MS)
+            .open()
+        {
+            Ok(cap) => {
+                eprintln!("Listening on device: {:?}", device);
+                cap
+            }
+            Err(e) => {
+                eprintln!("Failed to open interface {:?}: {}", device, e);
+                return EXIT_SETUP;
+            }
+        };
+        // responses are needed as well, so capture both directions
+        if let Err(e) = cap.filter(&filter) {
+            eprintln!("Invalid filter {:?}: {}", filter, e);
+            return EXIT_SETUP;
+        }
+
+        let datalink = match get_datalink(cap.get_datalink()) {
+            Some(link) => link,
+            None => return EXIT_SETUP,
+        };
+
+        captures.push((
+            Arc::<str>::from(device.as_str()),
+            cap,
+            datalink,
+            Arc::new(device_addrs),
+        ));
+    }
+    let ports = Arc::new(ports);
+    let telemetry = pipeline.telemetry.clone();
 
-    let registry = metrics::Registry::new(args.statsd_host, statsd_prefix);
+    // bounded on both ends: workers block on a full channel, and once the pool
+    // queue is full the capture threads shed packets
+    let (tx, rx): (Sender, Receiver) = mpsc::sync_channel(args.max_queue);
+    let pool = ThreadPool::new(cpus);
+    let max_batches = (args.max_queue / BATCH_SIZE).max(1);
+    let dispatcher = Dispatcher {
+        pool: pool.clone(),
+        tx,
+        // only the batches that are being filled or decoded need a buffer,
+        // a backlog allocates and frees its own
+        buffers: Buffers::new(2 * cpus + captures.len()),
+        overload: args.overload,
+        max_batches,
+    };
+
+    let mut joins = Vec::new();
+    for (iface, cap, datalink, device_addrs) in captures {
+        let decoder = Decoder {
+            datalink,
+            device_addrs,
+            ports: ports.clone(),
+            telemetry: telemetry.clone(),
+        };
+        let dispatcher = dispatcher.clone();
+        let registry = registry.clone();
+        let interval = Duration::from_secs(duration);
+        joins.push(thread::spawn(move || {
+            capture(cap, iface, decoder, dispatcher, registry, interval)
+        }));
+    }
+    // the channel closes once every capture thread and pool job is done
+    drop(dispatcher);
+
+    let (stop, stopped) = mpsc::channel::<()>();
     let registry2 = registry.clone();
-    let t = thread::spawn(move || loop {
-        thread::sleep(Duration::from_secs(duration));
-        registry2.send();
+    let telemetry2 = telemetry.clone();
+    // windows end on multiples of the duration, so every instance flushes at
+    // the same wall clock times
+    let t = thread::spawn(move || {
+        let mut sent = now();
+        loop {
+            let end = window_end(cmp::max(now(), sent), duration);
+            let wait = end.checked_sub(now()).unwrap_or_default();
+            if stopped.recv_timeout(wait) != Err(mpsc::RecvTimeoutError::Timeout) {
+                break;
+            }
+            telemetry2.report(&registry2, Some(&pool));
+            registry2.send_at(end);
+            sent = end;
+        }
+        sent
     });
 
-    for (_addr, request) in rx.iter() {
-        let real_ip = request.extra_headers.get("x-forwarded-for");
-        let pdid = request.extra_headers.get("x-xcf-pdid");
-        let host = if let Some(host) = request.host.as_ref() {
-            host.replace(".", "_")
-        } else {
-            continue;
-        };
+    // ends once every capture thread stopped and the pool is drained
+    for (iface, side, segment, ts) in rx.iter() {
+        telemetry.dequeue();
+        pipeline.push(&iface, side, &segment, ts);
+    }
 
-        if let Some(Some(ip)) = real_ip {
-            let unique_ips = registry.get_cardinality(
-                format!("{}.ips_per_{}s", &host, duration),
-                format!("ips_per_{}s", duration),
-                {
-                    let mut map = HashMap::new();
-                    map.insert("host".to_string(), host.clone());
-                    Some(map)
-                },
-            );
-            unique_ips.add(ip.to_owned());
+    drop(stop);
+    let sent = t.join().expect("join timer");
+
+    let mut code = EXIT_OK;
+    for join in joins {
+        if join.join().expect("join").is_err() {
+            code = EXIT_CAPTURE;
         }
+    }
 
-        let reqs = registry.get_counter(
-            format!("{}.reqs_per_{}s", &host, duration),
-            format!("reqs_per_{}s", duration),
-            {
-                let mut map = HashMap::new();
-                map.insert("host".to_string(), host.clone());
-                Some(map)
-            },
+    // the last, partial window, which the timer may have sent already for
+    // segments that were still queued when it fired
+    pipeline.flush(sent);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::Recorder;
+
+    use std::sync::Mutex;
+
+    use crate::sink::{Format, Output};
+
+    fn replay_file(path: &str, ports: &[&str]) -> Vec<String> {
+        replay_config(path, ports, &Config::default())
+    }
+
+    fn replay_config(path: &str, ports: &[&str], config: &Config) -> Vec<String> {
+        let ports = Ports::new(ports.iter().map(|port| port.parse().unwrap()).collect());
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let registry = metrics::Registry::from_sink("nginx", Recorder(lines.clone()));
+        let mut pipeline = Pipeline::new(registry, config, 10, 0).unwrap();
+        let cap = Capture::from_file(path).unwrap();
+        replay(cap, &file_iface(path), &mut pipeline, &ports).unwrap();
+        let lines = lines.lock().unwrap().clone();
+        lines
+    }
+
+    #[test]
+    fn replay_http_pcap() {
+        let lines: Vec<_> = replay_file("../sniffglue/pcaps/http.pcap", &["80"])
+            .into_iter()
+            .filter(|line| {
+                line.starts_with("nginx.latency.p50:")
+                    || line.starts_with("nginx.reqs_per_10s:")
+                    || line.starts_with("nginx.responses_per_10s:")
+            })
+            .collect();
+
+        // the second request is 2 seconds later but already in the next window
+        let mut first = lines[..3].to_vec();
+        first.sort();
+        assert_eq!(
+            first,
+            vec![
+                "nginx.latency.p50:771|g|#host:www_ethereal_com,iface:http,port:80",
+                "nginx.reqs_per_10s:1|c|#host:www_ethereal_com,iface:http,method:GET,port:80,route:/download.html",
+                "nginx.responses_per_10s:1|c|#host:www_ethereal_com,iface:http,port:80,status:2xx",
+            ]
         );
-        reqs.add(1);
-        if args.verbose > 0 {
-            println!("{:?}", &request);
+
+        let mut rest: Vec<_> = lines[3..]
+            .iter()
+            .filter(|line| !line.contains(":0|"))
+            .cloned()
+            .collect();
+        rest.sort();
+        // 931ms, the sketch is within 1%
+        assert_eq!(
+            rest,
+            vec![
+                "nginx.latency.p50:925|g|#host:pagead2_googlesyndication_com,iface:http,port:80",
+                "nginx.reqs_per_10s:1|c|#host:pagead2_googlesyndication_com,iface:http,method:GET,port:80,route:/pagead/ads",
+                "nginx.responses_per_10s:1|c|#host:pagead2_googlesyndication_com,iface:http,port:80,status:2xx",
+            ]
+        );
+    }
+
+    #[test]
+    fn continue_before_response() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let registry = metrics::Registry::from_sink("nginx", Recorder(lines.clone()));
+        let mut pipeline = Pipeline::new(registry, &Config::default(), 10, 0).unwrap();
+        let iface: Arc<str> = Arc::from("eth0");
+        let key = sniffglue::reassembly::FlowKey {
+            source_addr: "10.0.0.1".parse().unwrap(),
+            source_port: 41234,
+            dest_addr: "10.0.0.2".parse().unwrap(),
+            dest_port: 80,
+        };
+        let segment = |key, payload: &[u8]| Segment {
+            key,
+            sequence_no: 1,
+            syn: false,
+            fin: false,
+            rst: false,
+            payload: payload.to_vec(),
+        };
+
+        let request = b"POST /upload HTTP/1.1\r\nHost: example.com\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nok";
+        let ts = Duration::from_secs(1_556_000_000);
+        pipeline.push(&iface, Side::Client, &segment(key, request), ts);
+        let response =
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n";
+        let later = ts + Duration::from_millis(300);
+        pipeline.push(
+            &iface,
+            Side::Server,
+            &segment(key.reverse(), response),
+            later,
+        );
+        pipeline.flush(Duration::default());
+
+        let lines = lines.lock().unwrap();
+        let responses: Vec<_> = lines
+            .iter()
+            .filter(|line| line.starts_with("nginx.responses_per_10s:"))
+            .collect();
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].contains("status:2xx"), "{}", responses[0]);
+        // measured to the final response, not to the 100
+        let latency = "nginx.latency.p50:300|g|#host:example_com,iface:eth0,port:80";
+        assert!(lines.contains(&latency.to_string()), "{:?}", lines);
+    }
+
+    #[test]
+    fn replay_access_log() {
+        let path = std::env::temp_dir().join(format!("httpsniffer-replay-{}.log", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let registry = metrics::Registry::new();
+        let mut pipeline = Pipeline::new(registry, &Config::default(), 10, 0).unwrap();
+        let format = accesslog::Format::Combined;
+        pipeline.access_log =
+            Some(AccessLog::open(path.to_str().unwrap(), format, vec![], None).unwrap());
+        let cap = Capture::from_file("../sniffglue/pcaps/http.pcap").unwrap();
+        replay(cap, "http", &mut pipeline, &Ports::default()).unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(
+            lines[0].contains(
+                "\"GET /download.html HTTP/1.1\" - - \"http://www.ethereal.com/development.html\""
+            ),
+            "{}",
+            lines[0]
+        );
+        assert!(lines[0].ends_with("\"www.ethereal.com\""), "{}", lines[0]);
+        assert!(
+            lines[1].contains("\"GET /pagead/ads?client="),
+            "{}",
+            lines[1]
+        );
+    }
+
+    #[test]
+    fn replay_har() {
+        let path = std::env::temp_dir().join(format!("httpsniffer-replay-{}.har", process::id()));
+        let registry = metrics::Registry::new();
+        let mut pipeline = Pipeline::new(registry, &Config::default(), 10, 0).unwrap();
+        pipeline.har = Some(Har::open(path.to_str().unwrap(), false).unwrap());
+        let cap = Capture::from_file("../sniffglue/pcaps/http.pcap").unwrap();
+        replay(cap, "http", &mut pipeline, &Ports::default()).unwrap();
+        // closes the document
+        drop(pipeline);
+
+        let har: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0]["request"]["url"],
+            "http://www.ethereal.com/download.html"
+        );
+        assert_eq!(entries[0]["response"]["status"], 200);
+        assert_eq!(
+            entries[0]["timings"]["wait"].as_f64().unwrap().round(),
+            771.0
+        );
+        assert!(entries[1]["request"]["url"]
+            .as_str()
+            .unwrap()
+            .starts_with("http://pagead2.googlesyndication.com/pagead/ads?client="));
+    }
+
+    #[test]
+    fn replay_any_port() {
+        let mut any = replay_file("../sniffglue/pcaps/http.pcap", &[]);
+        let mut http = replay_file("../sniffglue/pcaps/http.pcap", &["80"]);
+        any.sort();
+        http.sort();
+        assert!(!any.is_empty());
+        assert_eq!(any, http);
+    }
+
+    #[test]
+    fn replay_interfaces() {
+        let ports = Ports::default();
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let registry = metrics::Registry::from_sink("nginx", Recorder(lines.clone()));
+        let mut pipeline = Pipeline::new(registry, &Config::default(), 10, 0).unwrap();
+        for iface in &["bond0", "bond0.100"] {
+            let cap = Capture::from_file("../sniffglue/pcaps/http.pcap").unwrap();
+            replay(cap, iface, &mut pipeline, &ports).unwrap();
         }
 
-        if let Some(Some(pdid)) = pdid {
-            let my_uuid = match Uuid::parse_str(&pdid.replace("-", "")) {
-                Ok(uuid) => uuid,
-                Err(_) => continue,
-            };
-            let unique_pdids = registry.get_cardinality(
-                format!("{}.pdids_per_{}s", &host, duration),
-                format!("pdids_per_{}s", duration),
-                {
-                    let mut map = HashMap::new();
-                    map.insert("host".to_string(), host.clone());
-                    Some(map)
-                },
+        let lines = lines.lock().unwrap();
+        for iface in &["bond0", "bond0.100"] {
+            let latency = format!(
+                "nginx.latency.p50:771|g|#host:www_ethereal_com,iface:{},port:80",
+                iface
             );
-            unique_pdids.add(my_uuid.to_string());
+            assert_eq!(lines.iter().filter(|line| **line == latency).count(), 1);
         }
     }
 
-    t.join().expect("join timer");
-    join.join().expect("join");
+    #[test]
+    fn replay_request_histograms() {
+        let lines = replay_file("../sniffglue/pcaps/http.pcap", &["80"]);
+        let tags = "|#host:www_ethereal_com,iface:http,port:80";
+        for line in &[
+            "nginx.request_body_bytes.p50:0|g",
+            "nginx.request_body_bytes.count:1|c",
+            "nginx.request_headers.p50:9|g",
+            "nginx.request_headers.p99:9|g",
+            "nginx.request_headers.count:1|c",
+        ] {
+            let line = format!("{}{}", line, tags);
+            assert!(lines.contains(&line), "{} not in {:?}", line, lines);
+        }
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("nginx.streams:") && line.ends_with("|g|#iface:http")));
+    }
+
+    #[test]
+    fn replay_top_uris() {
+        let config: Config = toml::from_str(
+            r#"
+            [[cardinality]]
+            name = "uris"
+            source = "uri"
+            top = 3
+            "#,
+        )
+        .unwrap();
+        let lines = replay_config("../sniffglue/pcaps/http.pcap", &["80"], &config);
+
+        let top: Vec<_> = lines
+            .iter()
+            .filter(|line| line.starts_with("nginx.uris_top_per_10s:"))
+            .collect();
+        assert_eq!(top.len(), 2);
+        assert_eq!(
+            top[0],
+            "nginx.uris_top_per_10s:1|g|#host:www_ethereal_com,iface:http,port:80,value:/download.html"
+        );
+        assert!(top[1].contains(",port:80,value:/pagead/ads?client="));
+    }
+
+    #[test]
+    fn replay_resolutions() {
+        let config: Config = toml::from_str(
+            r#"
+            [[cardinality]]
+            name = "ips"
+            source = "client_ip"
+            resolutions = ["1m", "1d"]
+            "#,
+        )
+        .unwrap();
+        let lines = replay_config("../sniffglue/pcaps/http.pcap", &["80"], &config);
+
+        // both requests are within the same minute, but in different windows
+        let mut daily: Vec<_> = lines
+            .iter()
+            .filter(|line| {
+                line.starts_with("nginx.ips_per_1m:") || line.starts_with("nginx.ips_per_1d:")
+            })
+            .collect();
+        // the last flush reports all of them
+        let mut daily = daily.split_off(daily.len() - 4);
+        daily.sort();
+        assert_eq!(
+            daily,
+            vec![
+                "nginx.ips_per_1d:1|g|#host:pagead2_googlesyndication_com,iface:http,port:80",
+                "nginx.ips_per_1d:1|g|#host:www_ethereal_com,iface:http,port:80",
+                "nginx.ips_per_1m:1|g|#host:pagead2_googlesyndication_com,iface:http,port:80",
+                "nginx.ips_per_1m:1|g|#host:www_ethereal_com,iface:http,port:80",
+            ]
+        );
+
+        let mut config = config;
+        config.cardinality[0].resolutions = vec!["5s".to_string()];
+        assert!(Pipeline::new(metrics::Registry::new(), &config, 10, 0).is_err());
+    }
+
+    #[test]
+    fn replay_sliding() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = metrics::Registry::new();
+        registry.add_exporter(Arc::new(Output::new(
+            Format::Json,
+            "nginx",
+            Box::new(Recorder(lines.clone())),
+        )));
+        let mut pipeline = Pipeline::new(registry, &Config::default(), 10, 0).unwrap();
+        pipeline.sliding = Some(60);
+        let cap = Capture::from_file("../sniffglue/pcaps/http.pcap").unwrap();
+        replay(cap, "http", &mut pipeline, &Ports::default()).unwrap();
+
+        let lines: Vec<serde_json::Value> = lines
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .filter(|line: &serde_json::Value| line["metric"] == "nginx.reqs_per_60s")
+            .collect();
+        // every flush is stamped with the end of its window, and the request
+        // is still counted in the windows after the one it was seen in
+        let ethereal: Vec<_> = lines
+            .iter()
+            .filter(|line| line["tags"]["host"] == "www_ethereal_com")
+            .map(|line| (line["timestamp"].as_u64().unwrap(), line["value"].clone()))
+            .collect();
+        assert_eq!(
+            ethereal,
+            vec![
+                (1_084_443_430, serde_json::json!(1)),
+                (1_084_443_440, serde_json::json!(1)),
+                (1_084_443_450, serde_json::json!(1)),
+                (1_084_443_460, serde_json::json!(1)),
+            ]
+        );
+        assert_eq!(lines.len(), 7);
+    }
+
+    #[test]
+    fn replay_host_allow_list() {
+        let config: Config = toml::from_str(
+            r#"
+            [hosts]
+            allow = [".ethereal.com"]
+            "#,
+        )
+        .unwrap();
+        let lines = replay_config("../sniffglue/pcaps/http.pcap", &["80"], &config);
+
+        let latency: Vec<_> = lines
+            .iter()
+            .filter(|line| line.starts_with("nginx.latency.count:"))
+            .collect();
+        assert_eq!(latency.len(), 2);
+        assert!(latency[0].ends_with("|c|#host:www_ethereal_com,iface:http,port:80"));
+        assert!(latency[1].ends_with("|c|#host:other,iface:http,port:80"));
+    }
+
+    #[test]
+    fn replay_telemetry() {
+        let lines = replay_file("../sniffglue/pcaps/http.pcap", &["80"]);
+        let telemetry: Vec<_> = lines
+            .iter()
+            .filter(|line| line.starts_with("nginx.httpsniffer.self."))
+            .collect();
+        assert!(telemetry.contains(&&"nginx.httpsniffer.self.http_errors:0|c".to_string()));
+        assert!(telemetry
+            .iter()
+            .any(|line| line.starts_with("nginx.httpsniffer.self.packets:")
+                && !line.starts_with("nginx.httpsniffer.self.packets:0|")));
+        // there is no pool when replaying
+        assert!(!telemetry.iter().any(|line| line.contains("_queue:")));
+    }
+
+    #[test]
+    fn replay_port_filter() {
+        let lines = replay_file("../sniffglue/pcaps/http.pcap", &["8000-8100", "443"]);
+        assert!(lines
+            .iter()
+            .all(|line| line.starts_with("nginx.httpsniffer.self.")));
+
+        let lines = replay_file("../sniffglue/pcaps/http.pcap", &["443", "1-100"]);
+        assert!(!lines.is_empty());
+    }
 }
@@ -1,64 +1,233 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::net::ToSocketAddrs;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use cadence::Counted;
-use cadence::NopMetricSink;
-use cadence::StatsdClient;
+use crate::hll::HyperLogLog;
+use crate::quantile::QuantileSketch;
+use crate::sink::{Format, MetricSink, Output};
+use crate::topk::{HeavyHitter, SpaceSaving};
 
 pub type CardinalityItem = String;
 
+/// Tag of the metric that collects everything beyond `Limits::max_metrics`
+pub const OVERFLOW_TAG: &str = "overflow";
+/// Number of metrics dropped by the last flush for being idle
+const EVICTED_KEY: &str = "metrics_evicted";
+/// Locks the map of metrics is split into
+const MAP_SHARDS: usize = 16;
+
+/// Tags in a stable order, so the same metric always renders the same way
+pub fn sorted(tags: &HashMap<String, String>) -> Vec<(&String, &String)> {
+    let mut tags: Vec<_> = tags.iter().collect();
+    tags.sort();
+    tags
+}
+
+/// Quantiles reported for every histogram, with their metric suffix
+pub const QUANTILES: &[(f64, &str)] = &[(0.5, "p50"), (0.9, "p90"), (0.99, "p99")];
+
+/// How a cardinality metric keeps track of the values it has seen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// Every distinct value, exact but grows with the number of values
+    Exact,
+    /// A HyperLogLog sketch with the given standard error, fixed size
+    HyperLogLog(f64),
+}
+
+/// The values of the windows that ended within `span` before the last flush,
+/// for metrics that report a longer period every window
+struct Sliding<T> {
+    span: Duration,
+    windows: Mutex<VecDeque<(Duration, T)>>,
+}
+
+impl<T> Sliding<T> {
+    fn new(span: Duration) -> Sliding<T> {
+        Sliding {
+            span,
+            windows: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Add the window that ended at `timestamp`, and combine it with the
+    /// others that are still within the span
+    fn push<R>(
+        &self,
+        timestamp: Duration,
+        value: T,
+        combine: impl FnOnce(&mut VecDeque<(Duration, T)>) -> R,
+    ) -> R {
+        let mut windows = self.windows.lock().expect("lock sliding");
+        windows.push_back((timestamp, value));
+        while let Some(&(ended, _)) = windows.front() {
+            if ended + self.span > timestamp {
+                break;
+            }
+            windows.pop_front();
+        }
+        combine(&mut windows)
+    }
+}
+
 pub struct Cardinality {
     name: String,
     key: String,
-    inner: Arc<RwLock<InnerCardinality>>,
+    backend: Backend,
+    inner: Arc<InnerCardinality>,
 }
 
 struct InnerCardinality {
-    tags: HashMap<String, String>,
-    set: HashSet<CardinalityItem>,
+    tags: RwLock<HashMap<String, String>>,
+    values: Mutex<Values>,
+    sliding: Option<SlidingValues>,
 }
 
-impl InnerCardinality {
-    fn new() -> Self {
-        InnerCardinality {
-            tags: HashMap::new(),
-            set: HashSet::new(),
+/// The distinct values of the windows within a span
+enum SlidingValues {
+    /// Every value with the end of the last window it was seen in, so the
+    /// sets of the windows don't have to be kept and merged again
+    Exact(Duration, Mutex<HashMap<CardinalityItem, Duration>>),
+    /// The sketches of the windows, merging them is cheap
+    Sketch(Sliding<Values>),
+}
+
+impl SlidingValues {
+    fn new(backend: Backend, span: Duration) -> SlidingValues {
+        match backend {
+            Backend::Exact => SlidingValues::Exact(span, Mutex::new(HashMap::new())),
+            Backend::HyperLogLog(_) => SlidingValues::Sketch(Sliding::new(span)),
+        }
+    }
+
+    /// Add the window that ended at `timestamp`, returns the number of
+    /// distinct values within the span
+    fn push(&self, timestamp: Duration, values: Values, backend: Backend) -> usize {
+        match (self, values) {
+            (SlidingValues::Exact(span, seen), Values::Exact(set)) => {
+                let mut seen = seen.lock().expect("lock sliding");
+                for value in set {
+                    seen.insert(value, timestamp);
+                }
+                seen.retain(|_, ended| *ended + *span > timestamp);
+                seen.len()
+            }
+            (SlidingValues::Sketch(sliding), values) => {
+                sliding.push(timestamp, values, |windows| {
+                    let mut all = Values::new(backend);
+                    for (_, values) in windows.iter() {
+                        all.merge(values);
+                    }
+                    all.len()
+                })
+            }
+            _ => unreachable!("cardinality backends are fixed"),
+        }
+    }
+}
+
+enum Values {
+    Exact(HashSet<CardinalityItem>),
+    Sketch(HyperLogLog),
+}
+
+impl Values {
+    fn new(backend: Backend) -> Values {
+        match backend {
+            Backend::Exact => Values::Exact(HashSet::new()),
+            Backend::HyperLogLog(error) => Values::Sketch(HyperLogLog::new(error)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Values::Exact(set) => set.len(),
+            Values::Sketch(hll) => hll.len(),
+        }
+    }
+
+    /// Add the values of `other`
+    fn merge(&mut self, other: &Values) {
+        match (self, other) {
+            (Values::Exact(set), Values::Exact(other)) => set.extend(other.iter().cloned()),
+            (Values::Sketch(hll), Values::Sketch(other)) => hll.merge(other),
+            _ => unreachable!("cardinality backends are fixed"),
         }
     }
 }
 
 impl Cardinality {
-    pub fn new(name: impl Into<String>, key: impl Into<String>) -> Cardinality {
+    pub fn new(name: impl Into<String>, key: impl Into<String>, backend: Backend) -> Cardinality {
+        Cardinality::with_span(name, key, backend, None)
+    }
+
+    /// Reports the distinct values of the windows within `span` every window
+    pub fn sliding(
+        name: impl Into<String>,
+        key: impl Into<String>,
+        backend: Backend,
+        span: Duration,
+    ) -> Cardinality {
+        Cardinality::with_span(name, key, backend, Some(span))
+    }
+
+    fn with_span(
+        name: impl Into<String>,
+        key: impl Into<String>,
+        backend: Backend,
+        span: Option<Duration>,
+    ) -> Cardinality {
         Cardinality {
             key: key.into(),
             name: name.into(),
-            inner: Arc::new(RwLock::new(InnerCardinality::new())),
+            backend,
+            inner: Arc::new(InnerCardinality {
+                tags: RwLock::new(HashMap::new()),
+                values: Mutex::new(Values::new(backend)),
+                sliding: span.map(|span| SlidingValues::new(backend, span)),
+            }),
         }
     }
 
+    /// Returns true if the value is new to the window
     pub fn add(&self, item: CardinalityItem) -> bool {
-        self.inner.write().expect("lock write").set.insert(item)
+        match *self.inner.values.lock().expect("add") {
+            Values::Exact(ref mut set) => set.insert(item),
+            Values::Sketch(ref mut hll) => hll.insert(&item),
+        }
     }
 
     pub fn set_tags(&self, tags: Option<HashMap<String, String>>) {
         if let Some(tags) = tags {
-            self.inner.write().expect("lock write").tags = tags;
+            *self.inner.tags.write().expect("lock write") = tags;
         }
     }
 
+    /// Distinct values of the current window
     #[allow(dead_code)]
     pub fn len(&self) -> usize {
-        self.inner.read().expect("lock read").set.len()
+        self.inner.values.lock().expect("len").len()
     }
 
-    pub fn flush(&self) -> (usize, HashMap<String, String>) {
-        let mut inner = self.inner.write().expect("lock write");
-        let len = inner.set.len();
-        let tags = inner.tags.clone();
-        inner.set.clear();
+    /// `timestamp` is the end of the window
+    pub fn flush(&self, timestamp: Duration) -> (usize, HashMap<String, String>) {
+        let tags = self.inner.tags.read().expect("lock read").clone();
+        let values = mem::replace(
+            &mut *self.inner.values.lock().expect("flush"),
+            Values::new(self.backend),
+        );
+        let len = match self.inner.sliding {
+            Some(ref sliding) => sliding.push(timestamp, values, self.backend),
+            // the old set is freed here, after the lock is released
+            None => values.len(),
+        };
         (len, tags)
     }
 }
@@ -68,46 +237,154 @@ impl Clone for Cardinality {
         Cardinality {
             key: self.key.clone(),
             name: self.name.clone(),
+            backend: self.backend,
             inner: self.inner.clone(),
         }
     }
 }
 
+/// Adding never blocks, the count is atomic
 #[derive(Clone)]
 pub struct Counter {
-    name: String,
     key: String,
-    inner: Arc<RwLock<InnerCounter>>,
+    inner: Arc<InnerCounter>,
 }
 
 struct InnerCounter {
+    tags: RwLock<HashMap<String, String>>,
+    size: AtomicUsize,
+    sliding: Option<Sliding<usize>>,
+}
+
+impl Counter {
+    pub fn new(key: impl Into<String>) -> Counter {
+        Counter::with_span(key, None)
+    }
+
+    /// Reports the sum of the windows within `span` every window
+    pub fn sliding(key: impl Into<String>, span: Duration) -> Counter {
+        Counter::with_span(key, Some(span))
+    }
+
+    fn with_span(key: impl Into<String>, span: Option<Duration>) -> Counter {
+        Counter {
+            key: key.into(),
+            inner: Arc::new(InnerCounter {
+                tags: RwLock::new(HashMap::new()),
+                size: AtomicUsize::new(0),
+                sliding: span.map(Sliding::new),
+            }),
+        }
+    }
+
+    pub fn add(&self, val: usize) {
+        self.inner.size.fetch_add(val, Ordering::Relaxed);
+    }
+
+    pub fn set_tags(&self, tags: Option<HashMap<String, String>>) {
+        if let Some(tags) = tags {
+            *self.inner.tags.write().expect("lock write") = tags;
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn value(&self) -> usize {
+        self.inner.size.load(Ordering::Relaxed)
+    }
+
+    /// `timestamp` is the end of the window
+    pub fn flush(&self, timestamp: Duration) -> (usize, HashMap<String, String>) {
+        let tags = self.inner.tags.read().expect("lock read").clone();
+        let size = self.inner.size.swap(0, Ordering::Relaxed);
+        let size = match self.inner.sliding {
+            Some(ref sliding) => sliding.push(timestamp, size, |windows| {
+                windows.iter().map(|(_, size)| size).sum()
+            }),
+            None => size,
+        };
+        (size, tags)
+    }
+}
+
+#[derive(Clone)]
+pub struct TopK {
+    key: String,
+    inner: Arc<RwLock<InnerTopK>>,
+}
+
+struct InnerTopK {
     tags: HashMap<String, String>,
     size: usize,
+    sketch: SpaceSaving,
+}
+
+impl InnerTopK {
+    fn new(size: usize) -> Self {
+        InnerTopK {
+            tags: HashMap::new(),
+            size,
+            sketch: SpaceSaving::new(size),
+        }
+    }
+}
+
+impl TopK {
+    /// Reports the `size` most frequent values of every window
+    pub fn new(key: impl Into<String>, size: usize) -> TopK {
+        TopK {
+            key: key.into(),
+            inner: Arc::new(RwLock::new(InnerTopK::new(size))),
+        }
+    }
+
+    pub fn add(&self, item: &str) {
+        self.inner.write().expect("add").sketch.insert(item);
+    }
+
+    pub fn set_tags(&self, tags: Option<HashMap<String, String>>) {
+        if let Some(tags) = tags {
+            self.inner.write().expect("lock write").tags = tags;
+        }
+    }
+
+    pub fn flush(&self) -> (Vec<HeavyHitter>, HashMap<String, String>) {
+        let mut inner = self.inner.write().expect("flush");
+        let top = inner.sketch.top(inner.size);
+        inner.sketch.clear();
+        (top, inner.tags.clone())
+    }
 }
 
-impl InnerCounter {
+#[derive(Clone)]
+pub struct Gauge {
+    key: String,
+    inner: Arc<RwLock<InnerGauge>>,
+}
+
+struct InnerGauge {
+    tags: HashMap<String, String>,
+    value: u64,
+}
+
+impl InnerGauge {
     fn new() -> Self {
-        InnerCounter {
+        InnerGauge {
             tags: HashMap::new(),
-            size: 0,
+            value: 0,
         }
     }
 }
 
-impl Counter {
-    pub fn new(name: impl Into<String>, key: impl Into<String>) -> Counter {
-        Counter {
+impl Gauge {
+    pub fn new(key: impl Into<String>) -> Gauge {
+        Gauge {
             key: key.into(),
-            name: name.into(),
-            inner: Arc::new(RwLock::new(InnerCounter::new())),
+            inner: Arc::new(RwLock::new(InnerGauge::new())),
         }
     }
 
-    pub fn add(&self, val: usize) -> usize {
-        let mut inner = self.inner.write().expect("add");
-        let old = inner.size;
-        inner.size += val;
-        old
+    pub fn set(&self, value: u64) {
+        self.inner.write().expect("set").value = value;
     }
 
     pub fn set_tags(&self, tags: Option<HashMap<String, String>>) {
@@ -116,90 +393,293 @@ impl Counter {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn value(&self) -> usize {
-        self.inner.read().expect("value").size
+    /// Gauges keep their value, it is reported again until it is set
+    pub fn flush(&self) -> (u64, HashMap<String, String>) {
+        let inner = self.inner.read().expect("flush");
+        (inner.value, inner.tags.clone())
     }
+}
+
+#[derive(Clone)]
+pub struct Histogram {
+    key: String,
+    inner: Arc<RwLock<InnerHistogram>>,
+}
 
-    pub fn flush(&self) -> (usize, HashMap<String, String>) {
-        let mut inner = self.inner.write().expect("add");
-        let old = inner.size;
-        let tags = inner.tags.clone();
-        inner.size = 0;
-        (old, tags)
+struct InnerHistogram {
+    tags: HashMap<String, String>,
+    sketch: QuantileSketch,
+}
+
+impl InnerHistogram {
+    fn new() -> Self {
+        InnerHistogram {
+            tags: HashMap::new(),
+            sketch: QuantileSketch::default(),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn new(key: impl Into<String>) -> Histogram {
+        Histogram {
+            key: key.into(),
+            inner: Arc::new(RwLock::new(InnerHistogram::new())),
+        }
+    }
+
+    pub fn add(&self, value: u64) {
+        self.inner.write().expect("add").sketch.insert(value);
+    }
+
+    pub fn set_tags(&self, tags: Option<HashMap<String, String>>) {
+        if let Some(tags) = tags {
+            self.inner.write().expect("lock write").tags = tags;
+        }
+    }
+
+    pub fn flush(&self) -> (QuantileSketch, HashMap<String, String>) {
+        let mut inner = self.inner.write().expect("flush");
+        let sketch = mem::take(&mut inner.sketch);
+        (sketch, inner.tags.clone())
     }
 }
 
 enum Metric {
     Cardinality(Cardinality),
     Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+    TopK(TopK),
+}
+
+impl Metric {
+    /// The key and tags the metric is exported with
+    fn labels(&self) -> (&str, HashMap<String, String>) {
+        match self {
+            Metric::Cardinality(m) => (&m.key, m.inner.tags.read().expect("lock read").clone()),
+            Metric::Counter(m) => (&m.key, m.inner.tags.read().expect("lock read").clone()),
+            Metric::Gauge(m) => (&m.key, m.inner.read().expect("lock read").tags.clone()),
+            Metric::Histogram(m) => (&m.key, m.inner.read().expect("lock read").tags.clone()),
+            Metric::TopK(m) => (&m.key, m.inner.read().expect("lock read").tags.clone()),
+        }
+    }
+}
+
+/// Receives every metric when the registry is flushed, in addition to statsd
+pub trait Exporter: Send + Sync {
+    /// Number of events in the last window
+    fn counter(&self, key: &str, value: usize, tags: &HashMap<String, String>);
+    /// Number of distinct values in the last window
+    fn cardinality(&self, key: &str, value: usize, tags: &HashMap<String, String>);
+    /// Current value of a gauge
+    fn gauge(&self, key: &str, value: u64, tags: &HashMap<String, String>);
+    /// Distribution of the values recorded in the last window
+    fn histogram(&self, key: &str, sketch: &QuantileSketch, tags: &HashMap<String, String>);
+    /// Most frequent values of the last window, highest count first
+    fn topk(&self, _key: &str, _top: &[HeavyHitter], _tags: &HashMap<String, String>) {}
+    /// A flush starts, the metrics that follow are of the window that ended
+    /// at `timestamp` since the epoch
+    fn start(&self, _timestamp: Duration) {}
+    /// Every metric of the window was exported
+    fn flush(&self) {}
+    /// The metric was dropped from the registry, exporters that keep state
+    /// across windows should forget it as well
+    fn evict(&self, _key: &str, _tags: &HashMap<String, String>) {}
+}
+
+/// Bounds on the number of metrics a registry keeps around
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Metrics that reported nothing for this many windows are dropped, 0
+    /// keeps them forever
+    pub idle_windows: usize,
+    /// New metrics beyond this number go into an overflow metric per key
+    pub max_metrics: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            idle_windows: 0,
+            max_metrics: usize::MAX,
+        }
+    }
+}
+
+struct Entry {
+    metric: Metric,
+    /// Number of flushes in a row that had nothing to report
+    idle: AtomicUsize,
 }
 
+/// Metrics by name, split by the hash of the name so that lookups from
+/// different threads rarely touch the same lock
+struct Metrics {
+    shards: Vec<RwLock<HashMap<String, Entry>>>,
+    len: AtomicUsize,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics {
+            shards: (0..MAP_SHARDS)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn shard(&self, name: &str) -> &RwLock<HashMap<String, Entry>> {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % MAP_SHARDS]
+    }
+}
+
+/// Collects metrics and hands them to every exporter once per window. Sinks
+/// like statsd are exporters as well, without any the metrics go nowhere.
 #[derive(Clone)]
 pub struct Registry {
-    metrics: Arc<RwLock<HashMap<String, Metric>>>,
-    client: Arc<StatsdClient>,
+    metrics: Arc<Metrics>,
+    exporters: Vec<Arc<dyn Exporter>>,
+    limits: Limits,
 }
 
 impl Registry {
-    pub fn new<S: ToSocketAddrs>(host: Option<S>, prefix: impl AsRef<str>) -> Self {
-        let client = match host {
-            Some(h) => StatsdClient::from_udp_host(prefix.as_ref(), h).expect("statsd client"),
-            None => StatsdClient::from_sink(prefix.as_ref(), NopMetricSink),
-        };
+    pub fn new() -> Self {
         Registry {
-            metrics: Arc::new(RwLock::new(HashMap::new())),
-            client: Arc::new(client),
+            metrics: Arc::new(Metrics::new()),
+            exporters: Vec::new(),
+            limits: Limits::default(),
         }
     }
 
-    fn new_cardinality(
+    /// A registry that writes dogstatsd lines to `sink`
+    #[allow(dead_code)]
+    pub fn from_sink(prefix: impl AsRef<str>, sink: impl MetricSink + 'static) -> Self {
+        let mut registry = Registry::new();
+        registry.add_exporter(Arc::new(Output::new(
+            Format::DogStatsd,
+            prefix.as_ref(),
+            Box::new(sink),
+        )));
+        registry
+    }
+
+    pub fn add_exporter(&mut self, exporter: Arc<dyn Exporter>) {
+        self.exporters.push(exporter);
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.metrics.len.load(Ordering::Relaxed)
+    }
+
+    /// Look up `name`, or register the metric built by `make`. Once the
+    /// registry is full, new metrics share one overflow metric per key.
+    fn get_or_insert<T, F>(
         &self,
-        name: impl Into<String>,
+        name: String,
         key: impl Into<String>,
         tags: Option<HashMap<String, String>>,
-    ) -> Cardinality {
-        let name = name.into();
+        get: fn(&Metric) -> Option<T>,
+        make: F,
+    ) -> T
+    where
+        F: FnOnce(String, String, Option<HashMap<String, String>>) -> (T, Metric),
+    {
+        let shard = self.metrics.shard(&name);
+        if let Some(metric) = shard
+            .read()
+            .expect("get metric")
+            .get(&name)
+            .and_then(|entry| get(&entry.metric))
+        {
+            return metric;
+        }
+
         let key = key.into();
-        let card = Cardinality::new(name.clone(), key.clone());
-        card.set_tags(tags);
-        self.metrics
-            .write()
-            .expect("new cardinality")
-            .insert(name, Metric::Cardinality(card.clone()));
-        card
+        let full = self.metrics.len.load(Ordering::Relaxed) >= self.limits.max_metrics;
+        let (name, tags) = if full {
+            let mut overflow = HashMap::new();
+            overflow.insert(OVERFLOW_TAG.to_string(), "true".to_string());
+            (format!("{}.{}", OVERFLOW_TAG, key), Some(overflow))
+        } else {
+            (name, tags)
+        };
+
+        let mut metrics = self.metrics.shard(&name).write().expect("new metric");
+        if let Some(metric) = metrics.get(&name).and_then(|entry| get(&entry.metric)) {
+            return metric;
+        }
+
+        let (handle, metric) = make(name.clone(), key, tags);
+        let entry = Entry {
+            metric,
+            idle: AtomicUsize::new(0),
+        };
+        if metrics.insert(name, entry).is_none() {
+            self.metrics.len.fetch_add(1, Ordering::Relaxed);
+        }
+        handle
     }
 
-    fn new_counter(
+    pub fn get_cardinality(
         &self,
         name: impl Into<String>,
         key: impl Into<String>,
         tags: Option<HashMap<String, String>>,
-    ) -> Counter {
-        let name = name.into();
-        let key = key.into();
-        let counter = Counter::new(name.clone(), key.clone());
-        counter.set_tags(tags);
-        self.metrics
-            .write()
-            .expect("new counter")
-            .insert(name, Metric::Counter(counter.clone()));
-        counter
+        backend: Backend,
+    ) -> Cardinality {
+        self.get_or_insert(
+            name.into(),
+            key,
+            tags,
+            |metric| match metric {
+                Metric::Cardinality(card) => Some(card.clone()),
+                _ => None,
+            },
+            |name, key, tags| {
+                let card = Cardinality::new(name, key, backend);
+                card.set_tags(tags);
+                (card.clone(), Metric::Cardinality(card))
+            },
+        )
     }
 
-    pub fn get_cardinality(
+    /// A cardinality that reports the distinct values of the windows within
+    /// `span`, instead of only the last one
+    pub fn get_sliding_cardinality(
         &self,
         name: impl Into<String>,
         key: impl Into<String>,
         tags: Option<HashMap<String, String>>,
+        backend: Backend,
+        span: Duration,
     ) -> Cardinality {
-        let name = name.into();
-        if let Some(Metric::Cardinality(card)) =
-            self.metrics.read().expect("get cardinality").get(&name)
-        {
-            return card.clone();
-        }
-        self.new_cardinality(name, key, tags)
+        self.get_or_insert(
+            name.into(),
+            key,
+            tags,
+            |metric| match metric {
+                Metric::Cardinality(card) => Some(card.clone()),
+                _ => None,
+            },
+            |name, key, tags| {
+                let card = Cardinality::sliding(name, key, backend, span);
+                card.set_tags(tags);
+                (card.clone(), Metric::Cardinality(card))
+            },
+        )
     }
 
     pub fn get_counter(
@@ -208,32 +688,355 @@ impl Registry {
         key: impl Into<String>,
         tags: Option<HashMap<String, String>>,
     ) -> Counter {
-        let name = name.into();
-        if let Some(Metric::Counter(counter)) = self.metrics.read().expect("get counter").get(&name)
-        {
-            return counter.clone();
-        }
-        self.new_counter(name, key, tags)
+        self.get_or_insert(
+            name.into(),
+            key,
+            tags,
+            |metric| match metric {
+                Metric::Counter(counter) => Some(counter.clone()),
+                _ => None,
+            },
+            |_, key, tags| {
+                let counter = Counter::new(key);
+                counter.set_tags(tags);
+                (counter.clone(), Metric::Counter(counter))
+            },
+        )
+    }
+
+    /// A counter that reports the sum of the windows within `span`, instead
+    /// of only the last one
+    pub fn get_sliding_counter(
+        &self,
+        name: impl Into<String>,
+        key: impl Into<String>,
+        tags: Option<HashMap<String, String>>,
+        span: Duration,
+    ) -> Counter {
+        self.get_or_insert(
+            name.into(),
+            key,
+            tags,
+            |metric| match metric {
+                Metric::Counter(counter) => Some(counter.clone()),
+                _ => None,
+            },
+            |_, key, tags| {
+                let counter = Counter::sliding(key, span);
+                counter.set_tags(tags);
+                (counter.clone(), Metric::Counter(counter))
+            },
+        )
+    }
+
+    pub fn get_gauge(
+        &self,
+        name: impl Into<String>,
+        key: impl Into<String>,
+        tags: Option<HashMap<String, String>>,
+    ) -> Gauge {
+        self.get_or_insert(
+            name.into(),
+            key,
+            tags,
+            |metric| match metric {
+                Metric::Gauge(gauge) => Some(gauge.clone()),
+                _ => None,
+            },
+            |_, key, tags| {
+                let gauge = Gauge::new(key);
+                gauge.set_tags(tags);
+                (gauge.clone(), Metric::Gauge(gauge))
+            },
+        )
+    }
+
+    pub fn get_histogram(
+        &self,
+        name: impl Into<String>,
+        key: impl Into<String>,
+        tags: Option<HashMap<String, String>>,
+    ) -> Histogram {
+        self.get_or_insert(
+            name.into(),
+            key,
+            tags,
+            |metric| match metric {
+                Metric::Histogram(histogram) => Some(histogram.clone()),
+                _ => None,
+            },
+            |_, key, tags| {
+                let histogram = Histogram::new(key);
+                histogram.set_tags(tags);
+                (histogram.clone(), Metric::Histogram(histogram))
+            },
+        )
     }
 
+    pub fn get_topk(
+        &self,
+        name: impl Into<String>,
+        key: impl Into<String>,
+        tags: Option<HashMap<String, String>>,
+        size: usize,
+    ) -> TopK {
+        self.get_or_insert(
+            name.into(),
+            key,
+            tags,
+            |metric| match metric {
+                Metric::TopK(topk) => Some(topk.clone()),
+                _ => None,
+            },
+            |_, key, tags| {
+                let topk = TopK::new(key, size);
+                topk.set_tags(tags);
+                (topk.clone(), Metric::TopK(topk))
+            },
+        )
+    }
+
+    /// Send the window that ends now
+    #[allow(dead_code)]
     pub fn send(&self) {
-        for metric in self.metrics.read().expect("send").values() {
-            let (key, (size, tags)) = match metric {
-                Metric::Cardinality(cardinality) => (&cardinality.key, cardinality.flush()),
-                Metric::Counter(counter) => (&counter.key, counter.flush()),
-            };
-
-            let mut builder = self.client.count_with_tags(&key, size as i64);
-            for (k, v) in tags.iter() {
-                builder = builder.with_tag(k, v);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.send_at(now);
+    }
+
+    /// Send the window that ended at `timestamp` since the epoch
+    pub fn send_at(&self, timestamp: Duration) {
+        for exporter in &self.exporters {
+            exporter.start(timestamp);
+        }
+
+        let mut evicted = 0;
+        for shard in &self.metrics.shards {
+            let mut idle = Vec::new();
+            for (name, entry) in shard.read().expect("send").iter() {
+                if self.send_metric(&entry.metric, timestamp) {
+                    entry.idle.store(0, Ordering::Relaxed);
+                } else {
+                    let windows = entry.idle.fetch_add(1, Ordering::Relaxed) + 1;
+                    if self.limits.idle_windows > 0 && windows >= self.limits.idle_windows {
+                        idle.push(name.clone());
+                    }
+                }
             }
-            let ret = builder.try_send();
-            match ret {
-                Ok(..) => {}
-                Err(err) => {
-                    eprintln!("send error: {:?}", err);
+
+            if !idle.is_empty() {
+                let mut metrics = shard.write().expect("evict");
+                for name in &idle {
+                    if let Some(entry) = metrics.remove(name) {
+                        self.metrics.len.fetch_sub(1, Ordering::Relaxed);
+                        evicted += 1;
+                        let (key, tags) = entry.metric.labels();
+                        for exporter in &self.exporters {
+                            exporter.evict(key, &tags);
+                        }
+                    }
                 }
-            };
+            }
+        }
+
+        if self.limits.idle_windows > 0 {
+            let tags = HashMap::new();
+            for exporter in &self.exporters {
+                exporter.counter(EVICTED_KEY, evicted, &tags);
+            }
         }
+        for exporter in &self.exporters {
+            exporter.flush();
+        }
+    }
+
+    /// Returns whether the metric had anything to report
+    fn send_metric(&self, metric: &Metric, timestamp: Duration) -> bool {
+        match metric {
+            Metric::Cardinality(cardinality) => {
+                let (size, tags) = cardinality.flush(timestamp);
+                for exporter in &self.exporters {
+                    exporter.cardinality(&cardinality.key, size, &tags);
+                }
+                size > 0
+            }
+            Metric::Counter(counter) => {
+                let (size, tags) = counter.flush(timestamp);
+                for exporter in &self.exporters {
+                    exporter.counter(&counter.key, size, &tags);
+                }
+                size > 0
+            }
+            Metric::Gauge(gauge) => {
+                let (value, tags) = gauge.flush();
+                for exporter in &self.exporters {
+                    exporter.gauge(&gauge.key, value, &tags);
+                }
+                value > 0
+            }
+            Metric::Histogram(histogram) => {
+                let (sketch, tags) = histogram.flush();
+                if sketch.is_empty() {
+                    return false;
+                }
+                for exporter in &self.exporters {
+                    exporter.histogram(&histogram.key, &sketch, &tags);
+                }
+                true
+            }
+            Metric::TopK(topk) => {
+                let (top, tags) = topk.flush();
+                for exporter in &self.exporters {
+                    exporter.topk(&topk.key, &top, &tags);
+                }
+                !top.is_empty()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::Recorder;
+
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    fn registry(limits: Limits) -> (Registry, Arc<Mutex<Vec<String>>>) {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = Registry::from_sink("", Recorder(lines.clone()));
+        registry.set_limits(limits);
+        (registry, lines)
+    }
+
+    fn host(host: &str) -> Option<HashMap<String, String>> {
+        let mut tags = HashMap::new();
+        tags.insert("host".to_string(), host.to_string());
+        Some(tags)
+    }
+
+    #[test]
+    fn evict_idle() {
+        let (registry, lines) = registry(Limits {
+            idle_windows: 2,
+            max_metrics: 100,
+        });
+        registry.get_counter("a.reqs", "reqs", host("a")).add(1);
+        registry.get_counter("b.reqs", "reqs", host("b")).add(1);
+        registry.send();
+
+        registry.get_counter("a.reqs", "reqs", host("a")).add(1);
+        registry.send();
+        assert_eq!(registry.len(), 2);
+        registry.send();
+        // b stayed zero for two windows, a only for one
+        assert_eq!(registry.len(), 1);
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.last().unwrap(), "metrics_evicted:1|c");
+        assert!(lines.contains(&"metrics_evicted:0|c".to_string()));
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        let (registry, lines) = registry(Limits::default());
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let registry = registry.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        registry.get_counter("reqs", "reqs", None).add(1);
+                        registry
+                            .get_cardinality("ips", "ips", None, Backend::Exact)
+                            .add(format!("10.0.{}.{}", t % 2, i));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        registry.send();
+
+        let mut lines = lines.lock().unwrap().clone();
+        lines.sort();
+        assert_eq!(lines, vec!["ips:2000|c", "reqs:8000|c"]);
+    }
+
+    #[test]
+    fn overflow() {
+        let (registry, lines) = registry(Limits {
+            idle_windows: 0,
+            max_metrics: 2,
+        });
+        for h in &["a", "b", "c", "d"] {
+            registry
+                .get_counter(format!("{}.reqs", h), "reqs", host(h))
+                .add(1);
+        }
+        registry.get_counter("a.reqs", "reqs", host("a")).add(1);
+        assert_eq!(registry.len(), 3);
+        registry.send();
+
+        let mut lines = lines.lock().unwrap().clone();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "reqs:1|c|#host:b",
+                "reqs:2|c|#host:a",
+                "reqs:2|c|#overflow:true",
+            ]
+        );
+    }
+
+    #[test]
+    fn sliding() {
+        let (registry, lines) = registry(Limits::default());
+        let span = Duration::from_secs(30);
+        let reqs = registry.get_sliding_counter("reqs", "reqs_per_30s", None, span);
+        let ips =
+            registry.get_sliding_cardinality("ips", "ips_per_30s", None, Backend::Exact, span);
+
+        let sketch = registry.get_sliding_cardinality(
+            "pdids",
+            "pdids_per_30s",
+            None,
+            Backend::HyperLogLog(0.01),
+            span,
+        );
+
+        for (window, values) in [&["a", "b"][..], &["b"], &[], &["c"]].iter().enumerate() {
+            for value in values.iter() {
+                reqs.add(1);
+                ips.add(value.to_string());
+                sketch.add(value.to_string());
+            }
+            registry.send_at(Duration::from_secs(10 * (window as u64 + 1)));
+        }
+
+        let lines = lines.lock().unwrap();
+        let windows: Vec<_> = lines
+            .chunks(3)
+            .map(|window| {
+                let mut window = window.to_vec();
+                window.sort();
+                window.join(" ")
+            })
+            .collect();
+        // the first window is out of the span once the fourth one ends
+        assert_eq!(
+            windows,
+            vec![
+                "ips_per_30s:2|c pdids_per_30s:2|c reqs_per_30s:2|c",
+                "ips_per_30s:2|c pdids_per_30s:2|c reqs_per_30s:3|c",
+                "ips_per_30s:2|c pdids_per_30s:2|c reqs_per_30s:3|c",
+                "ips_per_30s:2|c pdids_per_30s:2|c reqs_per_30s:2|c",
+            ]
+        );
     }
 }
@@ -0,0 +1,133 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A single port or an inclusive range like `8000-8100`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PortRange, String> {
+        let parse = |x: &str| {
+            x.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port: {:?}", x))
+        };
+
+        let mut parts = s.splitn(2, '-');
+        let start = parse(parts.next().unwrap_or(""))?;
+        let end = match parts.next() {
+            Some(end) => parse(end)?,
+            None => start,
+        };
+        if start > end {
+            return Err(format!("invalid port range: {:?}", s));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl fmt::Display for PortRange {
+    /// Formatted as a bpf primitive
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "port {}", self.start)
+        } else {
+            write!(f, "portrange {}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// The server ports that are watched, no ports means every port
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Ports(Vec<PortRange>);
+
+impl Ports {
+    pub fn new(ranges: Vec<PortRange>) -> Ports {
+        Ports(ranges)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.is_empty() || self.0.iter().any(|range| range.contains(port))
+    }
+
+    /// Capture filter for both directions of the watched ports, an additional
+    /// bpf expression further restricts the captured packets
+    pub fn filter(&self, extra: Option<&str>) -> String {
+        let mut filter = String::from("tcp");
+        if !self.is_empty() {
+            let ranges: Vec<String> = self.0.iter().map(ToString::to_string).collect();
+            filter.push_str(&format!(" and ({})", ranges.join(" or ")));
+        }
+        if let Some(extra) = extra {
+            filter.push_str(&format!(" and ({})", extra));
+        }
+        filter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(
+            "80".parse::<PortRange>(),
+            Ok(PortRange { start: 80, end: 80 })
+        );
+        assert_eq!(
+            "8000-8100".parse::<PortRange>(),
+            Ok(PortRange {
+                start: 8000,
+                end: 8100
+            })
+        );
+        assert!("".parse::<PortRange>().is_err());
+        assert!("http".parse::<PortRange>().is_err());
+        assert!("8100-8000".parse::<PortRange>().is_err());
+        assert!("80-".parse::<PortRange>().is_err());
+        assert!("70000".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn contains() {
+        assert!(Ports::default().contains(1234));
+
+        let ports = Ports::new(vec!["80".parse().unwrap(), "8000-8100".parse().unwrap()]);
+        assert!(ports.contains(80));
+        assert!(ports.contains(8000));
+        assert!(ports.contains(8100));
+        assert!(!ports.contains(81));
+        assert!(!ports.contains(8101));
+    }
+
+    #[test]
+    fn filter() {
+        assert_eq!(Ports::default().filter(None), "tcp");
+        assert_eq!(
+            Ports::default().filter(Some("host 10.0.0.1")),
+            "tcp and (host 10.0.0.1)"
+        );
+
+        let ports = Ports::new(vec!["80".parse().unwrap(), "8000-8100".parse().unwrap()]);
+        assert_eq!(
+            ports.filter(Some("not net 10.0.0.0/8")),
+            "tcp and (port 80 or portrange 8000-8100) and (not net 10.0.0.0/8)"
+        );
+    }
+}
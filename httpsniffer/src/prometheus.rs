@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::metrics::{Exporter, QUANTILES};
+use crate::quantile::QuantileSketch;
+
+/// Requests larger than this are rejected
+const MAX_REQUEST: usize = 8 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Summary,
+}
+
+#[derive(Debug, Default)]
+struct Series {
+    value: u64,
+    count: u64,
+    /// Quantiles of the last window, only used by histograms
+    quantiles: Vec<(f64, u64)>,
+}
+
+#[derive(Debug)]
+struct Family {
+    kind: Kind,
+    series: BTreeMap<String, Series>,
+}
+
+/// Keeps the registry's metrics in Prometheus text format
+///
+/// Counters are accumulated over every flush instead of being reset, unique
+/// counts are gauges holding the value of the last window and histograms are
+/// summaries with the quantiles of the last window and a cumulative sum and
+/// count. Tags become labels.
+pub struct Prometheus {
+    namespace: String,
+    families: Mutex<BTreeMap<String, Family>>,
+}
+
+impl Prometheus {
+    pub fn new(namespace: impl AsRef<str>) -> Prometheus {
+        Prometheus {
+            namespace: sanitize(namespace.as_ref()),
+            families: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn update<F>(&self, name: String, kind: Kind, tags: &HashMap<String, String>, f: F)
+    where
+        F: FnOnce(&mut Series),
+    {
+        let mut families = self.families.lock().expect("lock families");
+        let family = families.entry(name).or_insert_with(|| Family {
+            kind,
+            series: BTreeMap::new(),
+        });
+        if family.kind != kind {
+            // the same key was used for different kinds of metrics
+            return;
+        }
+        f(family.series.entry(labels(tags)).or_default());
+    }
+
+    fn name(&self, key: &str, suffix: &str) -> String {
+        let mut name = String::new();
+        if !self.namespace.is_empty() {
+            name.push_str(&self.namespace);
+            name.push('_');
+        }
+        name.push_str(&sanitize(key));
+        name.push_str(suffix);
+        name
+    }
+
+    pub fn render(&self) -> String {
+        let families = self.families.lock().expect("lock families");
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Summary => "summary",
+            };
+            writeln!(out, "# TYPE {} {}", name, kind).expect("write");
+            for (labels, series) in &family.series {
+                match family.kind {
+                    Kind::Counter | Kind::Gauge => {
+                        writeln!(out, "{}{} {}", name, labels, series.value).expect("write");
+                    }
+                    Kind::Summary => {
+                        for (q, value) in &series.quantiles {
+                            let labels = with_label(labels, "quantile", &q.to_string());
+                            writeln!(out, "{}{} {}", name, labels, value).expect("write");
+                        }
+                        writeln!(out, "{}_sum{} {}", name, labels, series.value).expect("write");
+                        writeln!(out, "{}_count{} {}", name, labels, series.count).expect("write");
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Serve `/metrics` on `addr` from a background thread
+    pub fn serve<A: ToSocketAddrs>(self: Arc<Self>, addr: A) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        eprintln!("prometheus accept error: {:?}", err);
+                        continue;
+                    }
+                };
+                if let Err(err) = self.handle(stream) {
+                    eprintln!("prometheus error: {:?}", err);
+                }
+            }
+        });
+
+        Ok(local_addr)
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+            let n = stream.read(&mut buf)?;
+            if n == 0 || request.len() + n > MAX_REQUEST {
+                return Ok(());
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        let line = request.split(|&c| c == b'\r').next().unwrap_or(b"");
+        let mut parts = line.split(|&c| c == b' ');
+        let method = parts.next().unwrap_or(b"");
+        let path = parts.next().unwrap_or(b"");
+        let path = path.split(|&c| c == b'?').next().unwrap_or(b"");
+
+        let (status, body) = if method != b"GET" {
+            ("405 Method Not Allowed", String::new())
+        } else if path != b"/metrics" {
+            ("404 Not Found", String::new())
+        } else {
+            ("200 OK", self.render())
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        )?;
+        stream.write_all(body.as_bytes())?;
+        stream.flush()
+    }
+}
+
+impl Exporter for Prometheus {
+    fn counter(&self, key: &str, value: usize, tags: &HashMap<String, String>) {
+        let name = self.name(key, "_total");
+        self.update(name, Kind::Counter, tags, |series| {
+            series.value += value as u64;
+        });
+    }
+
+    fn cardinality(&self, key: &str, value: usize, tags: &HashMap<String, String>) {
+        let name = self.name(key, "");
+        self.update(name, Kind::Gauge, tags, |series| {
+            series.value = value as u64;
+        });
+    }
+
+    fn gauge(&self, key: &str, value: u64, tags: &HashMap<String, String>) {
+        let name = self.name(key, "");
+        self.update(name, Kind::Gauge, tags, |series| {
+            series.value = value;
+        });
+    }
+
+    fn histogram(&self, key: &str, sketch: &QuantileSketch, tags: &HashMap<String, String>) {
+        let name = self.name(key, "");
+        self.update(name, Kind::Summary, tags, |series| {
+            series.value += sketch.sum();
+            series.count += sketch.count();
+            series.quantiles = QUANTILES
+                .iter()
+                .filter_map(|&(q, _)| sketch.quantile(q).map(|value| (q, value)))
+                .collect();
+        });
+    }
+
+    /// Evicted metrics disappear from the output instead of repeating their
+    /// last value forever
+    fn evict(&self, key: &str, tags: &HashMap<String, String>) {
+        let labels = labels(tags);
+        let mut families = self.families.lock().expect("lock families");
+        // the kind isn't known here, try the name of every kind
+        for suffix in &["", "_total"] {
+            let name = self.name(key, suffix);
+            let empty = match families.get_mut(&name) {
+                Some(family) => {
+                    family.series.remove(&labels);
+                    family.series.is_empty()
+                }
+                None => false,
+            };
+            if empty {
+                families.remove(&name);
+            }
+        }
+    }
+}
+
+/// Replace everything that isn't allowed in a metric or label name
+fn sanitize(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+fn labels(tags: &HashMap<String, String>) -> String {
+    if tags.is_empty() {
+        return String::new();
+    }
+
+    let mut tags: Vec<_> = tags.iter().collect();
+    tags.sort();
+    let labels: Vec<_> = tags
+        .into_iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", sanitize(k), v)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Add one more label to the rendered labels of a series
+fn with_label(labels: &str, key: &str, value: &str) -> String {
+    let label = format!("{}=\"{}\"", key, value);
+    if labels.is_empty() {
+        format!("{{{}}}", label)
+    } else {
+        format!("{},{}}}", &labels[..labels.len() - 1], label)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::metrics::{Limits, Registry};
+
+    fn tags(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn render() {
+        let prometheus = Prometheus::new("nginx");
+        let www = tags(&[("host", "www_example_com")]);
+        let api = tags(&[("status", "2xx"), ("host", "api\"x")]);
+
+        prometheus.counter("reqs_per_10s", 3, &www);
+        prometheus.counter("reqs_per_10s", 4, &www);
+        prometheus.counter("reqs_per_10s", 1, &api);
+        prometheus.cardinality("ips_per_10s", 5, &www);
+        prometheus.cardinality("ips_per_10s", 2, &www);
+        prometheus.gauge("streams", 12, &HashMap::new());
+        let mut sketch = QuantileSketch::default();
+        for value in 1..=100 {
+            sketch.insert(value);
+        }
+        prometheus.histogram("request_headers", &sketch, &www);
+
+        assert_eq!(
+            prometheus.render(),
+            "# TYPE nginx_ips_per_10s gauge\n\
+             nginx_ips_per_10s{host=\"www_example_com\"} 2\n\
+             # TYPE nginx_reqs_per_10s_total counter\n\
+             nginx_reqs_per_10s_total{host=\"api\\\"x\",status=\"2xx\"} 1\n\
+             nginx_reqs_per_10s_total{host=\"www_example_com\"} 7\n\
+             # TYPE nginx_request_headers summary\n\
+             nginx_request_headers{host=\"www_example_com\",quantile=\"0.5\"} 50\n\
+             nginx_request_headers{host=\"www_example_com\",quantile=\"0.9\"} 89\n\
+             nginx_request_headers{host=\"www_example_com\",quantile=\"0.99\"} 99\n\
+             nginx_request_headers_sum{host=\"www_example_com\"} 5050\n\
+             nginx_request_headers_count{host=\"www_example_com\"} 100\n\
+             # TYPE nginx_streams gauge\n\
+             nginx_streams 12\n"
+        );
+    }
+
+    #[test]
+    fn evict() {
+        let prometheus = Arc::new(Prometheus::new("nginx"));
+        let mut registry = Registry::new();
+        registry.add_exporter(prometheus.clone());
+        registry.set_limits(Limits {
+            idle_windows: 1,
+            max_metrics: 100,
+        });
+
+        let www = tags(&[("host", "www_example_com")]);
+        let junk = tags(&[("host", "junk")]);
+        registry
+            .get_counter("www.reqs", "reqs", Some(www.clone()))
+            .add(1);
+        registry
+            .get_counter("junk.reqs", "reqs", Some(junk.clone()))
+            .add(1);
+        registry.send();
+        registry.get_counter("www.reqs", "reqs", Some(www)).add(1);
+        registry.send();
+
+        let rendered = prometheus.render();
+        assert!(rendered.contains("nginx_reqs_total{host=\"www_example_com\"} 2\n"));
+        assert!(!rendered.contains("junk"), "{}", rendered);
+    }
+
+    #[test]
+    fn sanitize_names() {
+        assert_eq!(sanitize("xlb-01.nginx"), "xlb_01_nginx");
+        assert_eq!(sanitize("5xx"), "_5xx");
+        assert_eq!(Prometheus::new("").name("reqs", "_total"), "reqs_total");
+    }
+
+    #[test]
+    fn serve() {
+        let prometheus = Arc::new(Prometheus::new(""));
+        prometheus.counter("reqs", 1, &HashMap::new());
+        let addr = prometheus.serve("127.0.0.1:0").unwrap();
+
+        let get = |request: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n# TYPE reqs_total counter\nreqs_total 1\n"));
+
+        let response = get("GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+/// Relative error of the reported quantiles
+pub const DEFAULT_ACCURACY: f64 = 0.01;
+
+/// Streaming quantile sketch with relative accuracy
+///
+/// Values are counted in logarithmically sized buckets, a value `x` lands in
+/// bucket `ceil(log_gamma(x))` with `gamma = (1 + a) / (1 - a)`. Every
+/// quantile is then within a relative error `a` of the exact one, and the
+/// number of buckets only grows with the logarithm of the largest value.
+#[derive(Debug, Clone)]
+pub struct QuantileSketch {
+    gamma: f64,
+    ln_gamma: f64,
+    zeros: u64,
+    buckets: BTreeMap<i32, u64>,
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl QuantileSketch {
+    pub fn new(accuracy: f64) -> QuantileSketch {
+        let gamma = (1.0 + accuracy) / (1.0 - accuracy);
+        QuantileSketch {
+            gamma,
+            ln_gamma: gamma.ln(),
+            zeros: 0,
+            buckets: BTreeMap::new(),
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+
+    pub fn insert(&mut self, value: u64) {
+        if value == 0 {
+            self.zeros += 1;
+        } else {
+            let index = ((value as f64).ln() / self.ln_gamma).ceil() as i32;
+            *self.buckets.entry(index).or_insert(0) += 1;
+        }
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.max = self.max.max(value);
+    }
+
+    /// The value below which a fraction `q` of the values fall
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        if q >= 1.0 {
+            return Some(self.max);
+        }
+
+        let rank = (q.max(0.0) * (self.count - 1) as f64) as u64;
+        let mut seen = self.zeros;
+        if seen > rank {
+            return Some(0);
+        }
+        for (&index, &count) in &self.buckets {
+            seen += count;
+            if seen > rank {
+                // the middle of the bucket has the smallest relative error
+                let value = 2.0 * self.gamma.powi(index) / (self.gamma + 1.0);
+                return Some((value.round() as u64).min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+impl Default for QuantileSketch {
+    fn default() -> QuantileSketch {
+        QuantileSketch::new(DEFAULT_ACCURACY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty() {
+        let sketch = QuantileSketch::default();
+        assert!(sketch.is_empty());
+        assert_eq!(sketch.quantile(0.5), None);
+    }
+
+    #[test]
+    fn quantiles_within_accuracy() {
+        let mut sketch = QuantileSketch::default();
+        for value in 1..=10_000 {
+            sketch.insert(value);
+        }
+        assert_eq!(sketch.count(), 10_000);
+        assert_eq!(sketch.sum(), 50_005_000);
+
+        for &(q, exact) in &[(0.5, 5000.0), (0.9, 9000.0), (0.99, 9900.0)] {
+            let estimate = sketch.quantile(q).unwrap() as f64;
+            assert!(
+                (estimate - exact).abs() <= exact * DEFAULT_ACCURACY + 1.0,
+                "p{}: {} != {}",
+                q * 100.0,
+                estimate,
+                exact
+            );
+        }
+        assert_eq!(sketch.quantile(1.0), Some(10_000));
+    }
+
+    #[test]
+    fn zeros_and_small_values() {
+        let mut sketch = QuantileSketch::default();
+        for &value in &[0, 0, 0, 1, 2] {
+            sketch.insert(value);
+        }
+        assert_eq!(sketch.quantile(0.5), Some(0));
+        assert_eq!(sketch.quantile(0.8), Some(1));
+        assert_eq!(sketch.quantile(1.0), Some(2));
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use failure::{bail, format_err, Error};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::hll::{HyperLogLog, HASH_ID};
+use crate::metrics::{Registry, OVERFLOW_TAG};
+
+/// The state file is written at most this often while running
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+/// Bumped whenever the state file changes in an incompatible way
+const STATE_VERSION: u32 = 2;
+
+/// A period that unique values are counted over. Periods are aligned to the
+/// unix epoch, so `1h` starts at the full hour and `1d` is a calendar day in
+/// UTC.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolution {
+    name: String,
+    seconds: u64,
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    /// `<n>[s|m|h|d]`
+    fn from_str(s: &str) -> Result<Resolution, String> {
+        let invalid = || format!("invalid resolution: {:?}", s);
+        let unit = s.chars().last().ok_or_else(invalid)?;
+        let number = &s[..s.len() - unit.len_utf8()];
+        let multiplier = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        match number.parse::<u64>() {
+            Ok(n) if n > 0 => Ok(Resolution {
+                name: s.to_string(),
+                seconds: n.checked_mul(multiplier).ok_or_else(invalid)?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Resolution {
+    /// As it was configured, part of the metric name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn seconds(&self) -> u64 {
+        self.seconds
+    }
+}
+
+/// The values of one metric in the current period of its resolution
+struct Rollup {
+    key: String,
+    tags: HashMap<String, String>,
+    seconds: u64,
+    /// Number of the current period since the epoch
+    period: u64,
+    sketch: HyperLogLog,
+}
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    version: u32,
+    /// The hash the sketches were built with, see `hll::HASH_ID`
+    hash: String,
+    rollups: Vec<SavedRollup>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedRollup {
+    name: String,
+    key: String,
+    tags: HashMap<String, String>,
+    seconds: u64,
+    period: u64,
+    /// Hex encoded registers of the sketch
+    registers: String,
+}
+
+/// Unique values over periods that are longer than a window, like the number
+/// of devices today
+///
+/// Every metric is counted with a HyperLogLog sketch per resolution, which is
+/// reported as a gauge with the count so far in the current period. With a
+/// state file the sketches are checkpointed, and restored ones keep counting
+/// if their period hasn't ended yet.
+///
+/// Like the registry, once `max_rollups` metrics are counted new ones share
+/// one overflow sketch per key.
+#[derive(Default)]
+pub struct Rollups {
+    rollups: HashMap<String, Rollup>,
+    max_rollups: Option<usize>,
+    path: Option<PathBuf>,
+    saved: Option<Instant>,
+}
+
+impl Rollups {
+    /// Restores the sketches saved in `path`, a missing file starts empty
+    pub fn open(path: &str) -> Result<Rollups, Error> {
+        let mut rollups = Rollups::default();
+        rollups.path = Some(PathBuf::from(path));
+        let buf = match fs::read_to_string(path) {
+            Ok(buf) => buf,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(rollups),
+            Err(err) => return Err(err.into()),
+        };
+
+        let state: State = serde_json::from_str(&buf)?;
+        if state.version != STATE_VERSION {
+            bail!("unknown state version {}", state.version);
+        }
+        if state.hash != HASH_ID {
+            // the registers of the old sketches don't match values hashed now
+            eprintln!(
+                "Discarding state {:?} hashed with {:?}, counting starts over",
+                path, state.hash
+            );
+            return Ok(rollups);
+        }
+        for saved in state.rollups {
+            let sketch = decode(&saved.registers)
+                .and_then(HyperLogLog::from_registers)
+                .ok_or_else(|| format_err!("invalid sketch of {:?}", saved.name))?;
+            rollups.rollups.insert(
+                saved.name,
+                Rollup {
+                    key: saved.key,
+                    tags: saved.tags,
+                    seconds: saved.seconds,
+                    period: saved.period,
+                    sketch,
+                },
+            );
+        }
+        Ok(rollups)
+    }
+
+    /// Most metrics that are counted, usually the registry's `max_metrics`
+    pub fn set_max_rollups(&mut self, max: usize) {
+        self.max_rollups = Some(max);
+    }
+
+    /// Count `value` for the metric `name`. `describe` returns the key and the
+    /// tags of the metric, it's only called for new metrics.
+    pub fn add<F>(
+        &mut self,
+        name: String,
+        describe: F,
+        resolution: &Resolution,
+        error: f64,
+        ts: Duration,
+        value: &str,
+    ) where
+        F: FnOnce() -> (String, HashMap<String, String>),
+    {
+        let period = ts.as_secs() / resolution.seconds;
+        let precision = HyperLogLog::precision_for(error);
+        let new = |(key, tags)| Rollup {
+            key,
+            tags,
+            seconds: resolution.seconds,
+            period,
+            sketch: HyperLogLog::with_precision(precision),
+        };
+        let full = match self.max_rollups {
+            Some(max) => self.rollups.len() >= max && !self.rollups.contains_key(&name),
+            None => false,
+        };
+        let rollup = if full {
+            let (key, _) = describe();
+            let mut overflow = HashMap::new();
+            overflow.insert(OVERFLOW_TAG.to_string(), "true".to_string());
+            self.rollups
+                .entry(format!("{}.{}", OVERFLOW_TAG, key))
+                .or_insert_with(|| new((key, overflow)))
+        } else {
+            self.rollups.entry(name).or_insert_with(|| new(describe()))
+        };
+
+        if period < rollup.period {
+            // late for a period that was already reported
+            return;
+        }
+        if period > rollup.period {
+            rollup.sketch.clear();
+            rollup.period = period;
+        }
+        if rollup.sketch.precision() != precision {
+            // the error was changed since the sketch was saved
+            rollup.sketch = HyperLogLog::with_precision(precision);
+        }
+        rollup.sketch.insert(value);
+    }
+
+    /// Sets the gauges to the counts of the periods that `ts` is in. Metrics
+    /// that didn't see a value for a whole period are dropped.
+    pub fn report(&mut self, registry: &Registry, ts: Duration) {
+        self.rollups.retain(|name, rollup| {
+            let period = ts.as_secs() / rollup.seconds;
+            if period > rollup.period {
+                if rollup.sketch.is_empty() {
+                    return false;
+                }
+                rollup.sketch.clear();
+                rollup.period = period;
+            }
+
+            let tags = Some(rollup.tags.clone());
+            let gauge = registry.get_gauge(name.clone(), rollup.key.clone(), tags);
+            gauge.set(rollup.sketch.len() as u64);
+            true
+        });
+    }
+
+    /// Saves the state if the last checkpoint is old enough
+    pub fn checkpoint(&mut self) {
+        match self.saved {
+            Some(saved) if saved.elapsed() < CHECKPOINT_INTERVAL => {}
+            _ => self.save(),
+        }
+    }
+
+    fn save(&mut self) {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return,
+        };
+        self.saved = Some(Instant::now());
+
+        let state = State {
+            version: STATE_VERSION,
+            hash: HASH_ID.to_string(),
+            rollups: self
+                .rollups
+                .iter()
+                .map(|(name, rollup)| SavedRollup {
+                    name: name.clone(),
+                    key: rollup.key.clone(),
+                    tags: rollup.tags.clone(),
+                    seconds: rollup.seconds,
+                    period: rollup.period,
+                    registers: encode(rollup.sketch.registers()),
+                })
+                .collect(),
+        };
+
+        // written next to the old state and renamed, so a crash leaves one of both
+        let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+        let result = serde_json::to_vec(&state)
+            .map_err(io::Error::from)
+            .and_then(|buf| fs::write(&tmp, buf))
+            .and_then(|_| fs::rename(&tmp, &path));
+        if let Err(err) = result {
+            eprintln!("Failed to save state {:?}: {}", path, err);
+        }
+    }
+}
+
+impl Drop for Rollups {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+fn encode(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::Recorder;
+
+    use std::sync::{Arc, Mutex};
+
+    fn gauges(rollups: &mut Rollups, ts: Duration) -> Vec<String> {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let registry = Registry::from_sink("", Recorder(lines.clone()));
+        rollups.report(&registry, ts);
+        registry.send();
+        let mut lines = lines.lock().unwrap().clone();
+        lines.sort();
+        lines
+    }
+
+    fn add(rollups: &mut Rollups, resolution: &str, ts: Duration, value: &str) {
+        let resolution: Resolution = resolution.parse().unwrap();
+        let key = format!("ips_per_{}", resolution.name());
+        rollups.add(
+            key.clone(),
+            || (key, HashMap::new()),
+            &resolution,
+            0.01,
+            ts,
+            value,
+        );
+    }
+
+    // 2019-04-23T06:13:20Z
+    const TS: Duration = Duration::from_secs(1_556_000_000);
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn parse_resolution() {
+        let resolution: Resolution = "1d".parse().unwrap();
+        assert_eq!(resolution.name(), "1d");
+        assert_eq!(resolution.seconds(), 86_400);
+        assert_eq!("90s".parse::<Resolution>().unwrap().seconds(), 90);
+        assert_eq!("2h".parse::<Resolution>().unwrap().seconds(), 7200);
+        assert!("0m".parse::<Resolution>().is_err());
+        assert!("1w".parse::<Resolution>().is_err());
+        assert!("h".parse::<Resolution>().is_err());
+        assert!("".parse::<Resolution>().is_err());
+        assert!("1é".parse::<Resolution>().is_err());
+        assert!("999999999999999999d".parse::<Resolution>().is_err());
+    }
+
+    #[test]
+    fn periods() {
+        let mut rollups = Rollups::default();
+        for ip in &["1.1.1.1", "2.2.2.2", "1.1.1.1"] {
+            add(&mut rollups, "1h", TS, ip);
+            add(&mut rollups, "1d", TS, ip);
+        }
+        add(&mut rollups, "1h", TS + HOUR, "3.3.3.3");
+        add(&mut rollups, "1d", TS + HOUR, "3.3.3.3");
+        // too late for the hour that was counted already
+        add(&mut rollups, "1h", TS, "4.4.4.4");
+        assert_eq!(
+            gauges(&mut rollups, TS + HOUR),
+            vec!["ips_per_1d:3|g", "ips_per_1h:1|g"]
+        );
+
+        // the next hour is empty, the day goes on until midnight
+        assert_eq!(
+            gauges(&mut rollups, TS + HOUR * 2),
+            vec!["ips_per_1d:3|g", "ips_per_1h:0|g"]
+        );
+        assert_eq!(gauges(&mut rollups, TS + HOUR * 3), vec!["ips_per_1d:3|g"]);
+        assert_eq!(gauges(&mut rollups, TS + HOUR * 18), vec!["ips_per_1d:0|g"]);
+        assert!(gauges(&mut rollups, TS + HOUR * 42).is_empty());
+        assert!(rollups.rollups.is_empty());
+    }
+
+    #[test]
+    fn restore() {
+        let path = std::env::temp_dir().join(format!("httpsniffer-state-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let path = path.to_str().unwrap().to_string();
+
+        let mut rollups = Rollups::open(&path).unwrap();
+        add(&mut rollups, "1d", TS, "1.1.1.1");
+        add(&mut rollups, "1d", TS, "2.2.2.2");
+        drop(rollups);
+
+        // counting goes on where it stopped
+        let mut rollups = Rollups::open(&path).unwrap();
+        add(&mut rollups, "1d", TS + HOUR, "2.2.2.2");
+        add(&mut rollups, "1d", TS + HOUR, "3.3.3.3");
+        assert_eq!(gauges(&mut rollups, TS + HOUR), vec!["ips_per_1d:3|g"]);
+        drop(rollups);
+
+        // a day later the saved counts are gone
+        let mut rollups = Rollups::open(&path).unwrap();
+        add(&mut rollups, "1d", TS + HOUR * 24, "4.4.4.4");
+        assert_eq!(gauges(&mut rollups, TS + HOUR * 24), vec!["ips_per_1d:1|g"]);
+        drop(rollups);
+
+        // sketches of another hash are dropped
+        let state = fs::read_to_string(&path).unwrap().replace(HASH_ID, "sip");
+        fs::write(&path, state).unwrap();
+        assert!(Rollups::open(&path).unwrap().rollups.is_empty());
+
+        fs::write(&path, "{}").unwrap();
+        assert!(Rollups::open(&path).is_err());
+        fs::write(&path, format!(r#"{{"version":2,"hash":"{}","rollups":[{{"name":"a","key":"a","tags":{{}},"seconds":60,"period":1,"registers":"00"}}]}}"#, HASH_ID)).unwrap();
+        assert!(Rollups::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn overflow() {
+        let mut rollups = Rollups::default();
+        rollups.set_max_rollups(2);
+        let resolution: Resolution = "1d".parse().unwrap();
+        for host in &["a", "b", "c", "d"] {
+            for ip in &["1.1.1.1", host] {
+                rollups.add(
+                    format!("{}.ips_per_1d", host),
+                    || {
+                        let mut tags = HashMap::new();
+                        tags.insert("host".to_string(), host.to_string());
+                        ("ips_per_1d".to_string(), tags)
+                    },
+                    &resolution,
+                    0.01,
+                    TS,
+                    ip,
+                );
+            }
+        }
+        assert_eq!(
+            gauges(&mut rollups, TS),
+            vec![
+                "ips_per_1d:2|g|#host:a",
+                "ips_per_1d:2|g|#host:b",
+                "ips_per_1d:3|g|#overflow:true"
+            ]
+        );
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(encode(&[0, 15, 255]), "000fff");
+        assert_eq!(decode("000fff"), Some(vec![0, 15, 255]));
+        assert_eq!(decode("0"), None);
+        assert_eq!(decode("zz"), None);
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use failure::{bail, format_err, Error};
+use regex::Regex;
+use uuid::Uuid;
+
+use crate::config::RouteConfig;
+
+/// Route reported once a host has too many distinct routes
+pub const OVERFLOW_ROUTE: &str = "other";
+
+const METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    regex: Regex,
+}
+
+/// Maps request uris to a bounded set of route names per host
+#[derive(Debug)]
+pub struct Router {
+    rules: Vec<Rule>,
+    max_routes: usize,
+    /// Routes of every host, with the number of windows they weren't seen in
+    seen: HashMap<String, HashMap<String, usize>>,
+}
+
+impl Rule {
+    fn from_config(config: &RouteConfig) -> Result<Rule, Error> {
+        match (&config.pattern, &config.regex) {
+            (Some(pattern), None) => Ok(Rule {
+                name: config.name.clone().unwrap_or_else(|| pattern.clone()),
+                regex: template(pattern)?,
+            }),
+            (None, Some(regex)) => {
+                let name = config
+                    .name
+                    .clone()
+                    .ok_or_else(|| format_err!("regex route {:?} requires a name", regex))?;
+                Ok(Rule {
+                    name,
+                    regex: Regex::new(regex)?,
+                })
+            }
+            _ => bail!("route requires either a pattern or a regex"),
+        }
+    }
+}
+
+impl Router {
+    pub fn new(configs: &[RouteConfig], max_routes: usize) -> Result<Router, Error> {
+        let rules = configs
+            .iter()
+            .map(Rule::from_config)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Router {
+            rules,
+            max_routes,
+            seen: HashMap::new(),
+        })
+    }
+
+    /// The route name of `uri`, or `other` if `host` already has the maximum
+    /// number of routes
+    pub fn route(&mut self, host: &str, uri: &str) -> String {
+        let path = path(uri);
+        let route = match self.rules.iter().find(|rule| rule.regex.is_match(path)) {
+            Some(rule) => rule.name.clone(),
+            None => collapse(path),
+        };
+
+        if let Some(routes) = self.seen.get_mut(host) {
+            if let Some(idle) = routes.get_mut(&route) {
+                *idle = 0;
+                return route;
+            }
+        }
+        let routes = self.seen.entry(host.to_string()).or_default();
+        if routes.len() >= self.max_routes {
+            return OVERFLOW_ROUTE.to_string();
+        }
+        routes.insert(route.clone(), 0);
+        route
+    }
+
+    /// Forget routes that weren't seen for `idle_windows` windows, like the
+    /// registry drops their metrics, so they don't count against the cap
+    /// forever. 0 keeps them.
+    pub fn end_window(&mut self, idle_windows: usize) {
+        if idle_windows == 0 {
+            return;
+        }
+        for routes in self.seen.values_mut() {
+            routes.retain(|_, idle| {
+                *idle += 1;
+                *idle < idle_windows
+            });
+        }
+        self.seen.retain(|_, routes| !routes.is_empty());
+    }
+}
+
+/// Unknown methods are reported as `OTHER`
+pub fn method(method: &str) -> &str {
+    match METHODS.iter().find(|m| m.eq_ignore_ascii_case(method)) {
+        Some(m) => m,
+        None => "OTHER",
+    }
+}
+
+/// Compile `/recipe/{id}/comments` into a regex, every `{..}` matches one segment
+fn template(pattern: &str) -> Result<Regex, Error> {
+    let mut regex = String::from("^");
+    for (i, segment) in pattern.split('/').enumerate() {
+        if i > 0 {
+            regex.push('/');
+        }
+        if segment.starts_with('{') && segment.ends_with('}') {
+            regex.push_str("[^/]+");
+        } else {
+            regex.push_str(&regex::escape(segment));
+        }
+    }
+    regex.push_str("/?$");
+    Ok(Regex::new(&regex)?)
+}
+
+/// The path of an origin-form or absolute-form request target
+fn path(uri: &str) -> &str {
+    let uri = match uri.find("://") {
+        Some(pos) => {
+            let rest = &uri[pos + 3..];
+            rest.find('/').map(|pos| &rest[pos..]).unwrap_or("/")
+        }
+        None => uri,
+    };
+    let end = uri.find(&['?', '#'][..]).unwrap_or(uri.len());
+    &uri[..end]
+}
+
+/// Replace numeric and uuid segments so ids don't become separate routes
+fn collapse(path: &str) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+
+    let segments: Vec<&str> = path
+        .split('/')
+        .map(|segment| {
+            if !segment.is_empty() && segment.bytes().all(|c| c.is_ascii_digit()) {
+                "{id}"
+            } else if (segment.len() == 32 || segment.len() == 36)
+                && Uuid::parse_str(segment).is_ok()
+            {
+                "{uuid}"
+            } else {
+                segment
+            }
+        })
+        .collect();
+    segments.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: Option<&str>, regex: Option<&str>, name: Option<&str>) -> RouteConfig {
+        RouteConfig {
+            pattern: pattern.map(String::from),
+            regex: regex.map(String::from),
+            name: name.map(String::from),
+        }
+    }
+
+    #[test]
+    fn rules() {
+        let mut router = Router::new(
+            &[
+                rule(Some("/recipe/{id}/comments"), None, None),
+                rule(None, Some("^/api/v[0-9]+/users"), Some("/api/users")),
+            ],
+            100,
+        )
+        .unwrap();
+
+        assert_eq!(
+            router.route("h", "/recipe/abc/comments?page=2"),
+            "/recipe/{id}/comments"
+        );
+        assert_eq!(
+            router.route("h", "/recipe/abc/comments/"),
+            "/recipe/{id}/comments"
+        );
+        assert_eq!(router.route("h", "/api/v2/users/1"), "/api/users");
+        assert_eq!(
+            router.route("h", "http://example.com/recipe/1/comments"),
+            "/recipe/{id}/comments"
+        );
+    }
+
+    #[test]
+    fn fallback() {
+        let mut router = Router::new(&[], 100).unwrap();
+        assert_eq!(router.route("h", "/"), "/");
+        assert_eq!(router.route("h", "*"), "*");
+        assert_eq!(router.route("h", "http://example.com"), "/");
+        assert_eq!(router.route("h", "/recipe/123/"), "/recipe/{id}/");
+        assert_eq!(
+            router.route("h", "/user/0e0e4c0d-6b1b-4c1b-9b1d-6b1b4c1b9b1d/likes#x"),
+            "/user/{uuid}/likes"
+        );
+        assert_eq!(router.route("h", "/v2/x1"), "/v2/x1");
+    }
+
+    #[test]
+    fn cap_per_host() {
+        let mut router = Router::new(&[], 2).unwrap();
+        assert_eq!(router.route("a", "/one"), "/one");
+        assert_eq!(router.route("a", "/two"), "/two");
+        assert_eq!(router.route("a", "/three"), OVERFLOW_ROUTE);
+        assert_eq!(router.route("a", "/one"), "/one");
+        assert_eq!(router.route("b", "/three"), "/three");
+
+        // only /one was seen in the last window
+        router.end_window(2);
+        router.route("a", "/one");
+        router.end_window(2);
+        assert_eq!(router.route("a", "/three"), "/three");
+        assert_eq!(router.route("a", "/two"), OVERFLOW_ROUTE);
+        router.end_window(0);
+        assert_eq!(router.seen.len(), 1);
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert!(Router::new(&[rule(None, None, None)], 1).is_err());
+        assert!(Router::new(&[rule(Some("/a"), Some("^/a"), None)], 1).is_err());
+        assert!(Router::new(&[rule(None, Some("^/a"), None)], 1).is_err());
+        assert!(Router::new(&[rule(None, Some("("), Some("a"))], 1).is_err());
+    }
+
+    #[test]
+    fn methods() {
+        assert_eq!(method("get"), "GET");
+        assert_eq!(method("PATCH"), "PATCH");
+        assert_eq!(method("BREW"), "OTHER");
+    }
+}
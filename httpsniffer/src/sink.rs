@@ -0,0 +1,685 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::net::UdpSocket;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::metrics::{sorted, Exporter, QUANTILES};
+use crate::quantile::QuantileSketch;
+use crate::topk::HeavyHitter;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// A tcp sink that couldn't connect drops lines for this long before trying again
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Transport for rendered metric lines
+pub trait MetricSink: Send + Sync {
+    /// Send one line, without a trailing newline
+    fn emit(&self, line: &str) -> io::Result<()>;
+    /// Called after every window, buffered lines should be written now
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One datagram per line
+pub struct UdpSink {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+impl UdpSink {
+    pub fn new(addr: impl ToSocketAddrs) -> io::Result<UdpSink> {
+        let addr = resolve(addr)?;
+        let bind = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        Ok(UdpSink {
+            socket: UdpSocket::bind(bind)?,
+            addr,
+        })
+    }
+}
+
+impl MetricSink for UdpSink {
+    fn emit(&self, line: &str) -> io::Result<()> {
+        self.socket.send_to(line.as_bytes(), self.addr).map(|_| ())
+    }
+}
+
+/// Newline terminated lines over one connection, which is opened again after
+/// it broke
+pub struct TcpSink {
+    addr: SocketAddr,
+    conn: Mutex<Connection>,
+}
+
+struct Connection {
+    stream: Option<BufWriter<TcpStream>>,
+    retry: Option<Instant>,
+}
+
+impl TcpSink {
+    pub fn new(addr: impl ToSocketAddrs) -> io::Result<TcpSink> {
+        Ok(TcpSink {
+            addr: resolve(addr)?,
+            conn: Mutex::new(Connection {
+                stream: None,
+                retry: None,
+            }),
+        })
+    }
+
+    fn with_stream<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut BufWriter<TcpStream>) -> io::Result<()>,
+    {
+        let mut conn = self.conn.lock().expect("lock tcp sink");
+        if conn.stream.is_none() {
+            if let Some(retry) = conn.retry {
+                if Instant::now() < retry {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "reconnect pending",
+                    ));
+                }
+            }
+            match TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    conn.stream = Some(BufWriter::new(stream));
+                    conn.retry = None;
+                }
+                Err(err) => {
+                    conn.retry = Some(Instant::now() + RECONNECT_DELAY);
+                    return Err(err);
+                }
+            }
+        }
+
+        let result = f(conn.stream.as_mut().expect("connected"));
+        if result.is_err() {
+            conn.stream = None;
+        }
+        result
+    }
+}
+
+impl MetricSink for TcpSink {
+    fn emit(&self, line: &str) -> io::Result<()> {
+        self.with_stream(|stream| writeln!(stream, "{}", line))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if self.conn.lock().expect("lock tcp sink").stream.is_none() {
+            return Ok(());
+        }
+        self.with_stream(|stream| stream.flush())
+    }
+}
+
+/// Lines appended to a file, or stdout
+pub struct FileSink {
+    out: Mutex<BufWriter<Box<dyn Write + Send>>>,
+}
+
+impl FileSink {
+    /// `-` writes to stdout, everything else is a file that is appended to
+    pub fn open(path: &str) -> io::Result<FileSink> {
+        let out: Box<dyn Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        Ok(FileSink {
+            out: Mutex::new(BufWriter::new(out)),
+        })
+    }
+}
+
+impl MetricSink for FileSink {
+    fn emit(&self, line: &str) -> io::Result<()> {
+        writeln!(self.out.lock().expect("lock file sink"), "{}", line)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.out.lock().expect("lock file sink").flush()
+    }
+}
+
+fn resolve(addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))
+}
+
+/// How metrics are rendered into lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// `name:value|type|#tag:value`
+    DogStatsd,
+    /// Graphite style `name.tag.value:value|type`, for servers without tags
+    Statsd,
+    /// InfluxDB line protocol with integer fields
+    Influx,
+    /// One json object per metric
+    Json,
+}
+
+/// A sink from the command line, like `influx+tcp://127.0.0.1:8094`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SinkSpec {
+    format: Format,
+    transport: Transport,
+    target: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+    File,
+}
+
+impl SinkSpec {
+    /// Dogstatsd over udp, what `--statsd_host` sends
+    pub fn dogstatsd(host: impl Into<String>) -> SinkSpec {
+        SinkSpec {
+            format: Format::DogStatsd,
+            transport: Transport::Udp,
+            target: host.into(),
+        }
+    }
+
+    /// Connect or open the sink, names start with `prefix`
+    pub fn open(&self, prefix: &str) -> io::Result<Output> {
+        let sink: Box<dyn MetricSink> = match self.transport {
+            Transport::Udp => Box::new(UdpSink::new(self.target.as_str())?),
+            Transport::Tcp => Box::new(TcpSink::new(self.target.as_str())?),
+            Transport::File => Box::new(FileSink::open(&self.target)?),
+        };
+        Ok(Output::new(self.format, prefix, sink))
+    }
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    /// `<scheme>://<host:port>`, or `json://<path>`
+    fn from_str(s: &str) -> Result<SinkSpec, String> {
+        let mut parts = s.splitn(2, "://");
+        let (scheme, target) = match (parts.next(), parts.next()) {
+            (Some(scheme), Some(target)) if !target.is_empty() => (scheme, target),
+            _ => return Err(format!("invalid sink: {:?}", s)),
+        };
+        let (format, transport) = match scheme {
+            "dogstatsd" => (Format::DogStatsd, Transport::Udp),
+            "statsd" => (Format::Statsd, Transport::Udp),
+            "influx" | "influx+udp" => (Format::Influx, Transport::Udp),
+            "influx+tcp" => (Format::Influx, Transport::Tcp),
+            "json" => (Format::Json, Transport::File),
+            _ => return Err(format!("unknown sink scheme: {:?}", scheme)),
+        };
+        Ok(SinkSpec {
+            format,
+            transport,
+            target: target.to_string(),
+        })
+    }
+}
+
+/// Renders every metric of a window in one format and writes it to a sink
+pub struct Output {
+    format: Format,
+    prefix: String,
+    sink: Box<dyn MetricSink>,
+    /// End of the window that is being flushed
+    window: Mutex<Option<Duration>>,
+}
+
+enum Value {
+    Count(u64),
+    Gauge(u64),
+}
+
+impl Output {
+    pub fn new(format: Format, prefix: &str, sink: Box<dyn MetricSink>) -> Output {
+        Output {
+            format,
+            prefix: prefix.to_string(),
+            sink,
+            window: Mutex::new(None),
+        }
+    }
+
+    /// The window end in formats that carry a timestamp, the current time if
+    /// nothing was flushed yet
+    fn timestamp(&self) -> Duration {
+        match *self.window.lock().expect("lock window") {
+            Some(window) => window,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        }
+    }
+
+    fn name(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.prefix, key)
+        }
+    }
+
+    fn emit(&self, line: &str) {
+        if let Err(err) = self.sink.emit(line) {
+            eprintln!("send error: {:?}", err);
+        }
+    }
+
+    /// Send a single value, `suffix` is appended to the name after any tags
+    /// that are folded into it
+    fn single(&self, key: &str, suffix: &str, value: Value, tags: &HashMap<String, String>) {
+        let mut name = self.name(key);
+        let (kind, value) = match value {
+            Value::Count(value) => ("c", value),
+            Value::Gauge(value) => ("g", value),
+        };
+
+        match self.format {
+            Format::DogStatsd => {
+                name.push_str(suffix);
+                let mut line = format!("{}:{}|{}", name, value, kind);
+                let tags: Vec<_> = sorted(tags)
+                    .into_iter()
+                    .map(|(k, v)| format!("{}:{}", dogstatsd(k), dogstatsd(v)))
+                    .collect();
+                if !tags.is_empty() {
+                    let _ = write!(line, "|#{}", tags.join(","));
+                }
+                self.emit(&line);
+            }
+            Format::Statsd => {
+                for (k, v) in sorted(tags) {
+                    let _ = write!(name, ".{}.{}", graphite(k), graphite(v));
+                }
+                name.push_str(suffix);
+                self.emit(&format!("{}:{}|{}", name, value, kind));
+            }
+            Format::Influx | Format::Json => {
+                let field = match kind {
+                    "c" => "count",
+                    _ => "value",
+                };
+                let key = format!("{}{}", key, suffix);
+                self.fields(&key, &[(field, value)], tags);
+            }
+        }
+    }
+
+    /// Send several values of one metric, in one line where the format allows
+    fn fields(&self, key: &str, fields: &[(&str, u64)], tags: &HashMap<String, String>) {
+        match self.format {
+            Format::DogStatsd | Format::Statsd => {
+                for &(field, value) in fields {
+                    let value = match field {
+                        "count" => Value::Count(value),
+                        _ => Value::Gauge(value),
+                    };
+                    self.single(key, &format!(".{}", field), value, tags);
+                }
+            }
+            Format::Influx => {
+                let mut line = escape(&self.name(key), ", ");
+                for (k, v) in sorted(tags) {
+                    let _ = write!(line, ",{}={}", escape(k, ",= "), escape(v, ",= "));
+                }
+                for (i, (field, value)) in fields.iter().enumerate() {
+                    let sep = if i == 0 { ' ' } else { ',' };
+                    let _ = write!(line, "{}{}={}i", sep, escape(field, ",= "), value);
+                }
+                let _ = write!(line, " {}", self.timestamp().as_nanos());
+                self.emit(&line);
+            }
+            Format::Json => {
+                let tags: BTreeMap<_, _> = tags.iter().collect();
+                let value = match fields {
+                    [(_, value)] => json!(value),
+                    _ => json!(fields.iter().cloned().collect::<BTreeMap<_, _>>()),
+                };
+                let kind = match fields {
+                    [("count", _)] => "counter",
+                    [_] => "gauge",
+                    _ => "summary",
+                };
+                let line = json!({
+                    "timestamp": self.timestamp().as_secs(),
+                    "metric": self.name(key),
+                    "type": kind,
+                    "value": value,
+                    "tags": tags,
+                });
+                self.emit(&line.to_string());
+            }
+        }
+    }
+}
+
+impl Exporter for Output {
+    fn start(&self, timestamp: Duration) {
+        *self.window.lock().expect("lock window") = Some(timestamp);
+    }
+
+    fn counter(&self, key: &str, value: usize, tags: &HashMap<String, String>) {
+        self.single(key, "", Value::Count(value as u64), tags);
+    }
+
+    fn cardinality(&self, key: &str, value: usize, tags: &HashMap<String, String>) {
+        self.single(key, "", Value::Count(value as u64), tags);
+    }
+
+    fn gauge(&self, key: &str, value: u64, tags: &HashMap<String, String>) {
+        self.single(key, "", Value::Gauge(value), tags);
+    }
+
+    /// Quantiles are `{key}.p50` etc gauges plus a `{key}.count` counter in
+    /// statsd, and fields of one line otherwise
+    fn histogram(&self, key: &str, sketch: &QuantileSketch, tags: &HashMap<String, String>) {
+        let mut fields = Vec::new();
+        for &(q, suffix) in QUANTILES {
+            if let Some(value) = sketch.quantile(q) {
+                fields.push((suffix, value));
+            }
+        }
+        fields.push(("count", sketch.count()));
+        self.fields(key, &fields, tags);
+    }
+
+    /// One gauge per value, the value itself is the `value` tag
+    fn topk(&self, key: &str, top: &[HeavyHitter], tags: &HashMap<String, String>) {
+        for hitter in top {
+            let mut tags = tags.clone();
+            tags.insert("value".to_string(), hitter.value.clone());
+            self.single(key, "", Value::Gauge(hitter.count), &tags);
+        }
+    }
+
+    fn flush(&self) {
+        if let Err(err) = self.sink.flush() {
+            eprintln!("send error: {:?}", err);
+        }
+    }
+}
+
+/// A tag as one part of a dotted graphite name
+fn graphite(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// A tag without the characters that separate the parts of a dogstatsd line,
+/// routes and top values come straight from the request
+fn dogstatsd(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            ',' | '|' | '#' | ':' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Backslash escape `special` characters for the influx line protocol
+fn escape(s: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::Recorder;
+
+    use std::fs;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    fn render(format: Format, f: impl FnOnce(&Output)) -> Vec<String> {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let output = Output::new(format, "nginx", Box::new(Recorder(lines.clone())));
+        f(&output);
+        let lines = lines.lock().unwrap().clone();
+        lines
+    }
+
+    fn tags() -> HashMap<String, String> {
+        let mut tags = HashMap::new();
+        tags.insert("port".to_string(), "80".to_string());
+        tags.insert("host".to_string(), "a.example.com".to_string());
+        tags
+    }
+
+    #[test]
+    fn parse_spec() {
+        assert_eq!(
+            "influx+tcp://127.0.0.1:8094".parse(),
+            Ok(SinkSpec {
+                format: Format::Influx,
+                transport: Transport::Tcp,
+                target: "127.0.0.1:8094".to_string(),
+            })
+        );
+        assert_eq!(
+            "statsd://127.0.0.1:8125"
+                .parse::<SinkSpec>()
+                .unwrap()
+                .format,
+            Format::Statsd
+        );
+        assert_eq!(
+            "json://-".parse::<SinkSpec>().unwrap().transport,
+            Transport::File
+        );
+        assert!("json://".parse::<SinkSpec>().is_err());
+        assert!("127.0.0.1:8125".parse::<SinkSpec>().is_err());
+        assert!("carbon://127.0.0.1:2003".parse::<SinkSpec>().is_err());
+    }
+
+    #[test]
+    fn render_statsd() {
+        let lines = render(Format::DogStatsd, |output| {
+            output.counter("requests", 3, &tags());
+        });
+        assert_eq!(
+            lines,
+            vec!["nginx.requests:3|c|#host:a.example.com,port:80"]
+        );
+
+        // a route like this must not end the tags or add a metric
+        let mut forged = HashMap::new();
+        forged.insert("route".to_string(), "/a,b:c|d#e\nf:1|c".to_string());
+        let lines = render(Format::DogStatsd, |output| {
+            output.counter("requests", 1, &forged);
+        });
+        assert_eq!(lines, vec!["nginx.requests:1|c|#route:/a_b_c_d_e_f_1_c"]);
+
+        let lines = render(Format::Statsd, |output| {
+            output.counter("requests", 3, &tags());
+            output.gauge("queue", 1, &HashMap::new());
+        });
+        assert_eq!(
+            lines,
+            vec![
+                "nginx.requests.host.a_example_com.port.80:3|c",
+                "nginx.queue:1|g",
+            ]
+        );
+    }
+
+    #[test]
+    fn render_histogram() {
+        let mut sketch = QuantileSketch::default();
+        for value in 1..=100 {
+            sketch.insert(value);
+        }
+        let lines = render(Format::Statsd, |output| {
+            output.histogram("size", &sketch, &HashMap::new())
+        });
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("nginx.size.p50:"));
+        assert_eq!(lines[3], "nginx.size.count:100|c");
+
+        let lines = render(Format::Influx, |output| {
+            output.histogram("size", &sketch, &tags())
+        });
+        assert_eq!(lines.len(), 1);
+        assert!(
+            lines[0].starts_with("nginx.size,host=a.example.com,port=80 p50="),
+            "{}",
+            lines[0]
+        );
+        assert!(lines[0].contains(",count=100i "), "{}", lines[0]);
+    }
+
+    #[test]
+    fn render_influx() {
+        let mut tags = tags();
+        tags.insert("path".to_string(), "/a b,c=d".to_string());
+        let lines = render(Format::Influx, |output| {
+            output.counter("requests", 3, &tags);
+        });
+        let parts: Vec<_> = lines[0].rsplitn(2, ' ').collect();
+        assert_eq!(
+            parts[1],
+            r"nginx.requests,host=a.example.com,path=/a\ b\,c\=d,port=80 count=3i"
+        );
+        assert!(parts[0].parse::<u64>().is_ok());
+
+        let lines = render(Format::Influx, |output| {
+            output.start(Duration::from_secs(1_500_000_010));
+            output.counter("requests", 3, &HashMap::new());
+        });
+        assert_eq!(lines, ["nginx.requests count=3i 1500000010000000000"]);
+    }
+
+    #[test]
+    fn render_json() {
+        let lines = render(Format::Json, |output| {
+            output.start(Duration::from_secs(1_500_000_010));
+            output.gauge("queue", 1, &tags());
+            output.topk(
+                "top",
+                &[HeavyHitter {
+                    value: "/".to_string(),
+                    count: 9,
+                    error: 0,
+                }],
+                &HashMap::new(),
+            );
+        });
+        let queue: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(queue["metric"], "nginx.queue");
+        assert_eq!(queue["type"], "gauge");
+        assert_eq!(queue["value"], 1);
+        assert_eq!(queue["tags"]["host"], "a.example.com");
+        assert_eq!(queue["timestamp"], 1_500_000_010);
+        let top: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(top["value"], 9);
+        assert_eq!(top["tags"]["value"], "/");
+    }
+
+    #[test]
+    fn udp_sink() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let spec: SinkSpec = format!("statsd://{}", server.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let output = spec.open("nginx").unwrap();
+        output.counter("requests", 2, &HashMap::new());
+        output.flush();
+
+        let mut buf = [0; 512];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &b"nginx.requests:2|c"[..]);
+    }
+
+    #[test]
+    fn tcp_sink_reconnects() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let spec: SinkSpec = format!("influx+tcp://{}", server.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let output = spec.open("").unwrap();
+        output.gauge("queue", 1, &HashMap::new());
+        output.flush();
+
+        let (conn, _) = server.accept().unwrap();
+        let mut reader = BufReader::new(conn);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("queue value=1i "), "{:?}", line);
+        drop(reader);
+
+        // writes into the closed connection fail until it was opened again
+        for _ in 0..10 {
+            output.gauge("queue", 2, &HashMap::new());
+            output.flush();
+            if let Ok(conn) = server.set_nonblocking(true).and_then(|_| server.accept()) {
+                conn.0.set_nonblocking(false).unwrap();
+                let mut line = String::new();
+                BufReader::new(conn.0).read_line(&mut line).unwrap();
+                assert!(line.starts_with("queue value=2i "), "{:?}", line);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("sink didn't reconnect");
+    }
+
+    #[test]
+    fn json_sink() {
+        let path =
+            std::env::temp_dir().join(format!("httpsniffer-sink-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let spec: SinkSpec = format!("json://{}", path.display()).parse().unwrap();
+
+        let output = spec.open("nginx").unwrap();
+        output.counter("requests", 2, &tags());
+        output.flush();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["type"], "counter");
+        assert_eq!(line["value"], 2);
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use threadpool::ThreadPool;
+
+use sniffglue::structs::CentrifugeError;
+
+use crate::metrics::Registry;
+
+/// Metrics about the sniffer itself are reported below this key
+pub const PREFIX: &str = "httpsniffer.self";
+
+const ERROR_KINDS: [&str; 4] = [
+    "wrong_protocol",
+    "parsing_error",
+    "unknown_protocol",
+    "invalid_packet",
+];
+
+const GEOIP_DATABASES: [&str; 2] = ["country", "asn"];
+
+/// Counters that are updated from the capture threads and the pool, and
+/// moved into the registry right before every flush
+#[derive(Debug, Default)]
+pub struct Telemetry {
+    packets: AtomicUsize,
+    shed: AtomicUsize,
+    segments: AtomicUsize,
+    segment_errors: [AtomicUsize; 4],
+    http_errors: AtomicUsize,
+    streams_evicted: AtomicUsize,
+    exchanges_evicted: AtomicUsize,
+    geoip_misses: [AtomicUsize; 2],
+    queued: AtomicUsize,
+}
+
+impl Telemetry {
+    pub fn new() -> Telemetry {
+        Telemetry::default()
+    }
+
+    /// A packet was read from pcap
+    pub fn packet(&self) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A packet was dropped because the decoding queue was full
+    pub fn shed(&self) {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The result of decoding the headers of a packet
+    pub fn segment<T>(&self, result: &Result<T, CentrifugeError>) {
+        let counter = match result {
+            Ok(_) => &self.segments,
+            Err(CentrifugeError::WrongProtocol) => &self.segment_errors[0],
+            Err(CentrifugeError::ParsingError) => &self.segment_errors[1],
+            Err(CentrifugeError::UnknownProtocol) => &self.segment_errors[2],
+            Err(CentrifugeError::InvalidPacket) => &self.segment_errors[3],
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A stream was discarded because it couldn't be parsed as http
+    pub fn http_errors(&self, n: usize) {
+        if n > 0 {
+            self.http_errors.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    /// Streams were dropped because too many were tracked
+    pub fn streams_evicted(&self, n: usize) {
+        if n > 0 {
+            self.streams_evicted.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    /// Connections waiting for responses were given up on because too many
+    /// were waiting
+    pub fn exchanges_evicted(&self, n: usize) {
+        if n > 0 {
+            self.exchanges_evicted.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    /// A client address wasn't found in a geoip `database`
+    pub fn geoip_miss(&self, database: &str) {
+        if let Some(i) = GEOIP_DATABASES.iter().position(|db| *db == database) {
+            self.geoip_misses[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A segment was sent to the pipeline
+    pub fn enqueue(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A segment was taken from the pipeline channel
+    pub fn dequeue(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Move the counters into `registry`. Without a `pool` the packets are
+    /// decoded inline and no queue is reported.
+    pub fn report(&self, registry: &Registry, pool: Option<&ThreadPool>) {
+        let count = |name: &str, counter: &AtomicUsize, tag: Option<(&str, &str)>| {
+            let key = format!("{}.{}", PREFIX, name);
+            let value = counter.swap(0, Ordering::Relaxed);
+            match tag {
+                Some((name, tag)) => {
+                    let mut tags = HashMap::new();
+                    tags.insert(name.to_string(), tag.to_string());
+                    registry.get_counter(format!("{}.{}", key, tag), key, Some(tags))
+                }
+                None => registry.get_counter(key.clone(), key, None),
+            }
+            .add(value);
+        };
+
+        count("packets", &self.packets, None);
+        count("packets_shed", &self.shed, None);
+        count("segments", &self.segments, None);
+        for (kind, counter) in ERROR_KINDS.iter().zip(&self.segment_errors) {
+            count("segment_errors", counter, Some(("kind", kind)));
+        }
+        count("http_errors", &self.http_errors, None);
+        count("streams_evicted", &self.streams_evicted, None);
+        count("exchanges_evicted", &self.exchanges_evicted, None);
+        for (database, counter) in GEOIP_DATABASES.iter().zip(&self.geoip_misses) {
+            count("geoip_misses", counter, Some(("database", database)));
+        }
+
+        if let Some(pool) = pool {
+            let gauge = |name: &str, value: usize| {
+                let key = format!("{}.{}", PREFIX, name);
+                registry.get_gauge(key.clone(), key, None).set(value as u64);
+            };
+            gauge("channel_queue", self.queued.load(Ordering::Relaxed));
+            gauge("pool_queue", pool.queued_count());
+            gauge("pool_active", pool.active_count());
+        }
+    }
+}
+
+/// Cumulative pcap counters of a live device, reported as gauges
+pub fn pcap_stats(registry: &Registry, iface: &str, stats: &pcap::Stat) {
+    let gauge = |name: &str, value: u32| {
+        let key = format!("{}.{}", PREFIX, name);
+        let mut tags = HashMap::new();
+        tags.insert("iface".to_string(), iface.to_string());
+        registry
+            .get_gauge(format!("{}.{}", key, iface), key, Some(tags))
+            .set(u64::from(value));
+    };
+    gauge("pcap_received", stats.received);
+    gauge("pcap_dropped", stats.dropped);
+    gauge("pcap_if_dropped", stats.if_dropped);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::Recorder;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn report() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let registry = Registry::from_sink("nginx", Recorder(lines.clone()));
+        let telemetry = Telemetry::new();
+
+        telemetry.packet();
+        telemetry.packet();
+        telemetry.shed();
+        telemetry.segment(&Ok(()));
+        telemetry.segment::<()>(&Err(CentrifugeError::UnknownProtocol));
+        telemetry.http_errors(0);
+        telemetry.http_errors(3);
+        telemetry.streams_evicted(2);
+        telemetry.exchanges_evicted(1);
+        telemetry.geoip_miss("asn");
+        telemetry.enqueue();
+        telemetry.enqueue();
+        telemetry.dequeue();
+
+        let pool = ThreadPool::new(1);
+        telemetry.report(&registry, Some(&pool));
+        registry.send();
+
+        for line in &[
+            "nginx.httpsniffer.self.packets:2|c",
+            "nginx.httpsniffer.self.packets_shed:1|c",
+            "nginx.httpsniffer.self.segments:1|c",
+            "nginx.httpsniffer.self.segment_errors:1|c|#kind:unknown_protocol",
+            "nginx.httpsniffer.self.segment_errors:0|c|#kind:invalid_packet",
+            "nginx.httpsniffer.self.http_errors:3|c",
+            "nginx.httpsniffer.self.streams_evicted:2|c",
+            "nginx.httpsniffer.self.exchanges_evicted:1|c",
+            "nginx.httpsniffer.self.geoip_misses:1|c|#database:asn",
+            "nginx.httpsniffer.self.geoip_misses:0|c|#database:country",
+            "nginx.httpsniffer.self.channel_queue:1|g",
+            "nginx.httpsniffer.self.pool_queue:0|g",
+        ] {
+            let lines = lines.lock().unwrap();
+            assert!(
+                lines.contains(&line.to_string()),
+                "{} not in {:?}",
+                line,
+                lines
+            );
+        }
+
+        // counters start over in the next window
+        telemetry.report(&registry, None);
+        registry.send();
+        assert!(lines
+            .lock()
+            .unwrap()
+            .contains(&"nginx.httpsniffer.self.packets:0|c".to_string()));
+    }
+}
@@ -0,0 +1,41 @@
+//! Fixtures shared by the tests of several modules
+
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::sink::MetricSink;
+
+/// 2019-04-23T06:13:20.250Z
+pub const TS: Duration = Duration::from_millis(1_556_000_000_250);
+
+/// Keeps every line that is sent to it
+pub struct Recorder(pub Arc<Mutex<Vec<String>>>);
+
+impl MetricSink for Recorder {
+    fn emit(&self, line: &str) -> io::Result<()> {
+        self.0.lock().unwrap().push(line.to_string());
+        Ok(())
+    }
+}
+
+/// Keeps everything that is written to it, clones share the contents
+#[derive(Clone, Default)]
+pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Buffer {
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
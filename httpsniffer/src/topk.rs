@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+
+/// Counters kept per requested key, more counters make the top keys more accurate
+const CAPACITY_FACTOR: usize = 10;
+
+/// Space-Saving heavy hitter tracker
+///
+/// Keeps a fixed number of counters. An unseen item replaces the item with
+/// the smallest count and inherits that count as its error, so counts are
+/// overestimated by at most the smallest count but every item that occurred
+/// more often than `total / capacity` times is guaranteed to be tracked.
+///
+/// Counters are kept in a stream summary: buckets of the counters with the
+/// same count, linked in ascending order. Both incrementing a counter and
+/// finding the smallest one are O(1).
+#[derive(Debug, Clone)]
+pub struct SpaceSaving {
+    capacity: usize,
+    index: HashMap<String, usize>,
+    counters: Vec<Counter>,
+    buckets: Vec<Bucket>,
+    /// Buckets that became empty and can be reused
+    free: Vec<usize>,
+    /// The bucket with the smallest count
+    min: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct Counter {
+    value: String,
+    error: u64,
+    bucket: usize,
+    /// Position in the counters of the bucket
+    pos: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    count: u64,
+    counters: Vec<usize>,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+/// A tracked item with its estimated count and the maximum overestimation
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeavyHitter {
+    pub value: String,
+    pub count: u64,
+    pub error: u64,
+}
+
+impl SpaceSaving {
+    /// A tracker sized to report the top `k` items
+    pub fn new(k: usize) -> SpaceSaving {
+        SpaceSaving::with_capacity(k.saturating_mul(CAPACITY_FACTOR))
+    }
+
+    pub fn with_capacity(capacity: usize) -> SpaceSaving {
+        let capacity = capacity.max(1);
+        SpaceSaving {
+            capacity,
+            index: HashMap::with_capacity(capacity),
+            counters: Vec::with_capacity(capacity),
+            buckets: Vec::new(),
+            free: Vec::new(),
+            min: None,
+        }
+    }
+
+    pub fn insert(&mut self, item: &str) {
+        if let Some(&counter) = self.index.get(item) {
+            self.increment(counter);
+            return;
+        }
+
+        if self.counters.len() < self.capacity {
+            // every other count is at least 1, so the new counter goes first
+            let bucket = match self.min {
+                Some(min) if self.buckets[min].count == 1 => min,
+                min => {
+                    let bucket = self.bucket(1, None, min);
+                    self.min = Some(bucket);
+                    bucket
+                }
+            };
+            let counter = self.counters.len();
+            self.counters.push(Counter {
+                value: item.to_string(),
+                error: 0,
+                bucket,
+                pos: self.buckets[bucket].counters.len(),
+            });
+            self.buckets[bucket].counters.push(counter);
+            self.index.insert(item.to_string(), counter);
+            return;
+        }
+
+        let min = match self.min {
+            Some(min) => min,
+            None => return,
+        };
+        let counter = *self.buckets[min].counters.last().expect("bucket is empty");
+        let replaced = &mut self.counters[counter];
+        self.index.remove(&replaced.value);
+        replaced.value = item.to_string();
+        replaced.error = self.buckets[min].count;
+        self.index.insert(item.to_string(), counter);
+        self.increment(counter);
+    }
+
+    /// Move a counter into the bucket after its own
+    fn increment(&mut self, counter: usize) {
+        let old = self.counters[counter].bucket;
+        let count = self.buckets[old].count + 1;
+        let new = match self.buckets[old].next {
+            Some(next) if self.buckets[next].count == count => next,
+            next => self.bucket(count, Some(old), next),
+        };
+
+        // swap the last counter of the old bucket into the place of this one
+        let pos = self.counters[counter].pos;
+        self.buckets[old].counters.swap_remove(pos);
+        if let Some(&moved) = self.buckets[old].counters.get(pos) {
+            self.counters[moved].pos = pos;
+        }
+        self.counters[counter].bucket = new;
+        self.counters[counter].pos = self.buckets[new].counters.len();
+        self.buckets[new].counters.push(counter);
+
+        if self.buckets[old].counters.is_empty() {
+            self.unlink(old);
+        }
+    }
+
+    /// A new, empty bucket linked between `prev` and `next`
+    fn bucket(&mut self, count: u64, prev: Option<usize>, next: Option<usize>) -> usize {
+        let bucket = Bucket {
+            count,
+            counters: Vec::new(),
+            prev,
+            next,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.buckets[index] = bucket;
+                index
+            }
+            None => {
+                self.buckets.push(bucket);
+                self.buckets.len() - 1
+            }
+        };
+        if let Some(prev) = prev {
+            self.buckets[prev].next = Some(index);
+        }
+        if let Some(next) = next {
+            self.buckets[next].prev = Some(index);
+        }
+        index
+    }
+
+    fn unlink(&mut self, bucket: usize) {
+        let (prev, next) = (self.buckets[bucket].prev, self.buckets[bucket].next);
+        match prev {
+            Some(prev) => self.buckets[prev].next = next,
+            None => self.min = next,
+        }
+        if let Some(next) = next {
+            self.buckets[next].prev = prev;
+        }
+        self.free.push(bucket);
+    }
+
+    /// The `k` items with the highest counts, highest first
+    pub fn top(&self, k: usize) -> Vec<HeavyHitter> {
+        let mut top: Vec<HeavyHitter> = self
+            .counters
+            .iter()
+            .map(|counter| HeavyHitter {
+                value: counter.value.clone(),
+                count: self.buckets[counter.bucket].count,
+                error: counter.error,
+            })
+            .collect();
+        // ties are broken by value so the result doesn't depend on hashing
+        top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+        top.truncate(k);
+        top
+    }
+
+    pub fn clear(&mut self) {
+        self.index.clear();
+        self.counters.clear();
+        self.buckets.clear();
+        self.free.clear();
+        self.min = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_below_capacity() {
+        let mut top = SpaceSaving::with_capacity(10);
+        for item in &["a", "b", "a", "c", "a", "b"] {
+            top.insert(item);
+        }
+
+        let top = top.top(2);
+        assert_eq!(
+            top,
+            vec![
+                HeavyHitter {
+                    value: "a".to_string(),
+                    count: 3,
+                    error: 0
+                },
+                HeavyHitter {
+                    value: "b".to_string(),
+                    count: 2,
+                    error: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn heavy_hitters_survive() {
+        let mut top = SpaceSaving::new(2);
+        for i in 0..10_000 {
+            top.insert(&format!("noise-{}", i));
+            if i % 3 == 0 {
+                top.insert("10.0.0.1");
+            }
+            if i % 5 == 0 {
+                top.insert("10.0.0.2");
+            }
+        }
+
+        let top = top.top(2);
+        assert_eq!(top[0].value, "10.0.0.1");
+        assert_eq!(top[1].value, "10.0.0.2");
+        // estimates are never too low and off by at most the error
+        assert!(top[0].count >= 3334 && top[0].count - top[0].error <= 3334);
+        assert!(top[1].count >= 2000 && top[1].count - top[1].error <= 2000);
+    }
+
+    #[test]
+    fn same_as_scanning() {
+        // the plain algorithm, replacing the smallest counter found by a scan
+        let mut naive: HashMap<String, (u64, u64)> = HashMap::new();
+        let mut top = SpaceSaving::with_capacity(8);
+        let mut seed = 7u64;
+        for _ in 0..5_000 {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            let item = format!("{}", (seed >> 33) % 20);
+            top.insert(&item);
+
+            if let Some(entry) = naive.get_mut(&item) {
+                entry.0 += 1;
+                continue;
+            }
+            if naive.len() == 8 {
+                let min = *naive.values().map(|(count, _)| count).min().unwrap();
+                // which of the smallest counters is replaced doesn't matter for
+                // the counts, so follow the choice of the summary
+                let replaced = naive
+                    .keys()
+                    .find(|value| naive[*value].0 == min && !top.index.contains_key(*value))
+                    .unwrap()
+                    .clone();
+                naive.remove(&replaced);
+                naive.insert(item, (min + 1, min));
+            } else {
+                naive.insert(item, (1, 0));
+            }
+        }
+
+        let mut expected: Vec<_> = naive
+            .into_iter()
+            .map(|(value, (count, error))| HeavyHitter {
+                value,
+                count,
+                error,
+            })
+            .collect();
+        expected.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+        assert_eq!(top.top(8), expected);
+    }
+
+    #[test]
+    fn clear() {
+        let mut top = SpaceSaving::new(1);
+        top.insert("a");
+        top.clear();
+        assert!(top.top(1).is_empty());
+    }
+}
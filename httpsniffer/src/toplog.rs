@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::metrics::Exporter;
+use crate::quantile::QuantileSketch;
+use crate::topk::HeavyHitter;
+
+/// Writes the heavy hitters of every window as one json object per line
+///
+/// Other metrics are left to statsd and prometheus.
+pub struct TopLog {
+    out: Mutex<Box<dyn Write + Send>>,
+    /// End of the window that is being flushed
+    window: Mutex<Duration>,
+}
+
+impl TopLog {
+    /// `-` writes to stdout, everything else is a file that is appended to
+    pub fn open(path: &str) -> io::Result<TopLog> {
+        let out: Box<dyn Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        Ok(TopLog::new(out))
+    }
+
+    pub fn new(out: Box<dyn Write + Send>) -> TopLog {
+        TopLog {
+            out: Mutex::new(out),
+            window: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+}
+
+impl Exporter for TopLog {
+    fn start(&self, timestamp: Duration) {
+        *self.window.lock().expect("lock window") = timestamp;
+    }
+
+    fn counter(&self, _key: &str, _value: usize, _tags: &HashMap<String, String>) {}
+
+    fn cardinality(&self, _key: &str, _value: usize, _tags: &HashMap<String, String>) {}
+
+    fn gauge(&self, _key: &str, _value: u64, _tags: &HashMap<String, String>) {}
+
+    fn histogram(&self, _key: &str, _sketch: &QuantileSketch, _tags: &HashMap<String, String>) {}
+
+    fn topk(&self, key: &str, top: &[HeavyHitter], tags: &HashMap<String, String>) {
+        if top.is_empty() {
+            return;
+        }
+
+        let timestamp = self.window.lock().expect("lock window").as_secs();
+        let tags: BTreeMap<_, _> = tags.iter().collect();
+        let top: Vec<_> = top
+            .iter()
+            .map(|hitter| {
+                json!({
+                    "value": hitter.value,
+                    "count": hitter.count,
+                    "error": hitter.error,
+                })
+            })
+            .collect();
+        let line = json!({
+            "timestamp": timestamp,
+            "metric": key,
+            "tags": tags,
+            "top": top,
+        });
+
+        let mut out = self.out.lock().expect("lock top log");
+        if let Err(err) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+            eprintln!("top log error: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[derive(Clone)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        let buf = Buffer(Arc::new(Mutex::new(Vec::new())));
+        let log = TopLog::new(Box::new(buf.clone()));
+        let mut tags = HashMap::new();
+        tags.insert("port".to_string(), "80".to_string());
+        tags.insert("host".to_string(), "www_example_com".to_string());
+
+        log.start(Duration::from_secs(1_500_000_010));
+        log.topk("ips_top_per_10s", &[], &tags);
+        log.topk(
+            "ips_top_per_10s",
+            &[HeavyHitter {
+                value: "10.0.0.1".to_string(),
+                count: 42,
+                error: 1,
+            }],
+            &tags,
+        );
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        let line: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(line["metric"], "ips_top_per_10s");
+        assert_eq!(line["tags"]["host"], "www_example_com");
+        assert_eq!(
+            line["top"],
+            json!([{"value": "10.0.0.1", "count": 42, "error": 1}])
+        );
+        assert_eq!(line["timestamp"], 1_500_000_010);
+    }
+}
@@ -1,6 +1,11 @@
+use std::cmp;
+use std::str::from_utf8;
+
+use nom;
 use nom_http;
 
-use structs::http::Request;
+use reassembly::{Stream, MAX_BUFFER};
+use structs::http::{Request, Response};
 use structs::CentrifugeError;
 
 pub fn extract(remaining: &[u8]) -> Result<Request, CentrifugeError> {
@@ -13,3 +18,177 @@ pub fn extract(remaining: &[u8]) -> Result<Request, CentrifugeError> {
         Err(CentrifugeError::WrongProtocol)
     }
 }
+
+pub fn extract_response(remaining: &[u8]) -> Result<Response, CentrifugeError> {
+    if let Ok((_remaining, (response, headers))) = nom_http::response(remaining) {
+        match Response::from_nom(&response, headers) {
+            Ok(http) => Ok(http),
+            Err(_) => Err(CentrifugeError::ParsingError),
+        }
+    } else {
+        Err(CentrifugeError::WrongProtocol)
+    }
+}
+
+fn content_length(headers: &[nom_http::Header]) -> usize {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(b"content-length"))
+        .and_then(|header| {
+            let value: Vec<u8> = header.value.iter().flat_map(|x| x.to_vec()).collect();
+            from_utf8(&value).ok()?.trim().parse().ok()
+        })
+        .unwrap_or(0)
+}
+
+/// Parse every complete request at the front of a reassembled stream
+///
+/// Bodies are read according to their content-length so pipelined requests
+/// on a keep-alive connection are picked up as well. Bodies that don't fit
+/// into the stream buffer are truncated and the rest is skipped.
+pub fn extract_stream(stream: &mut Stream) -> Vec<Request> {
+    let mut requests = Vec::new();
+
+    while !stream.data().is_empty() {
+        let (request, consumed, skip) = match nom_http::request(stream.data()) {
+            Ok((remaining, (request, headers))) => {
+                let head = stream.data().len() - remaining.len();
+                let body = content_length(&headers);
+                let available = cmp::min(body, remaining.len());
+
+                if available < body && stream.data().len() < MAX_BUFFER {
+                    // wait for the rest of the body
+                    break;
+                }
+
+                let request = Request::from_nom(&request, headers, &remaining[..available]).ok();
+                (request, head + available, body - available)
+            }
+            Err(nom::Err::Incomplete(_)) if stream.data().len() < MAX_BUFFER => break,
+            Err(_) => {
+                // not http, or we lost track of the message boundaries
+                stream.discard();
+                break;
+            }
+        };
+
+        stream.consume(consumed);
+        stream.skip(skip);
+        if let Some(request) = request {
+            requests.push(request);
+        }
+    }
+
+    requests
+}
+
+/// Parse every response header at the front of a reassembled stream
+///
+/// Responses are returned as soon as their header is complete, their bodies
+/// are skipped using content-length or chunked transfer encoding. Bodies that
+/// are delimited by closing the connection are dropped with the buffer.
+///
+/// `method` is called once for every final response, in order, and returns
+/// the method of the request it answers if that is known. Responses to HEAD
+/// have a content-length but no body. Interim responses like `100 Continue`
+/// are skipped, the request is still waiting for its final response.
+pub fn extract_response_stream<F>(stream: &mut Stream, mut method: F) -> Vec<Response>
+where
+    F: FnMut() -> Option<String>,
+{
+    let mut responses = Vec::new();
+
+    while !stream.data().is_empty() {
+        if stream.is_chunked() {
+            if !skip_chunk(stream) {
+                break;
+            }
+            continue;
+        }
+
+        let (response, consumed) = match nom_http::response(stream.data()) {
+            Ok((remaining, (response, headers))) => {
+                let head = stream.data().len() - remaining.len();
+                (Response::from_nom(&response, headers).ok(), head)
+            }
+            Err(nom::Err::Incomplete(_)) if stream.data().len() < MAX_BUFFER => break,
+            Err(_) => {
+                stream.discard();
+                break;
+            }
+        };
+
+        stream.consume(consumed);
+        if let Some(response) = response {
+            if response.is_interim() {
+                continue;
+            }
+            let has_body = match method() {
+                Some(method) => response.has_body_for(&method),
+                None => response.has_body(),
+            };
+            if has_body {
+                if response.is_chunked() {
+                    stream.set_chunked(true);
+                } else if let Some(len) = response.content_length {
+                    stream.skip(len);
+                } else {
+                    stream.clear();
+                }
+            }
+            responses.push(response);
+        }
+    }
+
+    responses
+}
+
+/// Skip over one chunk of a chunked body, returns false if more data is needed
+fn skip_chunk(stream: &mut Stream) -> bool {
+    let line = match stream.data().iter().position(|&c| c == b'\n') {
+        Some(pos) => pos + 1,
+        None if stream.data().len() < MAX_BUFFER => return false,
+        None => {
+            stream.discard();
+            return false;
+        }
+    };
+
+    let size = from_utf8(&stream.data()[..line])
+        .ok()
+        .and_then(|x| x.split(';').next())
+        .and_then(|x| usize::from_str_radix(x.trim(), 16).ok());
+
+    match size {
+        Some(0) => {
+            // the last chunk is followed by optional trailers and an empty line
+            let trailers = &stream.data()[line..];
+            let end = if trailers.starts_with(b"\r\n") {
+                Some(2)
+            } else {
+                trailers
+                    .windows(4)
+                    .position(|x| x == b"\r\n\r\n")
+                    .map(|pos| pos + 4)
+            };
+            match end {
+                Some(end) => {
+                    stream.consume(line + end);
+                    stream.set_chunked(false);
+                    true
+                }
+                None => false,
+            }
+        }
+        Some(size) => {
+            stream.consume(line);
+            // the size is untrusted, a huge one skips the rest of the stream
+            stream.skip(size.saturating_add(2));
+            true
+        }
+        None => {
+            stream.discard();
+            false
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::cmp;
+use std::net::Ipv6Addr;
+
+use nom::{be_u16, be_u32, be_u8};
+use structs::{ipv6, CentrifugeError};
+
+pub const HOP_BY_HOP: u8 = 0;
+pub const TCP: u8 = 6;
+pub const UDP: u8 = 17;
+pub const ROUTING: u8 = 43;
+pub const FRAGMENT: u8 = 44;
+pub const AUTHENTICATION: u8 = 51;
+pub const DESTINATION_OPTIONS: u8 = 60;
+
+/// Extension headers of a single packet we are willing to walk
+const MAX_EXTENSION_HEADERS: usize = 16;
+
+named!(address<&[u8], Ipv6Addr>, map!(take!(16), |x| {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(x);
+    Ipv6Addr::from(octets)
+}));
+
+named!(ipv6_header<&[u8], ipv6::IPv6Header>, do_parse!(
+    first:          be_u32 >>
+    length:         be_u16 >>
+    next_header:    be_u8 >>
+    hop_limit:      be_u8 >>
+    source_addr:    address >>
+    dest_addr:      address >>
+
+    ({ ipv6::IPv6Header {
+        version: (first >> 28) as u8,
+        traffic_class: ((first >> 20) & 0xff) as u8,
+        flow_label: first & 0xf_ffff,
+        length,
+        next_header,
+        hop_limit,
+        source_addr,
+        dest_addr,
+    } })
+));
+
+/// Parse the fixed header and skip over the extension headers
+///
+/// `next_header` of the returned header is the protocol of the remaining
+/// bytes. Non-initial fragments stop the walk at the fragment header since
+/// they don't start with an upper layer header. The remaining bytes are cut to
+/// the payload length, so ethernet padding doesn't end up in the payload.
+pub fn parse_ipv6_header(data: &[u8]) -> Result<(&[u8], ipv6::IPv6Header), CentrifugeError> {
+    let (remaining, mut header) = match ipv6_header(data) {
+        Ok(x) => x,
+        Err(_) => return Err(CentrifugeError::InvalidPacket),
+    };
+    if header.version != 6 {
+        return Err(CentrifugeError::InvalidPacket);
+    }
+
+    // a payload length of 0 is used by jumbograms and segments captured before tso
+    let mut remaining = if header.length == 0 {
+        remaining
+    } else {
+        &remaining[..cmp::min(header.length as usize, remaining.len())]
+    };
+
+    for _ in 0..MAX_EXTENSION_HEADERS {
+        let len = match header.next_header {
+            HOP_BY_HOP | ROUTING | DESTINATION_OPTIONS => {
+                remaining.get(1).map(|&len| (len as usize + 1) * 8)
+            }
+            AUTHENTICATION => remaining.get(1).map(|&len| (len as usize + 2) * 4),
+            FRAGMENT => {
+                if remaining.len() < 8 {
+                    return Err(CentrifugeError::InvalidPacket);
+                }
+                let offset = ((remaining[2] as u16) << 8 | remaining[3] as u16) >> 3;
+                if offset != 0 {
+                    return Ok((remaining, header));
+                }
+                Some(8)
+            }
+            _ => return Ok((remaining, header)),
+        };
+
+        match len {
+            Some(len) if len <= remaining.len() => {
+                header.next_header = remaining[0];
+                remaining = &remaining[len..];
+            }
+            _ => return Err(CentrifugeError::InvalidPacket),
+        }
+    }
+
+    Err(CentrifugeError::InvalidPacket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(next_header: u8, length: u16) -> Vec<u8> {
+        let mut pkt = vec![0x60, 0x00, 0x00, 0x01];
+        pkt.push((length >> 8) as u8);
+        pkt.push(length as u8);
+        pkt.push(next_header);
+        pkt.push(64);
+        pkt.extend(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        pkt.extend(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        pkt
+    }
+
+    #[test]
+    fn fixed_header() {
+        let mut pkt = header(TCP, 4);
+        pkt.extend(&[1, 2, 3, 4, 0, 0]);
+
+        let (remaining, hdr) = parse_ipv6_header(&pkt).unwrap();
+        assert_eq!(remaining, &[1, 2, 3, 4]);
+        assert_eq!(
+            hdr,
+            ipv6::IPv6Header {
+                version: 6,
+                traffic_class: 0,
+                flow_label: 1,
+                length: 4,
+                next_header: TCP,
+                hop_limit: 64,
+                source_addr: "2001:db8::1".parse().unwrap(),
+                dest_addr: "2001:db8::2".parse().unwrap(),
+            }
+        );
+    }
+
+    #[test]
+    fn extension_headers() {
+        let mut pkt = header(HOP_BY_HOP, 8 + 16 + 8 + 2);
+        // hop-by-hop options with padding
+        pkt.extend(&[ROUTING, 0, 1, 4, 0, 0, 0, 0]);
+        // routing header with one extra block
+        pkt.extend(&[FRAGMENT, 1, 0, 0, 0, 0, 0, 0]);
+        pkt.extend(&[0; 8]);
+        // first fragment
+        pkt.extend(&[UDP, 0, 0, 1, 0, 0, 0, 42]);
+        pkt.extend(&[0xab, 0xcd]);
+
+        let (remaining, hdr) = parse_ipv6_header(&pkt).unwrap();
+        assert_eq!(hdr.next_header, UDP);
+        assert_eq!(remaining, &[0xab, 0xcd]);
+    }
+
+    #[test]
+    fn later_fragment() {
+        let mut pkt = header(FRAGMENT, 10);
+        pkt.extend(&[TCP, 0, 0, 0x10, 0, 0, 0, 42]);
+        pkt.extend(&[0xab, 0xcd]);
+
+        let (remaining, hdr) = parse_ipv6_header(&pkt).unwrap();
+        assert_eq!(hdr.next_header, FRAGMENT);
+        assert_eq!(remaining.len(), 10);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse_ipv6_header(&[0x60, 0, 0]).is_err());
+
+        let pkt = header(TCP, 0);
+        let mut ipv4 = pkt.clone();
+        ipv4[0] = 0x45;
+        assert!(parse_ipv6_header(&ipv4).is_err());
+
+        // extension header longer than the packet
+        let mut pkt = header(DESTINATION_OPTIONS, 0);
+        pkt.extend(&[TCP, 4, 0, 0]);
+        assert!(parse_ipv6_header(&pkt).is_err());
+    }
+}
@@ -11,6 +11,7 @@ use structs::CentrifugeError;
 
 pub mod arp;
 pub mod cjdns;
+pub mod ipv6;
 pub mod tcp;
 pub mod udp;
 
@@ -43,10 +44,10 @@ pub fn parse_eth(data: &[u8]) -> Result<raw::Raw, CentrifugeError> {
                 Ok(ipv4) => ipv4,
                 Err(_) => Unknown(remaining.to_vec()),
             },
-            EtherType::IPv6 => {
-                // TODO
-                Unknown(remaining.to_vec())
-            }
+            EtherType::IPv6 => match parse_ipv6(remaining) {
+                Ok(ipv6) => ipv6,
+                Err(_) => Unknown(remaining.to_vec()),
+            },
             EtherType::ARP => match arp::extract(remaining) {
                 Ok(arp_pkt) => Arp(arp_pkt),
                 Err(_) => Unknown(remaining.to_vec()),
@@ -65,8 +66,12 @@ pub fn parse_eth(data: &[u8]) -> Result<raw::Raw, CentrifugeError> {
 
 #[inline]
 pub fn parse_tun(data: &[u8]) -> raw::Raw {
-    raw::Raw::Tun(if let Ok(ipv4) = parse_ipv4(data) {
-        ipv4
+    let ether = match data.first().map(|x| x >> 4) {
+        Some(6) => parse_ipv6(data),
+        _ => parse_ipv4(data),
+    };
+    raw::Raw::Tun(if let Ok(ether) = ether {
+        ether
     } else {
         Ether::Unknown(data.to_vec())
     })
@@ -91,3 +96,20 @@ pub fn parse_ipv4(data: &[u8]) -> Result<ether::Ether, CentrifugeError> {
         Ok(Ether::Unknown(data.to_vec()))
     }
 }
+
+#[inline]
+pub fn parse_ipv6(data: &[u8]) -> Result<ether::Ether, CentrifugeError> {
+    let (remaining, ip_hdr) = ipv6::parse_ipv6_header(data)?;
+    let inner = match ip_hdr.next_header {
+        ipv6::TCP => match tcp::parse(remaining) {
+            Ok((tcp_hdr, tcp)) => TCP(tcp_hdr, tcp),
+            Err(_) => IPv4::Unknown(remaining.to_vec()),
+        },
+        ipv6::UDP => match udp::parse(remaining) {
+            Ok((udp_hdr, udp)) => UDP(udp_hdr, udp),
+            Err(_) => IPv4::Unknown(remaining.to_vec()),
+        },
+        _ => IPv4::Unknown(remaining.to_vec()),
+    };
+    Ok(IPv6(ip_hdr, inner))
+}
@@ -36,6 +36,9 @@ pub fn extract(tcp_hdr: &TcpHeader, remaining: &[u8]) -> Result<TCP, CentrifugeE
         }
         */
         Err(CentrifugeError::UnknownProtocol)
+    } else if remaining.starts_with(b"HTTP/") {
+        let response = http::extract_response(remaining)?;
+        Ok(TCP::HTTPResponse(response))
     } else {
         // we try to parse any other packet as http protocol
         let http = http::extract(remaining)?;
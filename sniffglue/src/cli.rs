@@ -21,6 +21,6 @@ pub struct Args {
     /// Number of cores
     #[structopt(short = "n", long = "cpus")]
     pub cpus: Option<usize>,
-    /// Device for sniffing
-    pub device: Option<String>,
+    /// Devices for sniffing, or pcap files with --read
+    pub devices: Vec<String>,
 }
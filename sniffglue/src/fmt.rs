@@ -1,3 +1,4 @@
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
 use ansi_term::Colour::{self, Blue, Green, Purple, Red, Yellow};
@@ -109,15 +110,18 @@ impl Format {
     fn format_compact_eth(&self, out: &mut String, eth: ether::Ether) -> Option<Colour> {
         match eth {
             Arp(arp_pkt) => self.format_compact_arp(out, arp_pkt),
-            IPv4(ip_hdr, TCP(tcp_hdr, tcp)) => {
-                self.format_compact_ipv4_tcp(out, &ip_hdr, &tcp_hdr, tcp)
-            }
-            IPv4(ip_hdr, UDP(udp_hdr, udp)) => {
-                self.format_compact_ipv4_udp(out, &ip_hdr, &udp_hdr, udp)
-            }
-            IPv4(ip_hdr, ipv4::IPv4::Unknown(data)) => {
-                self.format_compact_ipv4_unknown(out, &ip_hdr, &data)
-            }
+            IPv4(ip_hdr, inner) => self.format_compact_ip(
+                out,
+                IpAddr::V4(ip_hdr.source_addr),
+                IpAddr::V4(ip_hdr.dest_addr),
+                inner,
+            ),
+            IPv6(ip_hdr, inner) => self.format_compact_ip(
+                out,
+                IpAddr::V6(ip_hdr.source_addr),
+                IpAddr::V6(ip_hdr.dest_addr),
+                inner,
+            ),
             Cjdns(cjdns_pkt) => self.format_compact_cjdns(out, &cjdns_pkt),
             ether::Ether::Unknown(data) => self.format_compact_unknown_data(out, &data),
         }
@@ -183,31 +187,54 @@ impl Format {
     }
 
     #[inline]
-    fn format_compact_ipv4_unknown(
+    fn format_compact_ip(
+        &self,
+        out: &mut String,
+        source_addr: IpAddr,
+        dest_addr: IpAddr,
+        inner: ipv4::IPv4,
+    ) -> Option<Colour> {
+        match inner {
+            TCP(tcp_hdr, tcp) => {
+                self.format_compact_ip_tcp(out, source_addr, dest_addr, &tcp_hdr, tcp)
+            }
+            UDP(udp_hdr, udp) => {
+                self.format_compact_ip_udp(out, source_addr, dest_addr, &udp_hdr, udp)
+            }
+            ipv4::IPv4::Unknown(data) => {
+                self.format_compact_ip_unknown(out, source_addr, dest_addr, &data)
+            }
+        }
+    }
+
+    #[inline]
+    fn format_compact_ip_unknown(
         &self,
         out: &mut String,
-        ip_hdr: &pktparse::ipv4::IPv4Header,
+        source_addr: IpAddr,
+        dest_addr: IpAddr,
         data: &[u8],
     ) -> Option<Colour> {
         out.push_str(&format!(
             "[unknown] {:15} -> {:15} {:?}",
-            ip_hdr.source_addr, ip_hdr.dest_addr, data
+            source_addr, dest_addr, data
         ));
         None
     }
 
     #[inline]
-    fn format_compact_ipv4_tcp(
+    fn format_compact_ip_tcp(
         &self,
         out: &mut String,
-        ip_hdr: &pktparse::ipv4::IPv4Header,
+        source_addr: IpAddr,
+        dest_addr: IpAddr,
         tcp_hdr: &pktparse::tcp::TcpHeader,
         tcp: tcp::TCP,
     ) -> Option<Colour> {
         out.push_str(&format!(
             "[tcp] {:22} -> {:22} ",
-            format!("{}:{}", ip_hdr.source_addr, tcp_hdr.source_port),
-            format!("{}:{}", ip_hdr.dest_addr, tcp_hdr.dest_port)
+            SocketAddr::new(source_addr, tcp_hdr.source_port).to_string(),
+            SocketAddr::new(dest_addr, tcp_hdr.dest_port).to_string()
         ));
 
         use structs::tcp::TCP::*;
@@ -217,6 +244,10 @@ impl Format {
                 out.push_str(&format!("[http] {:?}", http)); // TODO
                 Some(Green)
             }
+            HTTPResponse(response) => {
+                out.push_str(&format!("[http] {:?}", response)); // TODO
+                Some(Green)
+            }
             TLS(client_hello) => {
                 let extra = display_kv_list(&[("hostname", client_hello.hostname)]);
 
@@ -236,17 +267,18 @@ impl Format {
     }
 
     #[inline]
-    fn format_compact_ipv4_udp(
+    fn format_compact_ip_udp(
         &self,
         out: &mut String,
-        ip_hdr: &pktparse::ipv4::IPv4Header,
+        source_addr: IpAddr,
+        dest_addr: IpAddr,
         udp_hdr: &pktparse::udp::UdpHeader,
         udp: udp::UDP,
     ) -> Option<Colour> {
         out.push_str(&format!(
             "[udp] {:22} -> {:22} ",
-            format!("{}:{}", ip_hdr.source_addr, udp_hdr.source_port),
-            format!("{}:{}", ip_hdr.dest_addr, udp_hdr.dest_port)
+            SocketAddr::new(source_addr, udp_hdr.source_port).to_string(),
+            SocketAddr::new(dest_addr, udp_hdr.dest_port).to_string()
         ));
 
         use structs::udp::UDP::*;
@@ -403,32 +435,43 @@ impl Format {
                     self.colorify(Blue, format!("arp: {:?}", arp_pkt))
                 );
             }
-            IPv4(ip_hdr, TCP(tcp_hdr, tcp)) => {
+            IPv4(ip_hdr, inner) => {
                 println!("{}ipv4: {:?}", "\t".repeat(indent), ip_hdr);
-                println!("{}tcp: {:?}", "\t".repeat(indent + 1), tcp_hdr);
+                self.print_detailed_ip(indent + 1, inner);
+            }
+            IPv6(ip_hdr, inner) => {
+                println!("{}ipv6: {:?}", "\t".repeat(indent), ip_hdr);
+                self.print_detailed_ip(indent + 1, inner);
+            }
+            Cjdns(cjdns_pkt) => {
+                println!("{}cjdns: {:?}", "\t".repeat(indent), cjdns_pkt);
+            }
+            ether::Ether::Unknown(data) => {
+                println!("{}unknown: {:?}", "\t".repeat(indent), data);
+            }
+        }
+    }
+
+    #[inline]
+    fn print_detailed_ip(&self, indent: usize, inner: ipv4::IPv4) {
+        match inner {
+            TCP(tcp_hdr, tcp) => {
+                println!("{}tcp: {:?}", "\t".repeat(indent), tcp_hdr);
                 println!(
                     "{}{}",
-                    "\t".repeat(indent + 2),
+                    "\t".repeat(indent + 1),
                     self.print_detailed_tcp(tcp)
                 );
             }
-            IPv4(ip_hdr, UDP(udp_hdr, udp)) => {
-                println!("{}ipv4: {:?}", "\t".repeat(indent), ip_hdr);
-                println!("{}udp: {:?}", "\t".repeat(indent + 1), udp_hdr);
+            UDP(udp_hdr, udp) => {
+                println!("{}udp: {:?}", "\t".repeat(indent), udp_hdr);
                 println!(
                     "{}{}",
-                    "\t".repeat(indent + 2),
+                    "\t".repeat(indent + 1),
                     self.print_detailed_udp(udp)
                 );
             }
-            IPv4(ip_hdr, ipv4::IPv4::Unknown(data)) => {
-                println!("{}ipv4: {:?}", "\t".repeat(indent), ip_hdr);
-                println!("{}unknown: {:?}", "\t".repeat(indent + 1), data);
-            }
-            Cjdns(cjdns_pkt) => {
-                println!("{}cjdns: {:?}", "\t".repeat(indent), cjdns_pkt);
-            }
-            ether::Ether::Unknown(data) => {
+            ipv4::IPv4::Unknown(data) => {
                 println!("{}unknown: {:?}", "\t".repeat(indent), data);
             }
         }
@@ -452,6 +495,17 @@ impl Format {
                     http
                 ),
             ),
+            HTTPResponse(response) => self.colorify(
+                Green,
+                format!(
+                    "http: {:?} {:?}",
+                    format!(
+                        "HTTP/{} {} {}",
+                        response.version, response.status, response.reason
+                    ),
+                    response
+                ),
+            ),
             TLS(client_hello) => self.colorify(Green, format!("tls: {:?}", client_hello)),
             Text(text) => self.colorify(Blue, format!("remaining: {:?}", text)),
             Binary(x) => self.colorify(Yellow, format!("remaining: {:?}", x)),
@@ -27,6 +27,7 @@ extern crate users;
 pub mod centrifuge;
 pub mod link;
 mod nom_http;
+pub mod reassembly;
 #[cfg(all(target_os = "linux", feature = "sandbox"))]
 pub mod sandbox;
 #[allow(clippy::large_enum_variant)]
@@ -131,6 +132,41 @@ mod tests {
         assert_eq!(expected, x);
     }
 
+    #[test]
+    fn tcp_ipv6() {
+        use structs::ether::Ether::IPv6;
+        use structs::ipv4::IPv4::TCP;
+        use structs::raw::Raw::Ether;
+        use structs::tcp::TCP::Text;
+
+        let mut pkt = Vec::new();
+        pkt.extend(
+            [
+                0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x86, 0xdd,
+                0x60, 0, 0, 0, 0x01, 0xa2, 60, 55, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 1, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 6, 0, 1, 4,
+                0, 0, 0, 0, 0, 80, 142, 158, 133, 72, 141, 7, 64, 115, 177, 1, 80, 24, 1, 27, 0, 0,
+                0, 0,
+            ]
+            .iter(),
+        );
+        pkt.extend(HTML.iter());
+
+        match centrifuge::parse_eth(&pkt) {
+            Ok(Ether(_, IPv6(ip_hdr, TCP(tcp_hdr, Text(text))))) => {
+                assert_eq!(ip_hdr.next_header, 6);
+                assert_eq!(
+                    ip_hdr.source_addr,
+                    "2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap()
+                );
+                assert_eq!(tcp_hdr.source_port, 80);
+                assert_eq!(tcp_hdr.dest_port, 36510);
+                assert_eq!(text, String::from_utf8(HTML.to_vec()).unwrap());
+            }
+            x => panic!("unexpected packet: {:?}", x),
+        }
+    }
+
     #[test]
     fn regression_dhcp_16() {
         // https://github.com/kpcyrd/sniffglue/issues/16
@@ -72,9 +72,10 @@ fn main() {
 
     let args = Args::from_args();
 
-    let device = match args.device {
-        Some(device) => device,
-        None => Device::lookup().unwrap().name,
+    let devices = if args.devices.is_empty() {
+        vec![Device::lookup().unwrap().name]
+    } else {
+        args.devices
     };
 
     let layout = if args.json {
@@ -90,33 +91,37 @@ fn main() {
     let colors = atty::is(atty::Stream::Stdout);
     let config = fmt::Config::new(layout, args.verbose, colors);
 
-    let cap: CapWrap = if !args.read {
-        match Capture::from_device(device.as_str())
-            .unwrap()
-            .promisc(args.promisc)
-            .open()
-        {
-            Ok(cap) => {
-                eprintln!("Listening on device: {:?}", device);
-                cap.into()
-            }
-            Err(e) => {
-                eprintln!("Failed to open interface {:?}: {}", device, e);
-                return;
-            }
-        }
-    } else {
-        match Capture::from_file(device.as_str()) {
-            Ok(cap) => {
-                eprintln!("Reading from file: {:?}", device);
-                cap.into()
+    let mut caps = Vec::new();
+    for device in &devices {
+        let cap: CapWrap = if !args.read {
+            match Capture::from_device(device.as_str())
+                .unwrap()
+                .promisc(args.promisc)
+                .open()
+            {
+                Ok(cap) => {
+                    eprintln!("Listening on device: {:?}", device);
+                    cap.into()
+                }
+                Err(e) => {
+                    eprintln!("Failed to open interface {:?}: {}", device, e);
+                    return;
+                }
             }
-            Err(e) => {
-                eprintln!("Failed to open pcap file {:?}: {}", device, e);
-                return;
+        } else {
+            match Capture::from_file(device.as_str()) {
+                Ok(cap) => {
+                    eprintln!("Reading from file: {:?}", device);
+                    cap.into()
+                }
+                Err(e) => {
+                    eprintln!("Failed to open pcap file {:?}: {}", device, e);
+                    return;
+                }
             }
-        }
-    };
+        };
+        caps.push(cap);
+    }
 
     let (tx, rx): (Sender, Receiver) = mpsc::channel();
     let filter = config.filter();
@@ -124,49 +129,60 @@ fn main() {
     #[cfg(all(target_os = "linux", feature = "sandbox"))]
     sandbox::activate_stage2().expect("init sandbox stage2");
 
-    let join = thread::spawn(move || {
-        let pool = ThreadPool::new(cpus);
-
-        let mut cap = cap.activate();
-
-        let datalink = match DataLink::from_linktype(cap.get_datalink()) {
-            Ok(link) => link,
-            Err(x) => {
-                // TODO: properly exit the program
-                eprintln!(
-                    "Unknown link type: {:?}, {:?}, {}",
-                    x.get_name().unwrap_or_else(|_| "???".into()),
-                    x.get_description().unwrap_or_else(|_| "???".into()),
-                    x.0
-                );
-                return;
-            }
-        };
-
-        loop {
-            if let Ok(packet) = cap.next() {
-                // let ts = packet.header.ts;
-                // let len = packet.header.len;
-
-                let tx = tx.clone();
-                let packet = packet.data.to_vec();
-
-                let filter = filter.clone();
-                let datalink = datalink.clone();
-                pool.execute(move || {
-                    let packet = centrifuge::parse(&datalink, &packet);
-                    if filter.matches(&packet) {
-                        tx.send(packet).unwrap()
-                    }
-                });
+    let pool = ThreadPool::new(cpus);
+
+    // every device gets its own thread since each one may have a different link type
+    let mut joins = Vec::new();
+    for cap in caps {
+        let tx = tx.clone();
+        let pool = pool.clone();
+        let filter = filter.clone();
+
+        joins.push(thread::spawn(move || {
+            let mut cap = cap.activate();
+
+            let datalink = match DataLink::from_linktype(cap.get_datalink()) {
+                Ok(link) => link,
+                Err(x) => {
+                    // TODO: properly exit the program
+                    eprintln!(
+                        "Unknown link type: {:?}, {:?}, {}",
+                        x.get_name().unwrap_or_else(|_| "???".into()),
+                        x.get_description().unwrap_or_else(|_| "???".into()),
+                        x.0
+                    );
+                    return;
+                }
+            };
+
+            loop {
+                if let Ok(packet) = cap.next() {
+                    // let ts = packet.header.ts;
+                    // let len = packet.header.len;
+
+                    let tx = tx.clone();
+                    let packet = packet.data.to_vec();
+
+                    let filter = filter.clone();
+                    let datalink = datalink.clone();
+                    pool.execute(move || {
+                        let packet = centrifuge::parse(&datalink, &packet);
+                        if filter.matches(&packet) {
+                            tx.send(packet).unwrap()
+                        }
+                    });
+                }
             }
-        }
-    });
+        }));
+    }
+    drop(tx);
 
     let format = config.format();
     for packet in rx.iter() {
         format.print(packet);
     }
 
-    join.join().unwrap();
+    for join in joins {
+        join.join().unwrap();
+    }
 }
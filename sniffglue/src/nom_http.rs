@@ -16,6 +16,13 @@ pub struct Request<'a> {
     pub version: &'a [u8],
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct StatusLine<'a> {
+    pub version: &'a [u8],
+    pub status: &'a [u8],
+    pub reason: &'a [u8],
+}
+
 #[derive(Debug)]
 pub struct Header<'a> {
     pub name: &'a [u8],
@@ -67,6 +74,10 @@ fn is_version(c: u8) -> bool {
     c >= b'0' && c <= b'9' || c == b'.'
 }
 
+fn is_digit(c: u8) -> bool {
+    c >= b'0' && c <= b'9'
+}
+
 named!(line_ending, alt!(tag!("\r\n") | tag!("\n")));
 
 fn request_line(input: &[u8]) -> IResult<&[u8], Request> {
@@ -86,6 +97,23 @@ fn request_line(input: &[u8]) -> IResult<&[u8], Request> {
     )
 }
 
+fn status_line(input: &[u8]) -> IResult<&[u8], StatusLine> {
+    do_parse!(
+        input,
+        version: http_version
+            >> take_while1!(is_space)
+            >> status: take_while_m_n!(3, 3, is_digit)
+            >> take_while!(is_space)
+            >> reason: take_while!(not_line_ending)
+            >> line_ending
+            >> (StatusLine {
+                version,
+                status,
+                reason,
+            })
+    )
+}
+
 named!(
     http_version,
     preceded!(tag!("HTTP/"), take_while1!(is_version))
@@ -118,6 +146,14 @@ pub fn request(input: &[u8]) -> IResult<&[u8], (Request, Vec<Header>)> {
     )
 }
 
+pub fn response(input: &[u8]) -> IResult<&[u8], (StatusLine, Vec<Header>)> {
+    terminated!(
+        input,
+        pair!(status_line, many0!(message_header)),
+        line_ending
+    )
+}
+
 /*
 pub fn parse(data:&[u8]) -> Option<Vec<(Request, Vec<Header>)>> {
   let mut buf = &data[..];
@@ -0,0 +1,692 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use pktparse::ethernet::{self, EtherType};
+use pktparse::ipv4::{self, IPv4Protocol};
+use pktparse::tcp;
+
+use centrifuge::ipv6;
+use link::DataLink;
+use structs::CentrifugeError;
+
+/// Segments further ahead of the stream than this are considered garbage
+const MAX_WINDOW: u32 = 1 << 20;
+/// Out-of-order bytes that are held back per stream before we give up on the gap
+const MAX_PENDING: usize = 256 * 1024;
+/// Contiguous bytes that are buffered per stream while waiting for a complete message
+pub const MAX_BUFFER: usize = 64 * 1024;
+/// Streams that are tracked at the same time unless configured otherwise
+pub const MAX_STREAMS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub source_addr: IpAddr,
+    pub source_port: u16,
+    pub dest_addr: IpAddr,
+    pub dest_port: u16,
+}
+
+impl FlowKey {
+    /// The key of the other direction of the same connection
+    pub fn reverse(&self) -> FlowKey {
+        FlowKey {
+            source_addr: self.dest_addr,
+            source_port: self.dest_port,
+            dest_addr: self.source_addr,
+            dest_port: self.source_port,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub key: FlowKey,
+    pub sequence_no: u32,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub payload: Vec<u8>,
+}
+
+/// Decode the link, ip and tcp headers of a packet without looking at the payload
+pub fn segment(link: &DataLink, data: &[u8]) -> Result<Segment, CentrifugeError> {
+    let data = match *link {
+        DataLink::Ethernet => match ethernet::parse_ethernet_frame(data) {
+            Ok((remaining, frame)) => match frame.ethertype {
+                EtherType::IPv4 => return segment_ipv4(remaining),
+                EtherType::IPv6 => return segment_ipv6(remaining),
+                _ => return Err(CentrifugeError::UnknownProtocol),
+            },
+            Err(_) => return Err(CentrifugeError::InvalidPacket),
+        },
+        DataLink::Tun => data,
+        DataLink::RadioTap => return Err(CentrifugeError::UnknownProtocol),
+    };
+
+    match data.first().map(|x| x >> 4) {
+        Some(6) => segment_ipv6(data),
+        _ => segment_ipv4(data),
+    }
+}
+
+fn segment_ipv4(data: &[u8]) -> Result<Segment, CentrifugeError> {
+    let (remaining, ip_hdr) = match ipv4::parse_ipv4_header(data) {
+        Ok(x) => x,
+        Err(_) => return Err(CentrifugeError::InvalidPacket),
+    };
+    if ip_hdr.protocol != IPv4Protocol::TCP {
+        return Err(CentrifugeError::UnknownProtocol);
+    }
+
+    // ethernet frames are padded to 60 bytes, don't mistake the padding for payload.
+    // a total length of 0 is reported for segments that were captured before tso.
+    let remaining = if ip_hdr.length == 0 {
+        remaining
+    } else {
+        let len = (ip_hdr.length as usize).saturating_sub(ip_hdr.ihl as usize);
+        &remaining[..cmp::min(len, remaining.len())]
+    };
+
+    segment_tcp(
+        IpAddr::V4(ip_hdr.source_addr),
+        IpAddr::V4(ip_hdr.dest_addr),
+        remaining,
+    )
+}
+
+fn segment_ipv6(data: &[u8]) -> Result<Segment, CentrifugeError> {
+    // the payload is already cut to the length from the header
+    let (remaining, ip_hdr) = ipv6::parse_ipv6_header(data)?;
+    if ip_hdr.next_header != ipv6::TCP {
+        return Err(CentrifugeError::UnknownProtocol);
+    }
+
+    segment_tcp(
+        IpAddr::V6(ip_hdr.source_addr),
+        IpAddr::V6(ip_hdr.dest_addr),
+        remaining,
+    )
+}
+
+fn segment_tcp(
+    source_addr: IpAddr,
+    dest_addr: IpAddr,
+    data: &[u8],
+) -> Result<Segment, CentrifugeError> {
+    let (payload, tcp_hdr) = match tcp::parse_tcp_header(data) {
+        Ok(x) => x,
+        Err(_) => return Err(CentrifugeError::InvalidPacket),
+    };
+
+    Ok(Segment {
+        key: FlowKey {
+            source_addr,
+            source_port: tcp_hdr.source_port,
+            dest_addr,
+            dest_port: tcp_hdr.dest_port,
+        },
+        sequence_no: tcp_hdr.sequence_no,
+        syn: tcp_hdr.flag_syn,
+        fin: tcp_hdr.flag_fin,
+        rst: tcp_hdr.flag_rst,
+        payload: payload.to_vec(),
+    })
+}
+
+/// One direction of a tcp connection, put back into sequence order
+#[derive(Debug)]
+pub struct Stream {
+    isn: u32,
+    next: u32,
+    pending: BTreeMap<u32, Vec<u8>>,
+    pending_len: usize,
+    buffer: Vec<u8>,
+    skip: usize,
+    chunked: bool,
+    closed: bool,
+    errors: usize,
+    last_seen: Duration,
+}
+
+impl Stream {
+    fn new(sequence_no: u32, syn: bool, ts: Duration) -> Stream {
+        Stream {
+            isn: if syn {
+                sequence_no.wrapping_add(1)
+            } else {
+                sequence_no
+            },
+            next: 0,
+            pending: BTreeMap::new(),
+            pending_len: 0,
+            buffer: Vec::new(),
+            skip: 0,
+            chunked: false,
+            closed: false,
+            errors: 0,
+            last_seen: ts,
+        }
+    }
+
+    /// Contiguous bytes that haven't been consumed yet
+    #[inline]
+    pub fn data(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Drop `n` bytes from the front of the buffer
+    pub fn consume(&mut self, n: usize) {
+        let n = cmp::min(n, self.buffer.len());
+        self.buffer.drain(..n);
+    }
+
+    /// Drop the next `n` bytes of the stream, even if they didn't arrive yet
+    pub fn skip(&mut self, n: usize) {
+        let buffered = cmp::min(n, self.buffer.len());
+        self.consume(buffered);
+        self.skip += n - buffered;
+    }
+
+    /// Throw away everything that is buffered, eg. after a parser lost track
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.skip = 0;
+        self.chunked = false;
+    }
+
+    /// Like `clear`, but because the data couldn't be parsed
+    pub fn discard(&mut self) {
+        self.clear();
+        self.errors += 1;
+    }
+
+    /// Number of times the stream was discarded since the last call
+    pub fn take_errors(&mut self) -> usize {
+        mem::replace(&mut self.errors, 0)
+    }
+
+    /// Whether the stream is in the middle of a chunked message body
+    #[inline]
+    pub fn is_chunked(&self) -> bool {
+        self.chunked
+    }
+
+    #[inline]
+    pub fn set_chunked(&mut self, chunked: bool) {
+        self.chunked = chunked;
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn insert(&mut self, rel: u32, payload: &[u8]) {
+        if payload.is_empty() {
+            return;
+        }
+
+        if rel <= self.next {
+            self.append_overlapping(rel, payload);
+        } else if rel - self.next < MAX_WINDOW {
+            if self.pending_len + payload.len() > MAX_PENDING {
+                // the gap is not going to be filled anymore, continue after it
+                self.resync();
+            }
+
+            let entry = self.pending.entry(rel).or_default();
+            if payload.len() > entry.len() {
+                self.pending_len += payload.len() - entry.len();
+                *entry = payload.to_vec();
+            }
+        }
+
+        while let Some(rel) = self.pending.keys().next().cloned() {
+            if rel > self.next {
+                break;
+            }
+            let payload = self.pending.remove(&rel).expect("pending segment");
+            self.pending_len -= payload.len();
+            self.append_overlapping(rel, &payload);
+        }
+    }
+
+    fn append_overlapping(&mut self, rel: u32, payload: &[u8]) {
+        // retransmissions may overlap with data we already have
+        let overlap = (self.next - rel) as usize;
+        if overlap < payload.len() {
+            self.append(&payload[overlap..]);
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        self.next = self.next.wrapping_add(data.len() as u32);
+        let skipped = cmp::min(self.skip, data.len());
+        self.skip -= skipped;
+        self.buffer.extend_from_slice(&data[skipped..]);
+    }
+
+    fn resync(&mut self) {
+        if let Some(rel) = self.pending.keys().next().cloned() {
+            self.clear();
+            self.next = rel;
+        }
+    }
+}
+
+/// Reassembles tcp streams keyed by their 4-tuple
+///
+/// Timestamps are passed in by the caller so captures read from a file expire
+/// their flows the same way a live capture would.
+#[derive(Debug)]
+pub struct Reassembler {
+    streams: HashMap<FlowKey, Stream>,
+    timeout: Duration,
+    last_sweep: Duration,
+    max_streams: usize,
+    evicted: usize,
+    /// Stream that saw a fin in the last push, it's dropped in the next one
+    closed: Option<FlowKey>,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Reassembler {
+        Reassembler {
+            streams: HashMap::new(),
+            timeout,
+            last_sweep: Duration::from_secs(0),
+            max_streams: MAX_STREAMS,
+            evicted: 0,
+            closed: None,
+        }
+    }
+
+    /// Once this many streams are tracked, the least recently seen ones are
+    /// dropped to make room for new ones
+    pub fn set_max_streams(&mut self, max_streams: usize) {
+        self.max_streams = cmp::max(max_streams, 1);
+    }
+
+    /// Add a segment to its stream and return the stream so the caller can
+    /// consume whatever became contiguous
+    pub fn push(&mut self, segment: &Segment, ts: Duration) -> Option<&mut Stream> {
+        // the caller had its chance to read the rest of a closed stream
+        if let Some(key) = self.closed.take() {
+            if let Some(true) = self.streams.get(&key).map(|stream| stream.closed) {
+                self.streams.remove(&key);
+            }
+        }
+        self.sweep(ts);
+
+        if segment.rst {
+            self.streams.remove(&segment.key);
+            return None;
+        }
+        if segment.syn {
+            // the 4-tuple might be reused by a new connection
+            self.streams.remove(&segment.key);
+        }
+        if self.streams.len() >= self.max_streams && !self.streams.contains_key(&segment.key) {
+            self.evict();
+        }
+
+        let stream = self
+            .streams
+            .entry(segment.key)
+            .or_insert_with(|| Stream::new(segment.sequence_no, segment.syn, ts));
+        stream.last_seen = ts;
+
+        let mut sequence_no = segment.sequence_no;
+        if segment.syn {
+            sequence_no = sequence_no.wrapping_add(1);
+        }
+        stream.insert(sequence_no.wrapping_sub(stream.isn), &segment.payload);
+
+        if segment.fin {
+            stream.closed = true;
+            self.closed = Some(segment.key);
+        }
+
+        Some(stream)
+    }
+
+    /// Drop the least recently seen eighth of the streams, so a flood of new
+    /// connections doesn't pay for a full scan on every segment
+    fn evict(&mut self) {
+        let mut last_seen: Vec<(Duration, FlowKey)> = self
+            .streams
+            .iter()
+            .map(|(key, stream)| (stream.last_seen, *key))
+            .collect();
+        // streams that were seen at the same time are picked arbitrarily, so
+        // a burst doesn't take down more than the eighth
+        last_seen.sort_unstable_by_key(|&(last_seen, _)| last_seen);
+        last_seen.truncate(cmp::max(last_seen.len() / 8, 1));
+
+        for (_, key) in &last_seen {
+            self.streams.remove(key);
+        }
+        self.evicted += last_seen.len();
+    }
+
+    /// Number of streams that were dropped to stay below the limit since the
+    /// last call
+    pub fn take_evicted(&mut self) -> usize {
+        mem::replace(&mut self.evicted, 0)
+    }
+
+    /// Forget about streams that were closed or didn't see any traffic for too long
+    pub fn sweep(&mut self, ts: Duration) {
+        if ts < self.last_sweep + self.timeout {
+            return;
+        }
+        self.last_sweep = ts;
+
+        let timeout = self.timeout;
+        self.streams
+            .retain(|_, stream| !stream.closed && ts < stream.last_seen + timeout);
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use centrifuge::http;
+
+    fn key() -> FlowKey {
+        FlowKey {
+            source_addr: "192.168.1.2".parse().unwrap(),
+            source_port: 41234,
+            dest_addr: "192.168.1.1".parse().unwrap(),
+            dest_port: 80,
+        }
+    }
+
+    fn seg(sequence_no: u32, payload: &[u8]) -> Segment {
+        Segment {
+            key: key(),
+            sequence_no,
+            syn: false,
+            fin: false,
+            rst: false,
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn ts(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn ipv6_segment() {
+        let payload = b"GET / HTTP/1.1\r\n\r\n";
+        let mut pkt = vec![0x02, 0, 0, 0, 0, 2, 0x02, 0, 0, 0, 0, 1, 0x86, 0xdd];
+        pkt.extend(&[0x60, 0, 0, 0, 0, 20 + payload.len() as u8, 6, 64]);
+        pkt.extend(
+            &"2001:db8::1"
+                .parse::<::std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        pkt.extend(
+            &"2001:db8::2"
+                .parse::<::std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        pkt.extend(&[
+            0xa1, 0x12, 0, 80, 0, 0, 0x03, 0xe8, 0, 0, 0, 0, 0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0,
+        ]);
+        pkt.extend(payload.iter());
+        // trailing garbage is not part of the payload
+        pkt.extend(&[0, 0]);
+
+        let segment = segment(&DataLink::Ethernet, &pkt).unwrap();
+        assert_eq!(
+            segment.key,
+            FlowKey {
+                source_addr: "2001:db8::1".parse().unwrap(),
+                source_port: 41234,
+                dest_addr: "2001:db8::2".parse().unwrap(),
+                dest_port: 80,
+            }
+        );
+        assert_eq!(segment.sequence_no, 1000);
+        assert_eq!(segment.payload, payload.to_vec());
+
+        // raw ip without link layer
+        let tun = super::segment(&DataLink::Tun, &pkt[14..]).unwrap();
+        assert_eq!(tun, segment);
+    }
+
+    #[test]
+    fn out_of_order_and_retransmission() {
+        let mut r = Reassembler::new(ts(60));
+
+        let mut syn = seg(999, b"");
+        syn.syn = true;
+        r.push(&syn, ts(1));
+
+        assert_eq!(r.push(&seg(1005, b"world"), ts(1)).unwrap().data(), b"");
+        assert_eq!(
+            r.push(&seg(1000, b"hello"), ts(1)).unwrap().data(),
+            b"helloworld"
+        );
+        assert_eq!(
+            r.push(&seg(1003, b"lowo"), ts(1)).unwrap().data(),
+            b"helloworld"
+        );
+        assert_eq!(
+            r.push(&seg(1008, b"ld!"), ts(1)).unwrap().data(),
+            b"helloworld!"
+        );
+    }
+
+    #[test]
+    fn request_across_segments() {
+        let mut r = Reassembler::new(ts(60));
+
+        let stream = r
+            .push(&seg(1, b"GET / HTTP/1.1\r\nHost: exam"), ts(1))
+            .unwrap();
+        assert!(http::extract_stream(stream).is_empty());
+
+        let stream = r
+            .push(
+                &seg(27, b"ple.com\r\nContent-Length: 3\r\n\r\nabcGET"),
+                ts(1),
+            )
+            .unwrap();
+        let requests = http::extract_stream(stream);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].host, Some(String::from("example.com")));
+        assert_eq!(requests[0].data, "abc");
+        assert_eq!(stream.data(), b"GET");
+    }
+
+    #[test]
+    fn count_parse_errors() {
+        let mut r = Reassembler::new(ts(60));
+
+        let stream = r
+            .push(&seg(1, b"\x16\x03\x01\x02\x00\r\n\r\n"), ts(1))
+            .unwrap();
+        assert!(http::extract_stream(stream).is_empty());
+        assert!(stream.data().is_empty());
+        assert_eq!(stream.take_errors(), 1);
+        assert_eq!(stream.take_errors(), 0);
+    }
+
+    #[test]
+    fn responses_with_bodies() {
+        let mut r = Reassembler::new(ts(60));
+
+        let stream = r
+            .push(
+                &seg(1, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel"),
+                ts(1),
+            )
+            .unwrap();
+        let responses = http::extract_response_stream(stream, || None);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 200);
+        assert_eq!(responses[0].content_length, Some(5));
+
+        let stream = r
+            .push(
+                &seg(
+                    42,
+                    b"loHTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nab",
+                ),
+                ts(1),
+            )
+            .unwrap();
+        let responses = http::extract_response_stream(stream, || None);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 404);
+        assert_eq!(responses[0].reason, "Not Found");
+
+        let stream = r
+            .push(&seg(103, b"cd\r\n0\r\n\r\nHTTP/1.1 304 \r\n\r\n"), ts(1))
+            .unwrap();
+        let responses = http::extract_response_stream(stream, || None);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 304);
+        assert!(stream.data().is_empty());
+    }
+
+    #[test]
+    fn responses_to_head() {
+        let mut r = Reassembler::new(ts(60));
+        let mut methods = vec!["HEAD", "GET"].into_iter();
+
+        let stream = r
+            .push(
+                &seg(
+                    1,
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+                ),
+                ts(1),
+            )
+            .unwrap();
+        let responses = http::extract_response_stream(stream, || methods.next().map(String::from));
+        assert_eq!(responses.len(), 2);
+        assert!(stream.data().is_empty());
+    }
+
+    #[test]
+    fn interim_responses() {
+        let mut r = Reassembler::new(ts(60));
+        let mut calls = 0;
+
+        let stream = r
+            .push(
+                &seg(
+                    1,
+                    b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok",
+                ),
+                ts(1),
+            )
+            .unwrap();
+        let responses = http::extract_response_stream(stream, || {
+            calls += 1;
+            Some("POST".to_string())
+        });
+        // only the final response answers the request
+        assert_eq!(calls, 1);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 201);
+        assert!(stream.data().is_empty());
+    }
+
+    #[test]
+    fn oversized_chunk() {
+        let mut r = Reassembler::new(ts(60));
+        let stream = r
+            .push(
+                &seg(
+                    1,
+                    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\nabc",
+                ),
+                ts(1),
+            )
+            .unwrap();
+        let responses = http::extract_response_stream(stream, || None);
+        assert_eq!(responses.len(), 1);
+        assert!(stream.data().is_empty());
+    }
+
+    #[test]
+    fn expire_flows() {
+        let mut r = Reassembler::new(ts(60));
+        r.push(&seg(1, b"GET"), ts(1));
+        assert_eq!(r.len(), 1);
+
+        let mut other = seg(1, b"GET");
+        other.key.source_port = 41235;
+        r.push(&other, ts(100));
+        assert_eq!(r.len(), 1);
+
+        // closed streams are gone with the next segment of any stream
+        let mut fin = seg(4, b" / HTTP/1.1\r\n");
+        fin.key.source_port = 41235;
+        fin.fin = true;
+        assert!(r.push(&fin, ts(101)).unwrap().is_closed());
+        assert_eq!(r.len(), 1);
+        r.push(&seg(1, b"GET"), ts(102));
+        assert_eq!(r.len(), 1);
+    }
+
+    #[test]
+    fn max_streams() {
+        let mut r = Reassembler::new(ts(60));
+        r.set_max_streams(16);
+        for port in 0..16 {
+            let mut syn = seg(1, b"");
+            syn.syn = true;
+            syn.key.source_port = port;
+            r.push(&syn, ts(u64::from(port)));
+        }
+        assert_eq!(r.len(), 16);
+        assert_eq!(r.take_evicted(), 0);
+
+        // the two streams that were seen first make room
+        r.push(&seg(1, b"GET"), ts(20));
+        assert_eq!(r.len(), 15);
+        assert_eq!(r.take_evicted(), 2);
+        assert_eq!(r.take_evicted(), 0);
+        let mut first = seg(2, b"ET");
+        first.key.source_port = 0;
+        assert_eq!(r.push(&first, ts(21)).unwrap().data(), b"ET");
+    }
+
+    #[test]
+    fn evict_same_time() {
+        let mut r = Reassembler::new(ts(60));
+        r.set_max_streams(16);
+        for port in 0..16 {
+            let mut syn = seg(1, b"");
+            syn.syn = true;
+            syn.key.source_port = port;
+            r.push(&syn, ts(1));
+        }
+
+        // only an eighth goes, even though all of them are equally old
+        r.push(&seg(1, b"GET"), ts(1));
+        assert_eq!(r.take_evicted(), 2);
+        assert_eq!(r.len(), 15);
+    }
+}
@@ -62,12 +62,15 @@ pub mod ether {
     use structs::arp;
     use structs::cjdns;
     use structs::ipv4;
+    use structs::ipv6;
     use structs::NoiseLevel;
 
     #[derive(Debug, PartialEq, Serialize)]
     pub enum Ether {
         Arp(arp::ARP),
         IPv4(pktparse::ipv4::IPv4Header, ipv4::IPv4),
+        /// The payload uses the same tcp/udp dissectors as ipv4
+        IPv6(ipv6::IPv6Header, ipv4::IPv4),
         Cjdns(cjdns::CjdnsEthPkt),
         Unknown(Vec<u8>),
     }
@@ -78,6 +81,7 @@ pub mod ether {
             match *self {
                 Arp(_) => NoiseLevel::One,
                 IPv4(_, ref ipv4) => ipv4.noise_level(),
+                IPv6(_, ref ipv4) => ipv4.noise_level(),
                 Cjdns(_) => NoiseLevel::Two,
                 Unknown(_) => NoiseLevel::Maximum,
             }
@@ -129,6 +133,23 @@ pub mod ipv4 {
     }
 }
 
+pub mod ipv6 {
+    use std::net::Ipv6Addr;
+
+    #[derive(Debug, PartialEq, Serialize)]
+    pub struct IPv6Header {
+        pub version: u8,
+        pub traffic_class: u8,
+        pub flow_label: u32,
+        pub length: u16,
+        /// Protocol of the payload, after skipping the extension headers
+        pub next_header: u8,
+        pub hop_limit: u8,
+        pub source_addr: Ipv6Addr,
+        pub dest_addr: Ipv6Addr,
+    }
+}
+
 pub mod tcp {
     use structs::http;
     use structs::tls;
@@ -138,6 +159,7 @@ pub mod tcp {
     pub enum TCP {
         TLS(tls::ClientHello),
         HTTP(http::Request),
+        HTTPResponse(http::Response),
 
         Text(String),
         Binary(Vec<u8>),
@@ -267,6 +289,77 @@ pub mod http {
             })
         }
     }
+
+    #[derive(Debug, PartialEq, Serialize)]
+    pub struct Response {
+        pub version: String,
+        pub status: u16,
+        pub reason: String,
+        pub content_length: Option<usize>,
+        pub headers: HashMap<String, Option<String>>,
+    }
+
+    impl Response {
+        pub fn from_nom(
+            res: &nom_http::StatusLine,
+            headers: Vec<nom_http::Header>,
+        ) -> Result<Response, FromUtf8Error> {
+            let mut content_length = None;
+
+            let mut all_headers = HashMap::new();
+            for header in headers {
+                if let Ok(name) = from_utf8(header.name) {
+                    let name = name.to_lowercase();
+                    let value = mkheader(header.value);
+                    if name == "content-length" {
+                        content_length = value.as_ref().and_then(|x| x.trim().parse().ok());
+                    }
+                    all_headers.insert(name, value);
+                }
+            }
+
+            Ok(Response {
+                version: String::from_utf8(res.version.to_vec())?,
+                // the parser only accepts exactly 3 digits
+                status: res
+                    .status
+                    .iter()
+                    .fold(0, |acc, d| acc * 10 + u16::from(d - b'0')),
+                reason: String::from_utf8(res.reason.to_vec())?,
+                content_length,
+                headers: all_headers,
+            })
+        }
+
+        /// Responses to these never carry a body, regardless of their headers
+        pub fn has_body(&self) -> bool {
+            !(self.status < 200 || self.status == 204 || self.status == 304)
+        }
+
+        /// Informational responses like `100 Continue` come before the final
+        /// response to the same request. `101 Switching Protocols` is final.
+        pub fn is_interim(&self) -> bool {
+            self.status / 100 == 1 && self.status != 101
+        }
+
+        /// Like `has_body`, for a response to a request with `method`. Responses
+        /// to HEAD only announce the body they would have sent, and an accepted
+        /// CONNECT turns the connection into a tunnel.
+        pub fn has_body_for(&self, method: &str) -> bool {
+            match method {
+                "HEAD" => false,
+                "CONNECT" if self.status / 100 == 2 => false,
+                _ => self.has_body(),
+            }
+        }
+
+        pub fn is_chunked(&self) -> bool {
+            match self.headers.get("transfer-encoding") {
+                Some(Some(value)) => value.to_lowercase().contains("chunked"),
+                _ => false,
+            }
+        }
+    }
 }
 
 pub mod dhcp {